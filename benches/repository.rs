@@ -0,0 +1,147 @@
+//!
+//! Throughput of the in memory repository with registrations spread over many profiles, run with `cargo bench`
+//!
+
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use profile_backend::repository::{
+    inram::InMemoryProfileRepository, model::PurchaseDetails, ProfileRepository,
+};
+
+const THREADS: [usize; 4] = [1, 2, 4, 8];
+const PROFILES: u64 = 10_000;
+
+fn setup() -> InMemoryProfileRepository {
+    let repository = InMemoryProfileRepository::new();
+    repository.insert_product("LEAF1", &[], None).unwrap();
+    repository
+        .insert_product("LEAF2", &[], Some(365 * 24 * 3600))
+        .unwrap();
+    repository
+        .insert_product("BUNDLE", &["LEAF1".into(), "LEAF2".into()], None)
+        .unwrap();
+    repository
+}
+
+fn register(repository: &InMemoryProfileRepository, profile_id: u64, product: &str) {
+    repository
+        .insert_product_registration(profile_id, product, None, PurchaseDetails::default(), None)
+        .unwrap();
+}
+
+// Makes `iters` calls spread over `threads` threads, and returns how long they took altogether
+fn run_concurrently(threads: usize, iters: u64, call: impl Fn(u64) + Sync) -> Duration {
+    let start = Instant::now();
+    std::thread::scope(|scope| {
+        for thread in 0..threads as u64 {
+            let call = &call;
+            scope.spawn(move || {
+                for i in (thread..iters).step_by(threads) {
+                    call(i);
+                }
+            });
+        }
+    });
+    start.elapsed()
+}
+
+fn insert_product_registrations(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert_product_registration");
+    group.throughput(Throughput::Elements(1));
+    for threads in THREADS {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                let repository = setup();
+                // every registration goes to a profile of its own, so none of them conflict
+                let next_profile_id = AtomicU64::new(1);
+                b.iter_custom(|iters| {
+                    run_concurrently(threads, iters, |_| {
+                        register(
+                            &repository,
+                            next_profile_id.fetch_add(1, Ordering::Relaxed),
+                            "BUNDLE",
+                        )
+                    })
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+fn read_product_registrations(c: &mut Criterion) {
+    let repository = setup();
+    for profile_id in 1..=PROFILES {
+        register(&repository, profile_id, "BUNDLE");
+    }
+
+    let mut group = c.benchmark_group("get_product_registrations_for_profile");
+    group.throughput(Throughput::Elements(1));
+    for threads in THREADS {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                b.iter_custom(|iters| {
+                    run_concurrently(threads, iters, |i| {
+                        repository.get_product_registrations_for_profile(
+                            i % PROFILES + 1,
+                            None,
+                            None,
+                            0,
+                            10,
+                        );
+                    })
+                });
+            },
+        );
+    }
+    group.finish();
+}
+
+// Conflicts are found through the profile's held products, however many registrations it has
+fn insert_conflicting_product_registration(c: &mut Criterion) {
+    let mut group = c.benchmark_group("insert_conflicting_product_registration");
+    for registrations in [10, 100, 1000] {
+        let repository = setup();
+        for product in 0..registrations {
+            let product = format!("PRODUCT{}", product);
+            repository.insert_product(&product, &[], None).unwrap();
+            register(&repository, 1, &product);
+        }
+        register(&repository, 1, "BUNDLE");
+
+        group.bench_with_input(
+            BenchmarkId::from_parameter(registrations),
+            &repository,
+            |b, repository| {
+                b.iter(|| {
+                    assert!(repository
+                        .insert_product_registration(
+                            1,
+                            "LEAF1",
+                            None,
+                            PurchaseDetails::default(),
+                            None,
+                        )
+                        .is_err())
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    insert_product_registrations,
+    read_product_registrations,
+    insert_conflicting_product_registration
+);
+criterion_main!(benches);
@@ -1,3 +1,4 @@
+import os
 import unittest
 import requests
 
@@ -6,35 +7,40 @@ class ProfileApiE2ETest(unittest.TestCase):
     HOST = "http://127.0.0.1:3000"
     PROFILE_ENDPOINT = "/api/v1/profiles"
     PROFILE_PRODUCT_REGISTRATION_ENDPOINT = "/api/v1/profiles/{}/product_registrations"
+    # the service has to be started with the same APP_BOOTSTRAP_ADMIN_API_KEY
+    HEADERS = {"X-Api-Key": os.environ.get("APP_BOOTSTRAP_ADMIN_API_KEY", "")}
 
     @classmethod
     def setUpClass(cls):
         cls.maxDiff = None
 
+    def get_profiles(self):
+        res = requests.get(self.HOST + self.PROFILE_ENDPOINT, headers=self.HEADERS)
+        self.assertEqual(res.status_code, 200)
+        return res.json()
+
     def test_profile_api(self):
         expected = {
             "page": 0,
             "items": [
                 {
-                    "id": 1,
                     "email": "john.doe@example.com",
                     "firstname": "John",
                     "lastname": "Doe",
-                    "product_registrations": [],
                 },
                 {
-                    "id": 2,
                     "email": "jane.smith@example.com",
                     "firstname": "Jane",
                     "lastname": "Smith",
-                    "product_registrations": [],
                 },
             ],
         }
 
-        res = requests.get(self.HOST + self.PROFILE_ENDPOINT)
-        self.assertEqual(res.status_code, 200)
-        self.assertDictEqual(res.json(), expected)
+        profiles = self.get_profiles()
+        # ids are opaque, so they are only checked for their prefix
+        for profile in profiles["items"]:
+            self.assertTrue(profile.pop("id").startswith("prof_"))
+        self.assertDictEqual(profiles, expected)
 
     def test_profile_api_out_of_range_page(self):
         page = 10
@@ -43,36 +49,50 @@ class ProfileApiE2ETest(unittest.TestCase):
             "items": [],
         }
 
-        res = requests.get(self.HOST + self.PROFILE_ENDPOINT, params={"page": page})
+        res = requests.get(
+            self.HOST + self.PROFILE_ENDPOINT, params={"page": page}, headers=self.HEADERS
+        )
         self.assertEqual(res.status_code, 200)
         self.assertEqual(res.json(), expected)
 
     def test_product_registrations_api(self):
-        expected = {
-            "page": 0,
-            "items": [
-                {
-                    "id": 1,
-                    "purchase_date": 1673795045000,
-                    "expiry_at": 1705331045000,
-                    "product": {"sku": "ARIE4"},
-                    "additional_product_registrations": [],
-                },
-                {
-                    "id": 2,
-                    "purchase_date": 1678449600000,
-                    "expiry_at": None,
-                    "product": {"sku": "ARCC4"},
-                    "additional_product_registrations": [],
-                },
-            ],
-        }
+        expected = [
+            {
+                "purchase_date": 1673795045000,
+                "expiry_at": 1705331045000,
+                "product": {"sku": "ARIE4"},
+                "serial_code": "A1B2C3D4",
+                "additional_product_registrations": [],
+            },
+            {
+                "purchase_date": 1678449600000,
+                "expiry_at": None,
+                "product": {"sku": "ARCC4"},
+                "serial_code": "L3M4N5O6",
+                "additional_product_registrations": [],
+            },
+        ]
 
+        profile_id = self.get_profiles()["items"][0]["id"]
         res = requests.get(
-            self.HOST + self.PROFILE_PRODUCT_REGISTRATION_ENDPOINT.format(1)
+            self.HOST + self.PROFILE_PRODUCT_REGISTRATION_ENDPOINT.format(profile_id),
+            headers=self.HEADERS,
         )
         self.assertEqual(res.status_code, 200)
-        self.assertDictEqual(res.json(), expected)
+        registrations = res.json()["items"]
+        self.assertEqual(len(registrations), len(expected))
+        for registration, expected_registration in zip(registrations, expected):
+            self.assertTrue(registration["id"].startswith("reg_"))
+            for field, value in expected_registration.items():
+                self.assertEqual(registration[field], value, field)
+
+    def test_product_registrations_api_unknown_profile(self):
+        for profile_id in ["1", "prof_0000000000000"]:
+            res = requests.get(
+                self.HOST + self.PROFILE_PRODUCT_REGISTRATION_ENDPOINT.format(profile_id),
+                headers=self.HEADERS,
+            )
+            self.assertEqual(res.status_code, 404)
 
 
 if __name__ == "__main__":
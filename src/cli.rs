@@ -0,0 +1,109 @@
+use std::fs;
+
+use profile_backend::repository::{inram::InMemoryProfileRepository, journal::FsyncPolicy};
+use profile_backend::service::{ProfileService, ProfileServiceConfig};
+
+use crate::config::Config;
+
+const USAGE: &str =
+    "usage: profile_backend [serve | backup <file> | restore <file> | fsck [--repair]]";
+
+///
+/// Maintenance run instead of serving, against the data in APP_DATA_DIR
+///
+pub(crate) enum Command {
+    Backup(String),
+    Restore(String),
+    // whether to repair what is found
+    Fsck(bool),
+}
+
+impl Command {
+    // None to serve, the usage if the arguments are not understood
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Option<Self>, &'static str> {
+        let args: Vec<String> = args.collect();
+        match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            [] | ["serve"] => Ok(None),
+            ["backup", file] => Ok(Some(Command::Backup(file.into()))),
+            ["restore", file] => Ok(Some(Command::Restore(file.into()))),
+            ["fsck"] => Ok(Some(Command::Fsck(false))),
+            ["fsck", "--repair"] => Ok(Some(Command::Fsck(true))),
+            _ => Err(USAGE),
+        }
+    }
+
+    pub fn run(self, config: &Config) -> Result<(), String> {
+        match self {
+            Command::Backup(file) => {
+                let service = open_data_dir(config)?;
+                fs::write(&file, service.export_backup())
+                    .map_err(|err| format!("Unable to write {}: {}", file, err))?;
+                tracing::info!("Wrote a backup to {}", file);
+            }
+            Command::Restore(file) => {
+                let backup =
+                    fs::read(&file).map_err(|err| format!("Unable to read {}: {}", file, err))?;
+                let service = open_data_dir(config)?;
+                let summary = service
+                    .restore_backup(&backup)
+                    .map_err(|err| format!("Unable to restore {}: {:?}", file, err))?;
+                service
+                    .repository()
+                    .compact()
+                    .map_err(|err| format!("Unable to write a snapshot: {}", err))?;
+                tracing::info!("Restored {:?} from {}", summary, file);
+            }
+            Command::Fsck(repair) => {
+                let service = open_data_dir(config)?;
+                let report = service
+                    .check_integrity(repair)
+                    .map_err(|err| format!("Unable to check the data: {:?}", err))?;
+                for violation in &report.violations {
+                    tracing::warn!(
+                        "{:?} profile:{:?} registration:{:?} product:{:?}: {}, repair: {}",
+                        violation.kind,
+                        violation.profile_id,
+                        violation.registration_id,
+                        violation.product,
+                        violation.description,
+                        violation.repair
+                    );
+                }
+
+                if report.repaired {
+                    service
+                        .repository()
+                        .compact()
+                        .map_err(|err| format!("Unable to write a snapshot: {}", err))?;
+                    tracing::info!("Repaired {} violations", report.violations.len());
+                } else if !report.violations.is_empty() {
+                    return Err(format!(
+                        "Found {} violations, run fsck --repair to repair them",
+                        report.violations.len()
+                    ));
+                } else {
+                    tracing::info!("No violations found");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// The data is opened directly, so the service must not be running on the same APP_DATA_DIR. The sample data is left
+// out, so a new data dir starts empty.
+fn open_data_dir(config: &Config) -> Result<ProfileService<InMemoryProfileRepository>, String> {
+    let data_dir = config
+        .data_dir
+        .as_ref()
+        .ok_or("APP_DATA_DIR has to be set to back up, restore or check the data")?;
+    let repository = InMemoryProfileRepository::new()
+        .with_journal(data_dir, FsyncPolicy::Always)
+        .map_err(|err| format!("Unable to load the data in {}: {}", data_dir, err))?;
+
+    Ok(ProfileService::new(
+        repository,
+        ProfileServiceConfig::default(),
+    ))
+}
@@ -1,15 +1,85 @@
 use envconfig::Envconfig;
 
+use crate::license::{LicenseSigningKey, LicenseVerifyingKeys};
+use crate::repository::id::IdStrategy;
+use crate::repository::journal::FsyncPolicy;
+
+// The config is logged on startup, so secrets are left out of its debug output
+pub(crate) struct Secret(pub String);
+
+impl std::str::FromStr for Secret {
+    type Err = std::convert::Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(value.into()))
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
 #[derive(Debug, envconfig::Envconfig)]
 pub(crate) struct Config {
     #[envconfig(from = "APP_HOST", default = "0.0.0.0")]
     pub host: String,
     #[envconfig(from = "APP_HOST", default = "3000")]
     pub port: u16,
+    // Where the service is reachable from the outside, for links handed out to customers
+    #[envconfig(from = "APP_PUBLIC_URL", default = "http://localhost:3000")]
+    pub public_url: String,
     #[envconfig(from = "APP_PROFILES_PER_PAGE", default = "30")]
     pub profiles_per_page: usize,
     #[envconfig(from = "APP_PRODUCT_REGISTRATIONS_PER_PAGE", default = "30")]
     pub product_registrations_per_page: usize,
+    // Per client address on the public verification endpoint
+    #[envconfig(from = "APP_VERIFY_REQUESTS_PER_MINUTE", default = "30")]
+    pub verify_requests_per_minute: u32,
+    // How long responses are kept for retries with the same Idempotency-Key
+    #[envconfig(from = "APP_IDEMPOTENCY_KEY_TTL_HOURS", default = "24")]
+    pub idempotency_key_ttl_hours: u64,
+    #[envconfig(from = "APP_MAX_PURCHASE_BACKDATE_DAYS", default = "365")]
+    pub max_purchase_backdate_days: u32,
     #[envconfig(from = "APP_USE_SAMPLE_DATA", default = "true")]
     pub use_sample_data: bool,
+    // Directory changes are journaled and snapshotted to, nothing is kept across restarts if it is not set
+    #[envconfig(from = "APP_DATA_DIR")]
+    pub data_dir: Option<String>,
+    // always, every_second or never
+    #[envconfig(from = "APP_JOURNAL_FSYNC", default = "always")]
+    pub journal_fsync: FsyncPolicy,
+    // How often the journal is folded into a new snapshot
+    #[envconfig(from = "APP_SNAPSHOT_INTERVAL_SECONDS", default = "300")]
+    pub snapshot_interval_seconds: u64,
+    // sequential, snowflake or ulid
+    #[envconfig(from = "APP_ID_STRATEGY", default = "sequential")]
+    pub id_strategy: IdStrategy,
+    // Has to be different for every instance handing out snowflake ids, 0 to 1023
+    #[envconfig(from = "APP_SNOWFLAKE_NODE_ID", default = "0")]
+    pub snowflake_node_id: u16,
+    // Admin API key which exists from the start, to create the other API keys with
+    #[envconfig(from = "APP_BOOTSTRAP_ADMIN_API_KEY")]
+    pub bootstrap_admin_api_key: Option<Secret>,
+    // Key the public ids are encrypted with, has to stay the same for ids handed out to remain valid
+    #[envconfig(from = "APP_PUBLIC_ID_SECRET")]
+    pub public_id_secret: Option<Secret>,
+    // Customer tokens, accepted if any of the keys is set
+    #[envconfig(from = "APP_JWT_HS256_SECRET")]
+    pub jwt_hs256_secret: Option<Secret>,
+    #[envconfig(from = "APP_JWT_RS256_PUBLIC_KEY_FILE")]
+    pub jwt_rs256_public_key_file: Option<String>,
+    #[envconfig(from = "APP_JWT_JWKS_FILE")]
+    pub jwt_jwks_file: Option<String>,
+    #[envconfig(from = "APP_JWT_ISSUER")]
+    pub jwt_issuer: Option<String>,
+    #[envconfig(from = "APP_JWT_AUDIENCE")]
+    pub jwt_audience: Option<String>,
+    // <key_id>:<base64 Ed25519 secret key>, license keys are only issued when set
+    #[envconfig(from = "APP_LICENSE_SIGNING_KEY")]
+    pub license_signing_key: Option<LicenseSigningKey>,
+    // <key_id>:<base64 Ed25519 public key>,... of rotated out keys which still have to verify
+    #[envconfig(from = "APP_LICENSE_VERIFYING_KEYS")]
+    pub license_verifying_keys: Option<LicenseVerifyingKeys>,
 }
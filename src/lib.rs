@@ -0,0 +1,7 @@
+//!
+//! The service and repository layers, a library so they can be benchmarked apart from the web layer in main.rs
+//!
+
+pub mod license;
+pub mod repository;
+pub mod service;
@@ -0,0 +1,201 @@
+//!
+//! Offline license keys
+//! A license key is `<key_id>.<claims>.<signature>`, the claims being base64url encoded JSON and the signature an
+//! Ed25519 signature over `<key_id>.<claims>`. Verifying one only needs this module and the public keys, so it can
+//! be shipped along with the desktop software.
+//!
+
+use std::str::FromStr;
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct LicenseClaims {
+    // The public profile id, the claims can be read by anyone holding the key
+    pub profile_id: String,
+    pub sku: String,
+    pub serial_code: String,
+    // None for registrations which never expire
+    pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub issued_at: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum LicenseError {
+    Malformed,
+    // Signed with a key which has been retired or never existed
+    UnknownKeyId(String),
+    InvalidSignature,
+    Expired,
+}
+
+fn is_key_id_valid(key_id: &str) -> bool {
+    !key_id.is_empty()
+        && key_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+// `<key_id>:<base64 key>`, as given in the config
+fn parse_key(value: &str) -> Result<(String, [u8; 32]), String> {
+    let Some((key_id, key)) = value.trim().split_once(':') else {
+        return Err("license keys have to be given as <key_id>:<base64 key>".into());
+    };
+    if !is_key_id_valid(key_id) {
+        return Err(format!(
+            "license key id:{} can only contain alphanumeric characters, - and _",
+            key_id
+        ));
+    }
+
+    let key = STANDARD
+        .decode(key)
+        .map_err(|err| format!("license key:{} is not base64, {}", key_id, err))?;
+    let key = key
+        .try_into()
+        .map_err(|_| format!("license key:{} has to be 32 bytes", key_id))?;
+
+    Ok((key_id.into(), key))
+}
+
+///
+/// Private key license keys are signed with, identified by its key id so it can be rotated
+///
+#[derive(Clone)]
+pub struct LicenseSigningKey {
+    pub key_id: String,
+    key: SigningKey,
+}
+
+impl LicenseSigningKey {
+    pub fn new(key_id: &str, secret_key: [u8; 32]) -> Self {
+        Self {
+            key_id: key_id.into(),
+            key: SigningKey::from_bytes(&secret_key),
+        }
+    }
+
+    pub fn verifying_key(&self) -> LicenseVerifyingKey {
+        LicenseVerifyingKey {
+            key_id: self.key_id.clone(),
+            key: self.key.verifying_key(),
+        }
+    }
+
+    pub fn sign(&self, claims: &LicenseClaims) -> String {
+        let claims = serde_json::to_vec(claims).unwrap();
+        let message = format!("{}.{}", self.key_id, URL_SAFE_NO_PAD.encode(claims));
+        let signature = self.key.sign(message.as_bytes());
+
+        format!(
+            "{}.{}",
+            message,
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        )
+    }
+}
+
+impl FromStr for LicenseSigningKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (key_id, secret_key) = parse_key(value)?;
+        Ok(Self::new(&key_id, secret_key))
+    }
+}
+
+// The config is logged on startup, so the private key must not end up in there
+impl std::fmt::Debug for LicenseSigningKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("LicenseSigningKey")
+            .field("key_id", &self.key_id)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LicenseVerifyingKey {
+    pub key_id: String,
+    key: VerifyingKey,
+}
+
+impl LicenseVerifyingKey {
+    pub fn public_key(&self) -> String {
+        STANDARD.encode(self.key.as_bytes())
+    }
+}
+
+impl FromStr for LicenseVerifyingKey {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (key_id, public_key) = parse_key(value)?;
+        let key = VerifyingKey::from_bytes(&public_key)
+            .map_err(|_| format!("license key:{} is not an Ed25519 public key", key_id))?;
+
+        Ok(Self { key_id, key })
+    }
+}
+
+///
+/// Comma separated verifying keys, so keys which were rotated out keep verifying the license keys they signed
+///
+#[derive(Clone, Debug, Default)]
+pub struct LicenseVerifyingKeys(pub Vec<LicenseVerifyingKey>);
+
+impl FromStr for LicenseVerifyingKeys {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        value
+            .split(',')
+            .filter(|key| !key.trim().is_empty())
+            .map(LicenseVerifyingKey::from_str)
+            .collect::<Result<Vec<_>, _>>()
+            .map(LicenseVerifyingKeys)
+    }
+}
+
+///
+/// Checks the license key was signed by one of the keys and hasn't expired by `now`
+///
+pub fn verify_license_key(
+    license_key: &str,
+    verifying_keys: &[LicenseVerifyingKey],
+    now: chrono::DateTime<chrono::Utc>,
+) -> Result<LicenseClaims, LicenseError> {
+    let Some((message, signature)) = license_key.rsplit_once('.') else {
+        return Err(LicenseError::Malformed);
+    };
+    let Some((key_id, claims)) = message.split_once('.') else {
+        return Err(LicenseError::Malformed);
+    };
+
+    let Some(verifying_key) = verifying_keys.iter().find(|key| key.key_id == key_id) else {
+        return Err(LicenseError::UnknownKeyId(key_id.into()));
+    };
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .ok()
+        .and_then(|signature| Signature::from_slice(&signature).ok())
+        .ok_or(LicenseError::Malformed)?;
+    verifying_key
+        .key
+        .verify(message.as_bytes(), &signature)
+        .map_err(|_| LicenseError::InvalidSignature)?;
+
+    let claims: LicenseClaims = URL_SAFE_NO_PAD
+        .decode(claims)
+        .ok()
+        .and_then(|claims| serde_json::from_slice(&claims).ok())
+        .ok_or(LicenseError::Malformed)?;
+    if claims.expiry_at.is_some_and(|expiry_at| expiry_at <= now) {
+        return Err(LicenseError::Expired);
+    }
+
+    Ok(claims)
+}
@@ -1,18 +1,35 @@
+mod cli;
 mod config;
-mod repository;
-mod service;
 mod web;
 
-use std::sync::Arc;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use axum::Router;
+use axum::{extract::DefaultBodyLimit, Extension, Router};
 use envconfig::Envconfig;
+use profile_backend::{license, repository, service};
 use repository::inram::InMemoryProfileRepository;
-use service::{ProfileService, ProfileServiceConfig};
+use repository::journal::FsyncPolicy;
+use service::model::ApiKeyRole;
+use service::{ProfileService, ProfileServiceConfig, ProfileServiceError};
+use web::auth::{self, authenticate, authorize, AuthState};
 use web::controller::{
-    product_post, product_registrations_get, product_registrations_post,
-    profile_product_registrations_get, profiles_get,
+    api_key_revoke_post, api_keys_get, api_keys_post, backup_get, fsck_get, fsck_repair_post,
+    license_key_verify_post, license_keys_get, lot_registrations_get, product_post,
+    product_registration_by_serial_get, product_registration_certificate_get,
+    product_registration_license_key_get, product_registration_ownership_get,
+    product_registration_renew_post, product_registration_renewals_get,
+    product_registration_resume_post, product_registration_revoke_post,
+    product_registration_suspend_post, product_registration_transfer_post,
+    product_registration_upgrade_post, product_registrations_get, product_registrations_post,
+    product_serial_format_put, product_serial_pool_get, product_serial_pool_post,
+    product_serial_template_get, product_serial_template_put, product_upgrade_post,
+    profile_entitlement_get, profile_entitlements_get, profile_product_registrations_get,
+    profiles_get, restore_post, verify_get,
 };
+use web::idempotency::{idempotency, IdempotencyStore, SWEEP_INTERVAL};
+use web::jwt::JwtVerifier;
+use web::public_id::PublicIds;
+use web::rate_limit::{rate_limit, RateLimiter};
 
 #[tokio::main]
 async fn main() {
@@ -21,44 +38,344 @@ async fn main() {
     let config = config::Config::init_from_env().unwrap();
     tracing::info!("Starting with the following configs: {:#?}", config);
 
+    match cli::Command::from_args(std::env::args().skip(1)) {
+        Ok(None) => {}
+        Ok(Some(command)) => {
+            if let Err(err) = command.run(&config) {
+                tracing::error!("{}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Err(usage) => {
+            eprintln!("{}", usage);
+            std::process::exit(2);
+        }
+    }
+
     let service_config = ProfileServiceConfig {
+        public_url: config.public_url,
         profile_per_page: config.profiles_per_page,
         product_registrations_per_page: config.product_registrations_per_page,
+        max_purchase_backdate_days: config.max_purchase_backdate_days,
+        license_signing_key: config.license_signing_key,
+        license_verifying_keys: config.license_verifying_keys.unwrap_or_default().0,
     };
 
+    let id_generator = config
+        .id_strategy
+        .id_generator(config.snowflake_node_id)
+        .unwrap_or_else(|err| {
+            tracing::error!("{}", err);
+            std::process::exit(2);
+        });
     let db = if config.use_sample_data {
         InMemoryProfileRepository::with_example_data(
-            crate::repository::inram::random_serial_generator,
+            crate::repository::serial::RandomSerialGenerator,
             crate::repository::inram::default_time_provider,
         )
     } else {
         InMemoryProfileRepository::new()
+    }
+    .with_id_generator(id_generator);
+    let db = match &config.data_dir {
+        Some(data_dir) => db
+            .with_journal(data_dir, config.journal_fsync)
+            .unwrap_or_else(|err| panic!("Unable to load the data in {}: {}", data_dir, err)),
+        None => db,
     };
 
     let service = Arc::new(ProfileService::new(db, service_config));
+    if config.data_dir.is_some() {
+        spawn_persistence_tasks(
+            service.clone(),
+            config.journal_fsync,
+            Duration::from_secs(config.snapshot_interval_seconds),
+        );
+    }
 
-    // build our application with a route
-    let profile_router = Router::new()
-        .route("/profiles", axum::routing::get(profiles_get))
+    if let Some(key) = &config.bootstrap_admin_api_key {
+        match service.import_api_key("bootstrap", ApiKeyRole::Admin, &key.0) {
+            // kept from an earlier run
+            Ok(_) | Err(ProfileServiceError::Conflict(_)) => {}
+            Err(err) => panic!("Unable to import the bootstrap admin api key: {:?}", err),
+        }
+    } else if service.get_api_keys().is_empty() {
+        tracing::warn!(
+            "No api keys exist and APP_BOOTSTRAP_ADMIN_API_KEY is not set, nobody can call the api"
+        );
+    }
+
+    let mut jwt_verifier = JwtVerifier::new(config.jwt_issuer, config.jwt_audience);
+    if let Some(secret) = &config.jwt_hs256_secret {
+        jwt_verifier.add_hs256_secret(secret.0.as_bytes());
+    }
+    if let Some(path) = &config.jwt_rs256_public_key_file {
+        let pem = std::fs::read(path).unwrap();
+        jwt_verifier.add_rs256_public_key_pem(&pem).unwrap();
+    }
+    if let Some(path) = &config.jwt_jwks_file {
+        let jwks = std::fs::read_to_string(path).unwrap();
+        jwt_verifier.add_jwks(&jwks).unwrap();
+    }
+    let public_ids = Arc::new(match &config.public_id_secret {
+        Some(secret) => PublicIds::new(secret.0.as_bytes()),
+        None => {
+            tracing::warn!(
+                "APP_PUBLIC_ID_SECRET is not set, ids handed out will change on every restart"
+            );
+            PublicIds::new(&rand::random::<[u8; 32]>())
+        }
+    });
+
+    let auth_state = AuthState {
+        service: service.clone(),
+        jwt_verifier: (!jwt_verifier.is_empty()).then(|| Arc::new(jwt_verifier)),
+        public_ids: public_ids.clone(),
+    };
+
+    // build our application with a route, grouped by who is allowed to call them
+    let customer_read_router = Router::new()
         .route(
             "/profiles/:profile/product_registrations",
             axum::routing::get(profile_product_registrations_get),
         )
+        .route_layer(axum::middleware::from_fn_with_state(
+            auth::CUSTOMER_READ,
+            authorize,
+        ));
+
+    let customer_register_router = Router::new()
+        .route(
+            "/profiles/:profile/product_registrations",
+            axum::routing::post(product_registrations_post),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(
+            auth::CUSTOMER_REGISTER,
+            authorize,
+        ));
+
+    let read_router = Router::new()
+        .route("/profiles", axum::routing::get(profiles_get))
+        .route(
+            "/profiles/:profile/entitlements",
+            axum::routing::get(profile_entitlements_get),
+        )
+        .route(
+            "/profiles/:profile/entitlements/:sku",
+            axum::routing::get(profile_entitlement_get),
+        )
         .route(
             "/product_registration/:id",
             axum::routing::get(product_registrations_get),
         )
         .route(
-            "/profiles/:profile/product_registrations",
-            axum::routing::post(product_registrations_post),
+            "/product_registration/by_serial/:serial_code",
+            axum::routing::get(product_registration_by_serial_get),
+        )
+        .route(
+            "/product_registration/:id/ownership",
+            axum::routing::get(product_registration_ownership_get),
+        )
+        .route(
+            "/product_registration/:id/renewals",
+            axum::routing::get(product_registration_renewals_get),
+        )
+        .route(
+            "/product_registration/:id/certificate",
+            axum::routing::get(product_registration_certificate_get),
+        )
+        .route(
+            "/license_keys/verify",
+            axum::routing::post(license_key_verify_post),
+        )
+        .route(
+            "/product/:sku/serial_template",
+            axum::routing::get(product_serial_template_get),
+        )
+        .route(
+            "/product/:sku/serial_pool",
+            axum::routing::get(product_serial_pool_get),
+        )
+        .route(
+            "/lots/:lot/registrations",
+            axum::routing::get(lot_registrations_get),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(auth::READ, authorize));
+
+    let support_router = Router::new()
+        .route(
+            "/product_registration/:id/renew",
+            axum::routing::post(product_registration_renew_post),
         )
+        .route(
+            "/product_registration/:id/revoke",
+            axum::routing::post(product_registration_revoke_post),
+        )
+        .route(
+            "/product_registration/:id/suspend",
+            axum::routing::post(product_registration_suspend_post),
+        )
+        .route(
+            "/product_registration/:id/resume",
+            axum::routing::post(product_registration_resume_post),
+        )
+        .route(
+            "/product_registration/:id/transfer",
+            axum::routing::post(product_registration_transfer_post),
+        )
+        .route(
+            "/product_registration/:id/upgrade",
+            axum::routing::post(product_registration_upgrade_post),
+        )
+        .route(
+            "/product_registration/:id/license_key",
+            axum::routing::get(product_registration_license_key_get),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(
+            auth::SUPPORT,
+            authorize,
+        ));
+
+    let admin_router = Router::new()
         .route("/product", axum::routing::post(product_post))
+        .route(
+            "/product/:sku/upgrades",
+            axum::routing::post(product_upgrade_post),
+        )
+        .route(
+            "/product/:sku/serial_format",
+            axum::routing::put(product_serial_format_put),
+        )
+        .route(
+            "/product/:sku/serial_template",
+            axum::routing::put(product_serial_template_put),
+        )
+        .route(
+            "/product/:sku/serial_pool",
+            axum::routing::post(product_serial_pool_post),
+        )
+        .route(
+            "/api_keys",
+            axum::routing::post(api_keys_post).get(api_keys_get),
+        )
+        .route(
+            "/api_keys/:id/revoke",
+            axum::routing::post(api_key_revoke_post),
+        )
+        .route("/backup", axum::routing::get(backup_get))
+        .route("/fsck", axum::routing::get(fsck_get))
+        .route("/fsck/repair", axum::routing::post(fsck_repair_post))
+        .route_layer(axum::middleware::from_fn_with_state(auth::ADMIN, authorize));
+
+    // backups are as big as the data, so they are neither size limited nor buffered for idempotency, a retried restore
+    // is turned away by the data the first one restored
+    let restore_router = Router::new()
+        .route(
+            "/restore",
+            axum::routing::post(restore_post).layer(DefaultBodyLimit::disable()),
+        )
+        .route_layer(axum::middleware::from_fn_with_state(auth::ADMIN, authorize));
+
+    let idempotency_store = Arc::new(IdempotencyStore::new(Duration::from_secs(
+        config.idempotency_key_ttl_hours * 3600,
+    )));
+    let profile_router = customer_read_router
+        .merge(customer_register_router)
+        .merge(read_router)
+        .merge(support_router)
+        .merge(admin_router)
+        // after authentication, so idempotency keys are kept per caller
+        .route_layer(axum::middleware::from_fn_with_state(
+            idempotency_store.clone(),
+            idempotency,
+        ))
+        .merge(restore_router)
+        .route_layer(axum::middleware::from_fn_with_state(
+            auth_state,
+            authenticate,
+        ))
+        .layer(Extension(public_ids))
+        .with_state(service.clone());
+
+    // Unauthenticated, so it is rate limited to make enumerating serial codes slow
+    let verify_limiter = Arc::new(RateLimiter::new(
+        config.verify_requests_per_minute,
+        Duration::from_secs(60),
+    ));
+    spawn_sweep_task(idempotency_store, verify_limiter.clone());
+    let public_router = Router::new()
+        .route("/verify/:serial_code", axum::routing::get(verify_get))
+        .route_layer(axum::middleware::from_fn_with_state(
+            verify_limiter,
+            rate_limit,
+        ))
+        // public keys, so offline license keys can be verified by anyone
+        .route("/license_keys", axum::routing::get(license_keys_get))
         .with_state(service);
 
-    let app = Router::new().nest("/api/v1", profile_router);
+    let app = Router::new().nest("/api/v1", profile_router.merge(public_router));
 
     let listener = tokio::net::TcpListener::bind(format!("{}:{}", config.host, config.port))
         .await
         .unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .unwrap();
+}
+
+// Snapshots are written every `snapshot_interval`, and the journal is synced every second if the fsync policy leaves
+// it to a background task
+fn spawn_persistence_tasks(
+    service: Arc<ProfileService<InMemoryProfileRepository>>,
+    fsync_policy: FsyncPolicy,
+    snapshot_interval: Duration,
+) {
+    let compacted_service = service.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(snapshot_interval);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let service = compacted_service.clone();
+            match tokio::task::spawn_blocking(move || service.repository().compact()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => tracing::error!("Unable to write a snapshot: {}", err),
+                Err(err) => tracing::error!("Snapshot task failed: {}", err),
+            }
+        }
+    });
+
+    if fsync_policy == FsyncPolicy::EverySecond {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                let service = service.clone();
+                match tokio::task::spawn_blocking(move || service.repository().sync_journal()).await
+                {
+                    Ok(Ok(())) => {}
+                    Ok(Err(err)) => tracing::error!("Unable to sync the journal: {}", err),
+                    Err(err) => tracing::error!("Journal sync task failed: {}", err),
+                }
+            }
+        });
+    }
+}
+
+// Expired idempotency keys and rate limit windows are dropped every SWEEP_INTERVAL
+fn spawn_sweep_task(idempotency_store: Arc<IdempotencyStore>, verify_limiter: Arc<RateLimiter>) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let now = std::time::Instant::now();
+            idempotency_store.sweep(now);
+            verify_limiter.sweep(now);
+        }
+    });
 }
@@ -0,0 +1,389 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::model::{
+    IntegrityViolation, IntegrityViolationKind, ProductRegistration, RegistrationStatus,
+    RegistrationStatusChange, Snapshot,
+};
+
+const OVERLAP_REVOKE_REASON: &str =
+    "Overlaps an earlier registration, revoked by the integrity check";
+
+fn violation(
+    kind: IntegrityViolationKind,
+    profile_id: Option<u64>,
+    registration_id: Option<u64>,
+    product: Option<&str>,
+    description: impl Into<String>,
+    repair: impl Into<String>,
+) -> IntegrityViolation {
+    IntegrityViolation {
+        kind,
+        profile_id,
+        registration_id,
+        product: product.map(String::from),
+        description: description.into(),
+        repair: repair.into(),
+    }
+}
+
+// Same as HeldProduct, suspended registrations still hold their products until they expire
+fn holds_products(registration: &ProductRegistration, now: chrono::DateTime<chrono::Utc>) -> bool {
+    matches!(
+        registration.status,
+        RegistrationStatus::Active | RegistrationStatus::Suspended
+    ) && registration
+        .expiry_at
+        .is_none_or(|expiry_at| expiry_at > now)
+}
+
+///
+/// Checks that registrations lead to existing profiles, products and parents, that no product is held by two
+/// registrations of a profile and that the lookups match the registrations. The snapshot is repaired as it is
+/// checked, so it can be restored afterwards if the repairs should be kept
+///
+pub(crate) fn check(
+    snapshot: &mut Snapshot,
+    now: chrono::DateTime<chrono::Utc>,
+) -> Vec<IntegrityViolation> {
+    let mut violations = Vec::new();
+
+    check_products(snapshot, &mut violations);
+    check_registrations(snapshot, &mut violations);
+    check_overlapping_registrations(snapshot, now, &mut violations);
+    check_indexes(snapshot, &mut violations);
+
+    violations
+}
+
+// A bundled product which is missing is added back as a leaf product
+fn check_products(snapshot: &mut Snapshot, violations: &mut Vec<IntegrityViolation>) {
+    let mut missing_products = BTreeSet::new();
+    for (product, leaf_products) in &snapshot.products {
+        for leaf_product in leaf_products {
+            if !snapshot.products.contains_key(leaf_product)
+                && missing_products.insert(leaf_product.clone())
+            {
+                violations.push(violation(
+                    IntegrityViolationKind::MissingProduct,
+                    None,
+                    None,
+                    Some(leaf_product),
+                    format!("bundled in {} but not in the catalog", product),
+                    "added to the catalog as a leaf product",
+                ));
+            }
+        }
+    }
+    for product in missing_products {
+        snapshot.products.insert(product, BTreeSet::new());
+    }
+
+    let products = &snapshot.products;
+    snapshot.product_active_for.retain(|product, _| {
+        let exists = products.contains_key(product);
+        if !exists {
+            violations.push(violation(
+                IntegrityViolationKind::MissingProduct,
+                None,
+                None,
+                Some(product),
+                "has an active_for but is not in the catalog",
+                "active_for removed",
+            ));
+        }
+        exists
+    });
+}
+
+// Registrations which can't be reached are removed, while products they are registered for are added back
+fn check_registrations(snapshot: &mut Snapshot, violations: &mut Vec<IntegrityViolation>) {
+    let profile_ids: HashSet<u64> = snapshot.profiles.iter().map(|profile| profile.id).collect();
+    // registration id -> (parent id, profile id)
+    let owners: HashMap<u64, (Option<u64>, u64)> = snapshot
+        .product_registrations
+        .iter()
+        .map(|registration| {
+            (
+                registration.id,
+                (registration.parent_id, registration.profile_id),
+            )
+        })
+        .collect();
+
+    let mut removed = HashSet::new();
+    for registration in &snapshot.product_registrations {
+        if !profile_ids.contains(&registration.profile_id) {
+            violations.push(violation(
+                IntegrityViolationKind::MissingProfile,
+                Some(registration.profile_id),
+                Some(registration.id),
+                Some(&registration.product),
+                "registered to a profile which does not exist",
+                "registration removed, along with its children",
+            ));
+            removed.insert(registration.id);
+        }
+    }
+    for registration in &snapshot.product_registrations {
+        let Some(parent_id) = registration.parent_id else {
+            continue;
+        };
+        if removed.contains(&parent_id) || removed.contains(&registration.id) {
+            removed.insert(registration.id);
+            continue;
+        }
+
+        let problem = match owners.get(&parent_id) {
+            None => "parent registration does not exist",
+            Some((Some(_), _)) => "parent registration is a child registration itself",
+            Some((None, profile_id)) if *profile_id != registration.profile_id => {
+                "parent registration belongs to another profile"
+            }
+            Some(_) => continue,
+        };
+        violations.push(violation(
+            IntegrityViolationKind::InvalidParent,
+            Some(registration.profile_id),
+            Some(registration.id),
+            Some(&registration.product),
+            problem,
+            "child registration removed",
+        ));
+        removed.insert(registration.id);
+    }
+    snapshot
+        .product_registrations
+        .retain(|registration| !removed.contains(&registration.id));
+
+    let mut missing_products: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for registration in &snapshot.product_registrations {
+        if !snapshot.products.contains_key(&registration.product)
+            && !missing_products.contains_key(&registration.product)
+        {
+            violations.push(violation(
+                IntegrityViolationKind::MissingProduct,
+                Some(registration.profile_id),
+                Some(registration.id),
+                Some(&registration.product),
+                "registered but not in the catalog",
+                "added to the catalog, with the products of its child registrations as leaf products",
+            ));
+            missing_products.insert(registration.product.clone(), BTreeSet::new());
+        }
+    }
+    let products: HashMap<u64, &String> = snapshot
+        .product_registrations
+        .iter()
+        .map(|registration| (registration.id, &registration.product))
+        .collect();
+    for registration in &snapshot.product_registrations {
+        let bundle = registration
+            .parent_id
+            .and_then(|parent_id| missing_products.get_mut(products[&parent_id]));
+        if let Some(leaf_products) = bundle {
+            leaf_products.insert(registration.product.clone());
+        }
+    }
+    snapshot.products.extend(missing_products);
+}
+
+// The earliest registration keeps the product, later ones were let through by a conflict check which did not
+// see it, so they are revoked
+fn check_overlapping_registrations(
+    snapshot: &mut Snapshot,
+    now: chrono::DateTime<chrono::Utc>,
+    violations: &mut Vec<IntegrityViolation>,
+) {
+    let mut children: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut top_level: BTreeMap<(u64, u64), usize> = BTreeMap::new();
+    for (index, registration) in snapshot.product_registrations.iter().enumerate() {
+        match registration.parent_id {
+            Some(parent_id) => children.entry(parent_id).or_default().push(index),
+            None => {
+                top_level.insert((registration.profile_id, registration.id), index);
+            }
+        }
+    }
+
+    let mut held_products: HashMap<u64, HashSet<String>> = HashMap::new();
+    for ((profile_id, id), index) in top_level {
+        let family: Vec<usize> = std::iter::once(index)
+            .chain(children.remove(&id).unwrap_or_default())
+            .filter(|&index| holds_products(&snapshot.product_registrations[index], now))
+            .collect();
+
+        let held = held_products.entry(profile_id).or_default();
+        let overlapping = family
+            .iter()
+            .map(|&index| &snapshot.product_registrations[index].product)
+            .find(|product| held.contains(*product))
+            .cloned();
+        let Some(product) = overlapping else {
+            held.extend(
+                family
+                    .iter()
+                    .map(|&index| snapshot.product_registrations[index].product.clone()),
+            );
+            continue;
+        };
+
+        violations.push(violation(
+            IntegrityViolationKind::OverlappingRegistrations,
+            Some(profile_id),
+            Some(id),
+            Some(&product),
+            "product is also held by an earlier registration of the profile",
+            "registration revoked, along with its children",
+        ));
+        for index in family {
+            let registration = &mut snapshot.product_registrations[index];
+            registration.status = RegistrationStatus::Revoked;
+            registration.status_history.push(RegistrationStatusChange {
+                status: RegistrationStatus::Revoked,
+                changed_at: now,
+                reason: Some(OVERLAP_REVOKE_REASON.into()),
+            });
+        }
+    }
+}
+
+// The lookups are rebuilt from the registrations, keeping the order of the entries which were right
+fn check_indexes(snapshot: &mut Snapshot, violations: &mut Vec<IntegrityViolation>) {
+    let mut profile_registrations: BTreeMap<u64, BTreeSet<u64>> = BTreeMap::new();
+    let mut registration_children: BTreeMap<u64, BTreeSet<u64>> = BTreeMap::new();
+    let mut serial_code_registrations: BTreeMap<String, BTreeSet<u64>> = BTreeMap::new();
+    let mut profile_ids = HashMap::new();
+    for registration in &snapshot.product_registrations {
+        match registration.parent_id {
+            Some(parent_id) => registration_children.entry(parent_id).or_default(),
+            None => profile_registrations
+                .entry(registration.profile_id)
+                .or_default(),
+        }
+        .insert(registration.id);
+        serial_code_registrations
+            .entry(registration.serial_code.clone())
+            .or_default()
+            .insert(registration.id);
+        profile_ids.insert(registration.id, registration.profile_id);
+    }
+
+    check_index(
+        &mut snapshot.profile_to_product_registrations,
+        profile_registrations,
+        |profile_id, id, listed| {
+            violation(
+                IntegrityViolationKind::IndexMismatch,
+                Some(*profile_id),
+                Some(id),
+                None,
+                match listed {
+                    true => "listed as a registration of the profile, but is not one",
+                    false => "not listed as a registration of its profile",
+                },
+                match listed {
+                    true => "removed from the registrations of the profile",
+                    false => "added to the registrations of its profile",
+                },
+            )
+        },
+        violations,
+    );
+    check_index(
+        &mut snapshot.product_registrations_children,
+        registration_children,
+        |parent_id, id, listed| {
+            violation(
+                IntegrityViolationKind::IndexMismatch,
+                profile_ids.get(parent_id).copied(),
+                Some(id),
+                None,
+                match listed {
+                    true => "listed as a child of a registration, but is not one",
+                    false => "not listed as a child of its parent registration",
+                },
+                match listed {
+                    true => "removed from the children of the registration",
+                    false => "added to the children of its parent registration",
+                },
+            )
+        },
+        violations,
+    );
+    check_index(
+        &mut snapshot.serial_codes,
+        serial_code_registrations,
+        |_, id, listed| {
+            violation(
+                IntegrityViolationKind::IndexMismatch,
+                profile_ids.get(&id).copied(),
+                Some(id),
+                None,
+                match listed {
+                    true => "listed as holding a serial code it does not have",
+                    false => "not listed as holding its serial code",
+                },
+                match listed {
+                    true => "removed from the holders of the serial code",
+                    false => "added to the holders of its serial code",
+                },
+            )
+        },
+        violations,
+    );
+
+    let top_level_ids: HashSet<u64> = snapshot
+        .product_registrations
+        .iter()
+        .filter(|registration| registration.parent_id.is_none())
+        .map(|registration| registration.id)
+        .collect();
+    snapshot.product_registration_renewals.retain(|id, _| {
+        let exists = top_level_ids.contains(id);
+        if !exists {
+            violations.push(violation(
+                IntegrityViolationKind::IndexMismatch,
+                None,
+                Some(*id),
+                None,
+                "has renewals but is not a top level registration",
+                "renewals removed",
+            ));
+        }
+        exists
+    });
+}
+
+// Entries which are not expected are removed and expected ones which are missing are added in id order, which is
+// the order registrations are created in. `report` is given the key, the registration id and whether it was listed
+fn check_index<K: Ord + Clone>(
+    index: &mut BTreeMap<K, Vec<u64>>,
+    mut expected: BTreeMap<K, BTreeSet<u64>>,
+    report: impl Fn(&K, u64, bool) -> IntegrityViolation,
+    violations: &mut Vec<IntegrityViolation>,
+) {
+    index.retain(|key, ids| {
+        let mut expected_ids = expected.remove(key).unwrap_or_default();
+        let listed = ids.len();
+        ids.retain(|id| {
+            let is_expected = expected_ids.remove(id);
+            if !is_expected {
+                violations.push(report(key, *id, true));
+            }
+            is_expected
+        });
+        for id in expected_ids {
+            violations.push(report(key, id, false));
+            let position = ids.partition_point(|&listed_id| listed_id < id);
+            ids.insert(position, id);
+        }
+        // an emptied list is dropped, like one which was never filled
+        !ids.is_empty() || listed == 0
+    });
+    for (key, expected_ids) in expected {
+        for &id in &expected_ids {
+            violations.push(report(&key, id, false));
+        }
+        index.insert(key, expected_ids.into_iter().collect());
+    }
+}
@@ -0,0 +1,182 @@
+use std::{
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use rand::Rng;
+
+///
+/// Hands out ids for new registrations and API keys, ids are never reused
+///
+pub trait IdGenerator: Send + Sync {
+    fn next_id(&self) -> u64;
+
+    // Ids up to `last` are taken by data which was loaded, generators counting up carry on after them
+    fn taken_up_to(&self, _last: u64) {}
+}
+
+// 1, 2, 3, ...
+pub struct SequentialIdGenerator {
+    last: AtomicU64,
+}
+
+impl SequentialIdGenerator {
+    pub fn new() -> Self {
+        Self::starting_after(0)
+    }
+
+    // Carries on after ids which are already taken
+    pub fn starting_after(last: u64) -> Self {
+        Self {
+            last: AtomicU64::new(last),
+        }
+    }
+}
+
+impl Default for SequentialIdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdGenerator for SequentialIdGenerator {
+    fn next_id(&self) -> u64 {
+        self.last.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn taken_up_to(&self, last: u64) {
+        self.last.fetch_max(last, Ordering::Relaxed);
+    }
+}
+
+fn milliseconds_since(epoch_ms: u64) -> u64 {
+    (chrono::Utc::now().timestamp_millis() as u64).saturating_sub(epoch_ms)
+}
+
+// 2024-01-01T00:00:00Z
+const SNOWFLAKE_EPOCH_MS: u64 = 1_704_067_200_000;
+const SNOWFLAKE_NODE_BITS: u32 = 10;
+const SNOWFLAKE_SEQUENCE_BITS: u32 = 12;
+pub const MAX_SNOWFLAKE_NODE_ID: u16 = (1 << SNOWFLAKE_NODE_BITS) - 1;
+
+// Twitter style ids, 41 bits of milliseconds since SNOWFLAKE_EPOCH_MS, 10 bits of node id and a 12 bit sequence, so
+// several instances can hand out ids without talking to each other
+pub struct SnowflakeIdGenerator {
+    node_id: u64,
+    // (millisecond, sequence) of the last id
+    last: Mutex<(u64, u64)>,
+}
+
+impl SnowflakeIdGenerator {
+    pub fn new(node_id: u16) -> Result<Self, String> {
+        if node_id > MAX_SNOWFLAKE_NODE_ID {
+            return Err(format!(
+                "Snowflake node id:{} is out of range, node ids go up to {}",
+                node_id, MAX_SNOWFLAKE_NODE_ID
+            ));
+        }
+
+        Ok(Self {
+            node_id: node_id.into(),
+            last: Mutex::new((0, 0)),
+        })
+    }
+}
+
+impl IdGenerator for SnowflakeIdGenerator {
+    fn next_id(&self) -> u64 {
+        let mut last = self.last.lock().unwrap();
+        let (last_ms, last_sequence) = *last;
+        // the clock going backwards is treated as still being in the last millisecond
+        let mut ms = milliseconds_since(SNOWFLAKE_EPOCH_MS).max(last_ms);
+        let mut sequence = 0;
+        if ms == last_ms {
+            sequence = (last_sequence + 1) & ((1 << SNOWFLAKE_SEQUENCE_BITS) - 1);
+            if sequence == 0 {
+                // 4096 ids this millisecond already, wait for the next one
+                while ms <= last_ms {
+                    std::hint::spin_loop();
+                    ms = milliseconds_since(SNOWFLAKE_EPOCH_MS);
+                }
+            }
+        }
+        *last = (ms, sequence);
+
+        (ms << (SNOWFLAKE_NODE_BITS + SNOWFLAKE_SEQUENCE_BITS))
+            | (self.node_id << SNOWFLAKE_SEQUENCE_BITS)
+            | sequence
+    }
+}
+
+const ULID_RANDOM_BITS: u32 = 16;
+
+// ULID layout, a 48 bit millisecond timestamp followed by random bits, squeezed into 64 bits so 16 random bits are
+// left. Ids of the same millisecond increment the last one, so ids stay sorted by creation time.
+pub struct UlidIdGenerator {
+    last: Mutex<u64>,
+}
+
+impl UlidIdGenerator {
+    pub fn new() -> Self {
+        Self {
+            last: Mutex::new(0),
+        }
+    }
+}
+
+impl Default for UlidIdGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IdGenerator for UlidIdGenerator {
+    fn next_id(&self) -> u64 {
+        let mut last = self.last.lock().unwrap();
+        let random: u64 = rand::thread_rng().gen_range(0..1 << ULID_RANDOM_BITS);
+        let id = (milliseconds_since(0) << ULID_RANDOM_BITS) | random;
+        // within the same millisecond, or with the clock going backwards, the random part would not keep the order
+        *last = if id > *last { id } else { *last + 1 };
+        *last
+    }
+}
+
+///
+/// Id generator picked in the config
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdStrategy {
+    Sequential,
+    Snowflake,
+    Ulid,
+}
+
+impl IdStrategy {
+    // Only snowflakes use the node id
+    pub fn id_generator(self, node_id: u16) -> Result<Box<dyn IdGenerator>, String> {
+        Ok(match self {
+            IdStrategy::Sequential => Box::new(SequentialIdGenerator::new()),
+            IdStrategy::Snowflake => Box::new(SnowflakeIdGenerator::new(node_id)?),
+            IdStrategy::Ulid => Box::new(UlidIdGenerator::new()),
+        })
+    }
+}
+
+impl FromStr for IdStrategy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "sequential" => Ok(IdStrategy::Sequential),
+            "snowflake" => Ok(IdStrategy::Snowflake),
+            "ulid" => Ok(IdStrategy::Ulid),
+            _ => Err(format!(
+                "Unknown id strategy:{}, expected sequential, snowflake or ulid",
+                value
+            )),
+        }
+    }
+}
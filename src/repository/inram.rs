@@ -1,42 +1,162 @@
-use std::{cmp::min, collections::HashSet, sync::Mutex};
+use std::{
+    cmp::{max, min},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    fs,
+    hash::Hash,
+    io,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+};
 
 use super::{
-    model::{ProductRegistration, ProductRegistrationRecord, Profile},
-    ProfileRepository,
+    fsck,
+    id::{IdGenerator, SequentialIdGenerator},
+    journal::{self, FsyncPolicy, Journal, JournalEntry},
+    model::{
+        ApiKey, ApiKeyRole, IntegrityViolation, PooledSerialCode, ProductEntitlement,
+        ProductRegistration, ProductRegistrationRecord, ProductRegistrationRenewal, Profile,
+        PurchaseDetails, RegistrationStatus, RegistrationStatusChange, SerialPoolLot,
+        SerialTemplate, Snapshot, SnapshotSerialPool,
+    },
+    serial::{RandomSerialGenerator, SerialGenerator},
+    ProfileRepository, RepositoryError,
 };
 use dashmap::DashMap;
-use rand::Rng;
 
 pub struct InMemoryProfileRepository {
-    profiles: Vec<Profile>,
+    // ordered by id, which is the order profiles were created in with every IdStrategy
+    profiles: RwLock<BTreeMap<u64, Profile>>,
     // profile id -> [product registration ids]
     profile_to_product_registrations: DashMap<u64, Vec<u64>>,
-    product_registrations: Mutex<Vec<ProductRegistration>>,
+    // product registration id -> registration, sharded so profiles don't wait on each other
+    product_registrations: DashMap<u64, ProductRegistration>,
+    // profile id -> lock held by anything changing the profile's registrations, so checking for conflicts and
+    // storing the registrations happen together, taken before serial_code_lock
+    profile_locks: DashMap<u64, Arc<Mutex<()>>>,
+    // profile id -> products held by its active and suspended registrations, updated along with the registrations
+    // so conflicts can be checked without going through every registration of the profile
+    profile_held_products: DashMap<u64, HashMap<String, Vec<HeldProduct>>>,
+    // held from checking a serial code is free until the registration holding it is stored
+    serial_code_lock: Mutex<()>,
     // product registration id
     product_registrations_children: DashMap<u64, Vec<u64>>,
     // product SKU -> set(sub product SKUs)
     products: DashMap<String, HashSet<String>>,
     // Product SKU -> expiry time, if it is not in the map, the product does not expire
     product_active_for: DashMap<String, u64>,
-    serial_generator: fn() -> String,
+    // product SKU -> set(product SKUs it can be upgraded to)
+    product_upgrades: DashMap<String, HashSet<String>>,
+    // product SKU -> pattern of serial codes printed on the product
+    product_serial_formats: DashMap<String, String>,
+    // product SKU -> template its serial codes are generated from
+    product_serial_templates: DashMap<String, SerialTemplate>,
+    // serial code -> [product registration ids], oldest first, transfers and upgrades carry serial codes over
+    serial_codes: DashMap<String, Vec<u64>>,
+    // product SKU -> serial codes pre-allocated by the factory
+    serial_pools: DashMap<String, SerialPool>,
+    // serial code -> lot it was pooled for
+    serial_code_lots: DashMap<String, String>,
+    // lot -> [serial codes], in the order they were imported
+    lot_serial_codes: DashMap<String, Vec<String>>,
+    // top level product registration id -> renewals, oldest first
+    product_registration_renewals: DashMap<u64, Vec<ProductRegistrationRenewal>>,
+    api_keys: Mutex<Vec<ApiKey>>,
+    // key hash -> api key id
+    api_key_hashes: DashMap<String, u64>,
+    // held by anything changing the api keys, from looking them up until the change is stored
+    api_key_lock: Mutex<()>,
+    // held shared while a change is applied, and exclusively by anything reading or replacing all of the state at
+    // once, so it has all of a change or none of it
+    changes_lock: RwLock<()>,
+    // every change is appended before it is applied, None if nothing is persisted. Held while the change is applied,
+    // so changes are applied in the order they were journaled
+    journal: Option<Mutex<Journal>>,
+    // only one snapshot is written at a time
+    compaction_lock: Mutex<()>,
+    // ids of new registrations and api keys
+    id_generator: Box<dyn IdGenerator>,
+    serial_generator: Box<dyn SerialGenerator>,
     time_provider: fn() -> chrono::DateTime<chrono::Utc>,
 }
 
+// Expiry is never written back, registrations past their expiry_at are reported as expired
+fn registration_status(
+    registration: &ProductRegistration,
+    timestamp: chrono::DateTime<chrono::Utc>,
+) -> RegistrationStatus {
+    match (registration.status, registration.expiry_at) {
+        (RegistrationStatus::Active | RegistrationStatus::Suspended, Some(expires_at))
+            if expires_at <= timestamp =>
+        {
+            RegistrationStatus::Expired
+        }
+        (status, _) => status,
+    }
+}
+
 fn registration_is_active(
     registration: &ProductRegistration,
     timestamp: chrono::DateTime<chrono::Utc>,
 ) -> bool {
-    match registration.expiry_at {
-        None => true,
-        Some(expires_at) => timestamp < expires_at,
+    registration_status(registration, timestamp) == RegistrationStatus::Active
+}
+
+// Makes a derived expiry explicit, so callers see the same status as registration_status
+fn with_effective_status(
+    mut registration: ProductRegistration,
+    timestamp: chrono::DateTime<chrono::Utc>,
+) -> ProductRegistration {
+    let status = registration_status(&registration, timestamp);
+    if status != registration.status {
+        registration.status = status;
+        registration.status_history.push(RegistrationStatusChange {
+            status,
+            changed_at: registration.expiry_at.unwrap_or(timestamp),
+            reason: None,
+        });
     }
+
+    registration
 }
 
-pub fn random_serial_generator() -> String {
-    let mut rng = rand::thread_rng();
-    (0..15)
-        .map(|_| rng.sample(rand::distributions::Alphanumeric) as char)
-        .collect()
+fn set_registration_status(
+    registration: &mut ProductRegistration,
+    status: RegistrationStatus,
+    changed_at: chrono::DateTime<chrono::Utc>,
+    reason: Option<&str>,
+) {
+    *registration = with_effective_status(registration.clone(), changed_at);
+    registration.status = status;
+    registration.status_history.push(RegistrationStatusChange {
+        status,
+        changed_at,
+        reason: reason.map(String::from),
+    });
+}
+
+const MAX_SERIAL_CODE_ATTEMPTS: usize = 10;
+
+// A product held by a registration, it stops being held once expiry_at has passed, expired holders are kept until
+// their registration changes
+#[derive(Clone, Copy)]
+struct HeldProduct {
+    // top level registration id
+    registration_id: u64,
+    expiry_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Default)]
+struct SerialPool {
+    // handed out oldest first
+    available: VecDeque<PooledSerialCode>,
+    // lot -> number of serial codes imported for it
+    imported: HashMap<String, usize>,
+}
+
+impl Default for InMemoryProfileRepository {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub fn default_time_provider() -> chrono::DateTime<chrono::Utc> {
@@ -46,19 +166,37 @@ pub fn default_time_provider() -> chrono::DateTime<chrono::Utc> {
 impl InMemoryProfileRepository {
     pub fn new() -> Self {
         Self {
-            profiles: Vec::new(),
+            profiles: RwLock::new(BTreeMap::new()),
             profile_to_product_registrations: DashMap::new(),
-            product_registrations: Mutex::new(Vec::new()),
+            product_registrations: DashMap::new(),
+            profile_locks: DashMap::new(),
+            profile_held_products: DashMap::new(),
+            serial_code_lock: Mutex::new(()),
             product_registrations_children: DashMap::new(),
             products: DashMap::new(),
             product_active_for: DashMap::new(),
-            serial_generator: random_serial_generator,
+            product_upgrades: DashMap::new(),
+            product_serial_formats: DashMap::new(),
+            product_registration_renewals: DashMap::new(),
+            product_serial_templates: DashMap::new(),
+            serial_codes: DashMap::new(),
+            serial_pools: DashMap::new(),
+            serial_code_lots: DashMap::new(),
+            lot_serial_codes: DashMap::new(),
+            api_keys: Mutex::new(Vec::new()),
+            api_key_hashes: DashMap::new(),
+            api_key_lock: Mutex::new(()),
+            changes_lock: RwLock::new(()),
+            journal: None,
+            compaction_lock: Mutex::new(()),
+            id_generator: Box::new(SequentialIdGenerator::new()),
+            serial_generator: Box::new(RandomSerialGenerator),
             time_provider: default_time_provider,
         }
     }
 
     pub fn with_example_data(
-        serial_generator: fn() -> String,
+        serial_generator: impl SerialGenerator + 'static,
         time_provider: fn() -> chrono::DateTime<chrono::Utc>,
     ) -> Self {
         let profiles = Vec::from([
@@ -76,7 +214,7 @@ impl InMemoryProfileRepository {
             },
         ]);
 
-        let product_registrations = Vec::from([
+        let mut product_registrations = Vec::from([
             ProductRegistration {
                 id: 1,
                 parent_id: None,
@@ -84,6 +222,7 @@ impl InMemoryProfileRepository {
                 purchase_date: chrono::DateTime::parse_from_rfc3339("2023-01-15T15:04:05Z")
                     .unwrap()
                     .into(),
+                purchase_details: PurchaseDetails::default(),
                 expiry_at: Some(
                     chrono::DateTime::parse_from_rfc3339("2024-01-15T15:04:05Z")
                         .unwrap()
@@ -91,6 +230,13 @@ impl InMemoryProfileRepository {
                 ),
                 product: "ARIE4".into(),
                 serial_code: "A1B2C3D4".into(),
+                lot: None,
+                status: RegistrationStatus::Active,
+                status_history: Vec::new(),
+                transferred_from: None,
+                transferred_to: None,
+                upgraded_from: None,
+                upgraded_to: None,
             },
             ProductRegistration {
                 id: 2,
@@ -99,9 +245,17 @@ impl InMemoryProfileRepository {
                 purchase_date: chrono::DateTime::parse_from_rfc3339("2023-03-10T12:00:00Z")
                     .unwrap()
                     .into(),
+                purchase_details: PurchaseDetails::default(),
                 expiry_at: None,
                 product: "ARCC4".into(),
                 serial_code: "L3M4N5O6".into(),
+                lot: None,
+                status: RegistrationStatus::Active,
+                status_history: Vec::new(),
+                transferred_from: None,
+                transferred_to: None,
+                upgraded_from: None,
+                upgraded_to: None,
             },
             ProductRegistration {
                 id: 3,
@@ -110,6 +264,7 @@ impl InMemoryProfileRepository {
                 purchase_date: chrono::DateTime::parse_from_rfc3339("2022-12-25T08:30:00Z")
                     .unwrap()
                     .into(),
+                purchase_details: PurchaseDetails::default(),
                 expiry_at: Some(
                     chrono::DateTime::parse_from_rfc3339("2023-12-25T08:30:00Z")
                         .unwrap()
@@ -117,6 +272,13 @@ impl InMemoryProfileRepository {
                 ),
                 product: "ARCM1".into(),
                 serial_code: "Z5X6C7V8".into(),
+                lot: None,
+                status: RegistrationStatus::Active,
+                status_history: Vec::new(),
+                transferred_from: None,
+                transferred_to: None,
+                upgraded_from: None,
+                upgraded_to: None,
             },
         ]);
 
@@ -150,12 +312,23 @@ impl InMemoryProfileRepository {
 
         let profile_to_product_registrations: DashMap<u64, Vec<u64>> = DashMap::new();
         let product_registrations_children: DashMap<u64, Vec<u64>> = DashMap::new();
+        let serial_codes: DashMap<String, Vec<u64>> = DashMap::new();
+
+        for registration in product_registrations.iter_mut() {
+            registration.status_history.push(RegistrationStatusChange {
+                status: registration.status,
+                changed_at: registration.purchase_date,
+                reason: None,
+            });
 
-        for registration in product_registrations.iter() {
             profile_to_product_registrations
                 .entry(registration.profile_id)
                 .or_default()
                 .push(registration.id);
+            serial_codes
+                .entry(registration.serial_code.clone())
+                .or_default()
+                .push(registration.id);
 
             if let Some(parent_id) = registration.parent_id {
                 product_registrations_children
@@ -165,308 +338,3317 @@ impl InMemoryProfileRepository {
             }
         }
 
-        Self {
-            profiles,
+        let last_product_registration_id = product_registrations
+            .iter()
+            .map(|registration| registration.id)
+            .max()
+            .unwrap_or_default();
+        let repository = Self {
+            profiles: RwLock::new(
+                profiles
+                    .into_iter()
+                    .map(|profile| (profile.id, profile))
+                    .collect(),
+            ),
             profile_to_product_registrations,
-            product_registrations: Mutex::new(product_registrations),
+            product_registrations: product_registrations
+                .into_iter()
+                .map(|registration| (registration.id, registration))
+                .collect(),
+            profile_locks: DashMap::new(),
+            profile_held_products: DashMap::new(),
+            serial_code_lock: Mutex::new(()),
             product_registrations_children,
             products,
             product_active_for: DashMap::new(),
-            serial_generator,
+            product_upgrades: DashMap::new(),
+            product_serial_formats: DashMap::new(),
+            product_registration_renewals: DashMap::new(),
+            product_serial_templates: DashMap::new(),
+            serial_codes,
+            serial_pools: DashMap::new(),
+            serial_code_lots: DashMap::new(),
+            lot_serial_codes: DashMap::new(),
+            api_keys: Mutex::new(Vec::new()),
+            api_key_hashes: DashMap::new(),
+            api_key_lock: Mutex::new(()),
+            changes_lock: RwLock::new(()),
+            journal: None,
+            compaction_lock: Mutex::new(()),
+            id_generator: Box::new(SequentialIdGenerator::starting_after(
+                last_product_registration_id,
+            )),
+            serial_generator: Box::new(serial_generator),
             time_provider,
+        };
+        let profile_ids: Vec<u64> = repository
+            .profiles
+            .read()
+            .unwrap()
+            .keys()
+            .copied()
+            .collect();
+        for profile_id in profile_ids {
+            for id in repository.get_profile_product_registration_ids(profile_id) {
+                repository.index_held_products(id);
+            }
         }
-    }
 
-    fn get_active_registered_products(&self, profile_id: u64) -> HashSet<String> {
-        let Some(product_registration_ids) = self.profile_to_product_registrations.get(&profile_id)
-        else {
-            tracing::error!("Did not find profile_id:{}", profile_id);
-            return HashSet::new();
-        };
+        repository
+    }
 
-        let mut existing_products = HashSet::new();
+    ///
+    /// Replaces the sequential ids, ids which are already taken are skipped
+    ///
+    pub fn with_id_generator(mut self, id_generator: Box<dyn IdGenerator>) -> Self {
+        id_generator.taken_up_to(self.last_id());
+        self.id_generator = id_generator;
+        self
+    }
 
-        let now = (self.time_provider)();
-        for id in product_registration_ids.value() {
-            let Some(registration_record) = self.get_product_registration(*id) else {
-                continue;
-            };
+    ///
+    /// Persists every change to a journal in `data_dir`, which `compact` folds into a snapshot now and then.
+    /// State found in `data_dir` replaces the repository's, otherwise the repository's state is the starting point.
+    ///
+    pub fn with_journal(
+        mut self,
+        data_dir: impl AsRef<Path>,
+        fsync_policy: FsyncPolicy,
+    ) -> io::Result<Self> {
+        let data_dir = data_dir.as_ref();
+        fs::create_dir_all(data_dir)?;
 
-            if registration_is_active(&registration_record.registration, now) {
-                existing_products.insert(registration_record.registration.product);
+        let generation = match journal::read_snapshot(data_dir)? {
+            Some((generation, snapshot)) => {
+                self.restore(snapshot);
+                generation
+            }
+            None if journal::journal_generations(data_dir)?.is_empty() => {
+                journal::write_snapshot(data_dir, 0, self.snapshot())?;
+                0
             }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} has journals but no snapshot", data_dir.display()),
+                ))
+            }
+        };
 
-            for child_record in registration_record.children.iter() {
-                if registration_is_active(child_record, now) {
-                    existing_products.insert(child_record.product.clone());
-                }
+        // Journals older than the snapshot are left over from a compaction which did not finish removing them
+        let mut last_generation = generation;
+        let mut replayed = 0;
+        for journal_generation in journal::journal_generations(data_dir)? {
+            if journal_generation < generation {
+                continue;
             }
+            for entry in journal::read_journal(data_dir, journal_generation)? {
+                self.apply(entry);
+                replayed += 1;
+            }
+            last_generation = journal_generation;
         }
+        self.id_generator.taken_up_to(self.last_id());
+        tracing::info!(
+            "Loaded the snapshot of {} and replayed {} journal entries",
+            data_dir.display(),
+            replayed
+        );
 
-        existing_products
+        self.journal = Some(Mutex::new(Journal::open(
+            data_dir,
+            last_generation,
+            fsync_policy,
+        )?));
+        self.compact()?;
+
+        Ok(self)
     }
 
-    fn append_product_registration(
-        &self,
-        registrations: &mut Vec<ProductRegistration>,
-        profile_id: u64,
-        parent_id: Option<u64>,
-        purchase_date: chrono::DateTime<chrono::Utc>,
-        product_sku: &str,
-    ) -> ProductRegistration {
-        let new_registration_id = (registrations.len() + 1) as u64;
-        let product_expiration = self.product_active_for.get(product_sku);
-        let registration = ProductRegistration {
-            id: new_registration_id,
-            profile_id,
-            parent_id,
-            purchase_date,
-            expiry_at: product_expiration.map(|expires_in| {
-                purchase_date + chrono::Duration::seconds(*expires_in.value() as i64)
-            }),
-            product: product_sku.into(),
-            serial_code: (self.serial_generator)(),
+    ///
+    /// Writes a snapshot and removes the journals it covers, nothing is done if there were no changes since the last one
+    ///
+    pub fn compact(&self) -> io::Result<()> {
+        let _compaction_guard = self.compaction_lock.lock().unwrap();
+
+        let Some(journal) = &self.journal else {
+            return Ok(());
+        };
+        let (data_dir, generation, snapshot) = {
+            let mut journal = journal.lock().unwrap();
+            if journal.is_empty() {
+                return Ok(());
+            }
+            // Changes are applied while holding the journal, so the snapshot has exactly the journaled ones
+            let snapshot = self.snapshot();
+            let generation = journal.rotate()?;
+            (journal.data_dir().to_owned(), generation, snapshot)
         };
-        registrations.push(registration.clone());
+        journal::write_snapshot(&data_dir, generation, snapshot)?;
+        journal::remove_journals_before(&data_dir, generation)
+    }
 
-        registration
+    ///
+    /// Flushes the journal to disk, for FsyncPolicy::EverySecond
+    ///
+    pub fn sync_journal(&self) -> io::Result<()> {
+        match &self.journal {
+            Some(journal) => journal.lock().unwrap().sync(),
+            None => Ok(()),
+        }
     }
-}
 
-impl ProfileRepository for InMemoryProfileRepository {
-    fn get_profiles(&self, start: u64, count: usize) -> Vec<Profile> {
-        let start = start as usize;
+    fn snapshot(&self) -> Snapshot {
+        let mut product_registrations: Vec<ProductRegistration> = self
+            .product_registrations
+            .iter()
+            .map(|registration| registration.value().clone())
+            .collect();
+        product_registrations.sort_by_key(|registration| registration.id);
 
-        if start >= self.profiles.len() {
-            return Vec::new();
+        Snapshot {
+            profiles: self.profiles.read().unwrap().values().cloned().collect(),
+            product_registrations,
+            profile_to_product_registrations: sorted(
+                &self.profile_to_product_registrations,
+                Vec::clone,
+            ),
+            product_registrations_children: sorted(
+                &self.product_registrations_children,
+                Vec::clone,
+            ),
+            products: sorted(&self.products, |products| {
+                products.iter().cloned().collect()
+            }),
+            product_active_for: sorted(&self.product_active_for, |active_for| *active_for),
+            product_upgrades: sorted(&self.product_upgrades, |upgrades| {
+                upgrades.iter().cloned().collect()
+            }),
+            product_serial_formats: sorted(&self.product_serial_formats, String::clone),
+            product_serial_templates: sorted(&self.product_serial_templates, SerialTemplate::clone),
+            serial_codes: sorted(&self.serial_codes, Vec::clone),
+            serial_pools: sorted(&self.serial_pools, |serial_pool| SnapshotSerialPool {
+                available: serial_pool.available.iter().cloned().collect(),
+                imported: serial_pool
+                    .imported
+                    .iter()
+                    .map(|(lot, imported)| (lot.clone(), *imported))
+                    .collect(),
+            }),
+            lot_serial_codes: sorted(&self.lot_serial_codes, Vec::clone),
+            product_registration_renewals: sorted(&self.product_registration_renewals, Vec::clone),
+            api_keys: self.api_keys.lock().unwrap().clone(),
         }
+    }
 
-        let end = min(start + count, self.profiles.len());
+    // Highest id of the registrations and api keys
+    fn last_id(&self) -> u64 {
+        let last_registration_id = self
+            .product_registrations
+            .iter()
+            .map(|registration| *registration.key())
+            .max();
+        let last_api_key_id = self
+            .api_keys
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|api_key| api_key.id)
+            .max();
 
-        self.profiles.get(start..end).unwrap_or_default().to_vec()
+        max(last_registration_id, last_api_key_id).unwrap_or(0)
     }
 
-    fn get_profile(&self, id: u64) -> Option<Profile> {
-        self.profiles.get((id - 1) as usize).cloned()
+    // Replaces everything stored, the lookups left out of the snapshot are rebuilt
+    fn restore(&self, snapshot: Snapshot) {
+        *self.profiles.write().unwrap() = snapshot
+            .profiles
+            .into_iter()
+            .map(|profile| (profile.id, profile))
+            .collect();
+        replace(
+            &self.product_registrations,
+            snapshot
+                .product_registrations
+                .into_iter()
+                .map(|registration| (registration.id, registration)),
+        );
+        replace(
+            &self.profile_to_product_registrations,
+            snapshot.profile_to_product_registrations,
+        );
+        replace(
+            &self.product_registrations_children,
+            snapshot.product_registrations_children,
+        );
+        replace(
+            &self.products,
+            snapshot
+                .products
+                .into_iter()
+                .map(|(product, subproducts)| (product, subproducts.into_iter().collect())),
+        );
+        replace(&self.product_active_for, snapshot.product_active_for);
+        replace(
+            &self.product_upgrades,
+            snapshot
+                .product_upgrades
+                .into_iter()
+                .map(|(product, upgrades)| (product, upgrades.into_iter().collect())),
+        );
+        replace(
+            &self.product_serial_formats,
+            snapshot.product_serial_formats,
+        );
+        replace(
+            &self.product_serial_templates,
+            snapshot.product_serial_templates,
+        );
+        replace(&self.serial_codes, snapshot.serial_codes);
+        replace(
+            &self.serial_pools,
+            snapshot
+                .serial_pools
+                .into_iter()
+                .map(|(product, serial_pool)| {
+                    (
+                        product,
+                        SerialPool {
+                            available: serial_pool.available.into(),
+                            imported: serial_pool.imported.into_iter().collect(),
+                        },
+                    )
+                }),
+        );
+        replace(
+            &self.serial_code_lots,
+            snapshot
+                .lot_serial_codes
+                .iter()
+                .flat_map(|(lot, serial_codes)| {
+                    serial_codes
+                        .iter()
+                        .map(|serial_code| (serial_code.clone(), lot.clone()))
+                }),
+        );
+        replace(&self.lot_serial_codes, snapshot.lot_serial_codes);
+        replace(
+            &self.product_registration_renewals,
+            snapshot.product_registration_renewals,
+        );
+        let mut api_keys = self.api_keys.lock().unwrap();
+        replace(
+            &self.api_key_hashes,
+            snapshot
+                .api_keys
+                .iter()
+                .map(|api_key| (api_key.key_hash.clone(), api_key.id)),
+        );
+        *api_keys = snapshot.api_keys;
+        drop(api_keys);
+
+        self.profile_held_products.clear();
+        let top_level_ids: Vec<u64> = self
+            .profile_to_product_registrations
+            .iter()
+            .flat_map(|ids| ids.value().clone())
+            .collect();
+        for id in top_level_ids {
+            self.index_held_products(id);
+        }
+        self.id_generator.taken_up_to(self.last_id());
     }
 
-    fn get_product_registrations_for_profile(
-        &self,
-        profile_id: u64,
-        start: u64,
-        count: usize,
-    ) -> Vec<ProductRegistrationRecord> {
-        let Some(product_registration_ids) = self.profile_to_product_registrations.get(&profile_id)
-        else {
-            return Vec::new();
+    // Journals the change before applying it, so it is only made once it would survive a restart
+    fn commit(&self, entry: JournalEntry) -> Result<(), RepositoryError> {
+        // Restoring a snapshot replaces everything, nothing else can be applied alongside it
+        if matches!(entry, JournalEntry::SnapshotRestored(_)) {
+            let _changes_guard = self.changes_lock.write().unwrap();
+            self.commit_holding_changes(entry)
+        } else {
+            let _changes_guard = self.changes_lock.read().unwrap();
+            self.commit_holding_changes(entry)
+        }
+    }
+
+    // For callers which already hold changes_lock
+    fn commit_holding_changes(&self, entry: JournalEntry) -> Result<(), RepositoryError> {
+        let Some(journal) = &self.journal else {
+            self.apply(entry);
+            return Ok(());
         };
 
-        let start = start as usize;
-        let end = min(start + count, product_registration_ids.len());
-        if start >= product_registration_ids.len() {
-            return Vec::new();
+        let pending_sync = {
+            let mut journal = journal.lock().unwrap();
+            let pending_sync = journal.append(&entry).map_err(|err| {
+                tracing::error!("Unable to append to the journal: {}", err);
+                RepositoryError::Storage(err.to_string())
+            })?;
+            self.apply(entry);
+            pending_sync
+        };
+        // Waited for once the journal is released, so changes appended in the meantime share the sync
+        pending_sync.wait().map_err(|err| {
+            tracing::error!("Unable to sync the journal: {}", err);
+            RepositoryError::Storage(err.to_string())
+        })
+    }
+
+    // Only depends on the entry and what was applied before it, so replaying the journal ends up in the same state
+    fn apply(&self, entry: JournalEntry) {
+        match entry {
+            JournalEntry::ProductStored {
+                product,
+                leaf_products,
+                active_for,
+            } => {
+                if let Some(active_seconds) = active_for {
+                    self.product_active_for
+                        .insert(product.clone(), active_seconds);
+                }
+                self.products
+                    .insert(product, leaf_products.into_iter().collect());
+            }
+            JournalEntry::ProductUpgradeStored { product, upgrade } => {
+                self.product_upgrades
+                    .entry(product)
+                    .or_default()
+                    .insert(upgrade);
+            }
+            JournalEntry::ProductSerialFormatStored {
+                product,
+                serial_format,
+            } => {
+                self.product_serial_formats.insert(product, serial_format);
+            }
+            JournalEntry::ProductSerialTemplateStored {
+                product,
+                serial_template,
+            } => {
+                self.product_serial_templates
+                    .insert(product, serial_template);
+            }
+            JournalEntry::SerialCodesPooled {
+                product,
+                serial_codes,
+            } => {
+                let mut serial_pool = self.serial_pools.entry(product).or_default();
+                for pooled in serial_codes {
+                    self.serial_code_lots
+                        .insert(pooled.serial_code.clone(), pooled.lot.clone());
+                    self.lot_serial_codes
+                        .entry(pooled.lot.clone())
+                        .or_default()
+                        .push(pooled.serial_code.clone());
+                    *serial_pool.imported.entry(pooled.lot.clone()).or_default() += 1;
+                    serial_pool.available.push_back(pooled);
+                }
+            }
+            JournalEntry::ProductRegistrationsStored {
+                registrations,
+                renewals,
+            } => {
+                self.store_product_registrations(registrations, renewals);
+            }
+            JournalEntry::SnapshotRestored(snapshot) => self.restore(*snapshot),
+            JournalEntry::ApiKeyStored(api_key) => {
+                let mut api_keys = self.api_keys.lock().unwrap();
+                self.api_key_hashes
+                    .insert(api_key.key_hash.clone(), api_key.id);
+                match api_keys.iter_mut().find(|stored| stored.id == api_key.id) {
+                    Some(stored) => *stored = api_key,
+                    None => api_keys.push(api_key),
+                }
+            }
         }
+    }
 
-        product_registration_ids
-            .get(start..end)
+    // The lock is cloned out of the map, so waiting for it does not block other profiles in the same shard
+    fn profile_lock(&self, profile_id: u64) -> Arc<Mutex<()>> {
+        self.profile_locks.entry(profile_id).or_default().clone()
+    }
+
+    // Copied out, so no shard of the map is held while the registrations are looked up
+    fn get_profile_product_registration_ids(&self, profile_id: u64) -> Vec<u64> {
+        self.profile_to_product_registrations
+            .get(&profile_id)
+            .map(|ids| ids.value().clone())
             .unwrap_or_default()
-            .iter()
-            .filter_map(|id| self.get_product_registration(*id))
+    }
+
+    fn get_stored_product_registration(&self, id: u64) -> Option<ProductRegistration> {
+        self.product_registrations
+            .get(&id)
+            .map(|registration| registration.value().clone())
+    }
+
+    // Changed copies of the stored registrations, for storing them with commit
+    fn updated_product_registrations(
+        &self,
+        ids: impl Iterator<Item = u64>,
+        mut update: impl FnMut(&mut ProductRegistration),
+    ) -> Vec<ProductRegistration> {
+        ids.filter_map(|id| self.get_stored_product_registration(id))
+            .map(|mut registration| {
+                update(&mut registration);
+                registration
+            })
             .collect()
     }
 
-    fn get_product_registration(&self, id: u64) -> Option<ProductRegistrationRecord> {
-        let guard = self.product_registrations.lock().unwrap();
+    // A registration never moves to another profile, so its lock can be taken before looking the registration up
+    fn get_product_registration_profile_id(&self, id: u64) -> Result<u64, RepositoryError> {
+        self.product_registrations
+            .get(&id)
+            .map(|registration| registration.profile_id)
+            .ok_or(RepositoryError::NotFound)
+    }
 
-        let registration = guard.get((id - 1) as usize)?.to_owned();
-        let product_registration_children: Vec<ProductRegistration> = self
+    // A sequential generator replacing the one of the example data starts over, so taken ids are skipped
+    fn next_product_registration_id(&self) -> u64 {
+        loop {
+            let id = self.id_generator.next_id();
+            if !self.product_registrations.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
+    // Ids for a registration and its children, in order
+    fn next_product_registration_ids(&self, count: usize) -> impl Iterator<Item = u64> + '_ {
+        (0..count).map(|_| self.next_product_registration_id())
+    }
+
+    fn get_stored_product_registration_children(&self, id: u64) -> Vec<ProductRegistration> {
+        let child_ids = self
             .product_registrations_children
-            .get(&registration.id)
-            .map(|subregistrations| {
-                subregistrations
-                    .iter()
-                    .filter_map(|child_id| guard.get((child_id - 1) as usize))
-                    .cloned()
-                    .collect()
-            })
+            .get(&id)
+            .map(|child_ids| child_ids.value().clone())
             .unwrap_or_default();
 
-        Some(ProductRegistrationRecord {
-            registration,
-            children: product_registration_children,
-        })
+        child_ids
+            .into_iter()
+            .filter_map(|child_id| self.get_stored_product_registration(child_id))
+            .collect()
     }
 
-    fn product_exists(&self, product: &str) -> bool {
-        self.products.contains_key(product)
+    // Brings the products held by a top level registration up to date, has to be called after every change to it
+    fn index_held_products(&self, id: u64) {
+        let Some(registration) = self.get_stored_product_registration(id) else {
+            return;
+        };
+        let children = self.get_stored_product_registration_children(id);
+
+        let mut held_products = self
+            .profile_held_products
+            .entry(registration.profile_id)
+            .or_default();
+        for holders in held_products.values_mut() {
+            holders.retain(|held| held.registration_id != id);
+        }
+        held_products.retain(|_, holders| !holders.is_empty());
+        // Suspended registrations still hold on to their products, so they can be resumed later, expiry is left to
+        // HeldProduct as it is never written back
+        let holding = std::iter::once(&registration)
+            .chain(children.iter())
+            .filter(|registration| {
+                matches!(
+                    registration.status,
+                    RegistrationStatus::Active | RegistrationStatus::Suspended
+                )
+            });
+        for registration in holding {
+            held_products
+                .entry(registration.product.clone())
+                .or_default()
+                .push(HeldProduct {
+                    registration_id: id,
+                    expiry_at: registration.expiry_at,
+                });
+        }
     }
 
-    fn insert_product(
+    // Which of the products the profile already holds, looked up one by one so it doesn't matter how many it holds
+    fn get_conflicting_products(
         &self,
-        product: &str,
-        subproducts: &[String],
-        active_for: Option<u64>,
+        profile_id: u64,
+        products: &HashSet<String>,
+        excluded_registration_id: Option<u64>,
     ) -> HashSet<String> {
-        let mut products_to_add = HashSet::new();
-        let mut visited_products = HashSet::new();
-
-        for subproduct in subproducts {
-            find_subproduct_dfs(
-                subproduct,
-                &self.products,
-                &mut visited_products,
-                &mut products_to_add,
-            );
-        }
-
-        self.products
-            .insert(product.to_owned(), products_to_add.clone());
-        if let Some(active_seconds) = active_for {
-            self.product_active_for
-                .insert(product.to_owned(), active_seconds);
-        }
+        let now = (self.time_provider)();
+        let Some(held_products) = self.profile_held_products.get(&profile_id) else {
+            return HashSet::new();
+        };
 
-        products_to_add
+        products
+            .iter()
+            .filter(|product| {
+                held_products.get(*product).is_some_and(|holders| {
+                    holders.iter().any(|held| {
+                        Some(held.registration_id) != excluded_registration_id
+                            && held.expiry_at.is_none_or(|expiry_at| expiry_at > now)
+                    })
+                })
+            })
+            .cloned()
+            .collect()
     }
 
-    fn insert_product_registration(
+    // Products of a profile whose registration satisfies `is_registered`, now or as of a point in time
+    fn get_registered_products(
         &self,
         profile_id: u64,
-        product_sku: &str,
-    ) -> Result<ProductRegistrationRecord, HashSet<String>> {
-        let registered_products = self.get_active_registered_products(profile_id);
+        as_of: Option<chrono::DateTime<chrono::Utc>>,
+        is_registered: fn(&ProductRegistration, chrono::DateTime<chrono::Utc>) -> bool,
+    ) -> HashMap<String, ProductEntitlement> {
+        let timestamp = as_of.unwrap_or_else(self.time_provider);
+        if !self
+            .profile_to_product_registrations
+            .contains_key(&profile_id)
+        {
+            tracing::error!("Did not find profile_id:{}", profile_id);
+            return HashMap::new();
+        }
 
-        let mut visited_products = HashSet::new();
-        let mut products_to_add = HashSet::new();
+        let mut existing_products = HashMap::new();
 
-        find_subproduct_dfs(
-            product_sku,
-            &self.products,
-            &mut visited_products,
-            &mut products_to_add,
-        );
+        for id in self.get_profile_product_registration_ids(profile_id) {
+            let Some(registration_record) = self.find_product_registration(id, as_of) else {
+                continue;
+            };
 
-        let intersection: HashSet<String> = products_to_add
-            .intersection(&registered_products)
-            .cloned()
-            .collect();
-        if !intersection.is_empty() {
-            return Err(intersection);
+            let registered = std::iter::once(&registration_record.registration)
+                .chain(registration_record.children.iter())
+                .filter(|registration| is_registered(registration, timestamp));
+            for registration in registered {
+                existing_products.insert(
+                    registration.product.clone(),
+                    ProductEntitlement {
+                        product: registration.product.clone(),
+                        registration_id: id,
+                        expiry_at: registration.expiry_at,
+                    },
+                );
+            }
         }
 
+        existing_products
+    }
+
+    // Looks up a registration as it is now, or as it was at `as_of` (None if it did not exist yet)
+    fn find_product_registration(
+        &self,
+        id: u64,
+        as_of: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Option<ProductRegistrationRecord> {
         let now = (self.time_provider)();
-        let mut registrations = self.product_registrations.lock().unwrap();
-        let parent_registration = self.append_product_registration(
-            &mut registrations,
-            profile_id,
-            None,
-            now,
-            product_sku,
-        );
-        self.profile_to_product_registrations
-            .entry(profile_id)
-            .or_default()
-            .push(parent_registration.id);
+        let registration_at = |registration: ProductRegistration| match as_of {
+            Some(as_of) => self.registration_at(&registration, as_of),
+            None => Some(with_effective_status(registration, now)),
+        };
 
-        let mut child_registrations = Vec::new();
-        for child in products_to_add {
-            let child_registration = self.append_product_registration(
-                &mut registrations,
-                profile_id,
-                Some(parent_registration.id),
-                now,
-                &child,
-            );
-            self.product_registrations_children
-                .entry(parent_registration.id)
-                .or_default()
-                .push(child_registration.id);
-            child_registrations.push(child_registration);
-        }
-        Ok(ProductRegistrationRecord {
-            registration: parent_registration,
-            children: child_registrations,
+        let registration = registration_at(self.get_stored_product_registration(id)?)?;
+        let product_registration_children: Vec<ProductRegistration> = self
+            .get_stored_product_registration_children(registration.id)
+            .into_iter()
+            .filter_map(registration_at)
+            .collect();
+
+        Some(ProductRegistrationRecord {
+            registration,
+            children: product_registration_children,
         })
     }
-}
 
-fn find_subproduct_dfs(
-    product: &str,
-    existing_products: &DashMap<String, HashSet<String>>,
-    visited_products: &mut HashSet<String>,
-    products_to_add: &mut HashSet<String>,
-) {
-    if visited_products.contains(product) {
-        return;
-    }
+    // Rewinds status changes and renewals made after `timestamp`
+    fn registration_at(
+        &self,
+        registration: &ProductRegistration,
+        timestamp: chrono::DateTime<chrono::Utc>,
+    ) -> Option<ProductRegistration> {
+        let created_at = registration
+            .status_history
+            .first()
+            .map(|change| change.changed_at)
+            .unwrap_or(registration.purchase_date);
+        if created_at > timestamp {
+            return None;
+        }
 
-    visited_products.insert(product.to_owned());
+        let mut registration = registration.clone();
+        registration
+            .status_history
+            .retain(|change| change.changed_at <= timestamp);
+        if let Some(change) = registration.status_history.last() {
+            registration.status = change.status;
+        }
 
-    let Some(subproducts) = existing_products.get(product) else {
+        if let Some(renewals) = self.product_registration_renewals.get(&registration.id) {
+            if let Some(renewal) = renewals
+                .iter()
+                .find(|renewal| renewal.renewed_at > timestamp)
+            {
+                registration.expiry_at = Some(renewal.previous_expiry_at);
+            }
+        }
+
+        if registration.status != RegistrationStatus::Transferred {
+            registration.transferred_to = None;
+        }
+        if registration.status != RegistrationStatus::Upgraded {
+            registration.upgraded_to = None;
+        }
+
+        Some(with_effective_status(registration, timestamp))
+    }
+
+    // Pooled serial codes are used up first, ones customers already registered by hand are skipped. They stay in the
+    // pool until the registration taking them is stored.
+    fn take_pooled_serial_code(
+        &self,
+        product_sku: &str,
+        reserved: &mut HashSet<String>,
+    ) -> Option<PooledSerialCode> {
+        let serial_pool = self.serial_pools.get(product_sku)?;
+        let pooled = serial_pool
+            .available
+            .iter()
+            .find(|pooled| {
+                !self.serial_codes.contains_key(&pooled.serial_code)
+                    && !reserved.contains(&pooled.serial_code)
+            })?
+            .clone();
+        reserved.insert(pooled.serial_code.clone());
+
+        Some(pooled)
+    }
+
+    // Drops the registered serial codes at the front of the pool, which is where the ones handed out are taken from
+    fn remove_registered_pooled_serial_codes(&self, product_sku: &str) {
+        let Some(mut serial_pool) = self.serial_pools.get_mut(product_sku) else {
+            return;
+        };
+
+        let mut removed = false;
+        while serial_pool
+            .available
+            .front()
+            .is_some_and(|pooled| self.serial_codes.contains_key(&pooled.serial_code))
+        {
+            serial_pool.available.pop_front();
+            removed = true;
+        }
+        if removed && serial_pool.available.is_empty() {
+            tracing::warn!(
+                "Serial pool of product:{} is depleted, serial codes will be generated",
+                product_sku
+            );
+        }
+    }
+
+    // Generated serial codes are never handed out twice, not even ones of revoked registrations. The lot is set
+    // when the serial code is taken from the pool, generated ones never collide with pooled serial codes.
+    fn generate_serial_code(
+        &self,
+        product_sku: &str,
+        reserved: &mut HashSet<String>,
+    ) -> Result<(String, Option<String>), RepositoryError> {
+        if let Some(pooled) = self.take_pooled_serial_code(product_sku, reserved) {
+            return Ok((pooled.serial_code, Some(pooled.lot)));
+        }
+
+        let serial_template = self
+            .get_product_serial_template(product_sku)
+            .unwrap_or_default();
+
+        for _ in 0..MAX_SERIAL_CODE_ATTEMPTS {
+            let serial_code = self.serial_generator.generate(&serial_template);
+            if !self.serial_codes.contains_key(&serial_code)
+                && !self.serial_code_lots.contains_key(&serial_code)
+                && reserved.insert(serial_code.clone())
+            {
+                return Ok((serial_code, None));
+            }
+
+            tracing::warn!(
+                "Generated serial_code:{} for product:{} is taken, retrying",
+                serial_code,
+                product_sku
+            );
+        }
+
+        Err(RepositoryError::SerialCodesExhausted(product_sku.into()))
+    }
+
+    // Latest registration holding the serial code, preferring ones which were not revoked
+    fn find_serial_code_holder(&self, serial_code: &str) -> Option<ProductRegistration> {
+        let ids = self
+            .serial_codes
+            .get(serial_code)
+            .map(|ids| ids.value().clone())?;
+        let holders: Vec<ProductRegistration> = ids
+            .iter()
+            .rev()
+            .filter_map(|id| self.get_stored_product_registration(*id))
+            .collect();
+
+        holders
+            .iter()
+            .find(|registration| registration.status != RegistrationStatus::Revoked)
+            .or(holders.first())
+            .cloned()
+    }
+
+    fn new_product_registration(
+        &self,
+        id: u64,
+        profile_id: u64,
+        parent_id: Option<u64>,
+        purchase_date: chrono::DateTime<chrono::Utc>,
+        product_sku: &str,
+        (serial_code, lot): (String, Option<String>),
+    ) -> ProductRegistration {
+        let product_expiration = self.product_active_for.get(product_sku);
+        ProductRegistration {
+            id,
+            profile_id,
+            parent_id,
+            purchase_date,
+            purchase_details: PurchaseDetails::default(),
+            expiry_at: product_expiration.map(|expires_in| {
+                purchase_date + chrono::Duration::seconds(*expires_in.value() as i64)
+            }),
+            product: product_sku.into(),
+            serial_code,
+            lot,
+            status: RegistrationStatus::Active,
+            status_history: Vec::from([RegistrationStatusChange {
+                status: RegistrationStatus::Active,
+                changed_at: purchase_date,
+                reason: None,
+            }]),
+            transferred_from: None,
+            transferred_to: None,
+            upgraded_from: None,
+            upgraded_to: None,
+        }
+    }
+
+    fn new_transferred_product_registration(
+        &self,
+        id: u64,
+        previous: &ProductRegistration,
+        profile_id: u64,
+        parent_id: Option<u64>,
+        transferred_at: chrono::DateTime<chrono::Utc>,
+    ) -> ProductRegistration {
+        let status = RegistrationStatus::Active;
+        ProductRegistration {
+            id,
+            profile_id,
+            parent_id,
+            purchase_date: previous.purchase_date,
+            purchase_details: previous.purchase_details.clone(),
+            expiry_at: previous.expiry_at,
+            product: previous.product.clone(),
+            serial_code: previous.serial_code.clone(),
+            lot: previous.lot.clone(),
+            status,
+            status_history: Vec::from([RegistrationStatusChange {
+                status,
+                changed_at: transferred_at,
+                reason: Some(String::from("transferred in")),
+            }]),
+            transferred_from: Some(previous.id),
+            transferred_to: None,
+            upgraded_from: None,
+            upgraded_to: None,
+        }
+    }
+
+    // Registrations are stored in the order given, new children come before their parent so a parent is never seen
+    // without its children, and the products are indexed last
+    fn store_product_registrations(
+        &self,
+        registrations: Vec<ProductRegistration>,
+        renewals: Vec<ProductRegistrationRenewal>,
+    ) {
+        let mut top_level_ids = Vec::new();
+        for registration in registrations {
+            let id = registration.id;
+            let top_level_id = registration.parent_id.unwrap_or(id);
+            if !top_level_ids.contains(&top_level_id) {
+                top_level_ids.push(top_level_id);
+            }
+
+            let (parent_id, profile_id) = (registration.parent_id, registration.profile_id);
+            let (product, serial_code) = (
+                registration.product.clone(),
+                registration.serial_code.clone(),
+            );
+            if self
+                .product_registrations
+                .insert(id, registration)
+                .is_some()
+            {
+                continue;
+            }
+
+            match parent_id {
+                Some(parent_id) => self
+                    .product_registrations_children
+                    .entry(parent_id)
+                    .or_default()
+                    .push(id),
+                None => self
+                    .profile_to_product_registrations
+                    .entry(profile_id)
+                    .or_default()
+                    .push(id),
+            }
+            let pooled = self.serial_code_lots.contains_key(&serial_code);
+            self.serial_codes.entry(serial_code).or_default().push(id);
+            if pooled {
+                self.remove_registered_pooled_serial_codes(&product);
+            }
+        }
+
+        for renewal in renewals {
+            self.product_registration_renewals
+                .entry(renewal.registration_id)
+                .or_default()
+                .push(renewal);
+        }
+        for id in top_level_ids {
+            self.index_held_products(id);
+        }
+    }
+}
+
+// A new registration in the order it is stored in
+fn children_first(record: &ProductRegistrationRecord) -> Vec<ProductRegistration> {
+    record
+        .children
+        .iter()
+        .chain(std::iter::once(&record.registration))
+        .cloned()
+        .collect()
+}
+
+// Clears the map before filling it again
+fn replace<K: Eq + Hash, V>(map: &DashMap<K, V>, entries: impl IntoIterator<Item = (K, V)>) {
+    map.clear();
+    for (key, value) in entries {
+        map.insert(key, value);
+    }
+}
+
+// DashMaps go into snapshots sorted, so the same state always makes the same snapshot
+fn sorted<K: Clone + Ord + Eq + Hash, V, T>(
+    map: &DashMap<K, V>,
+    convert: impl Fn(&V) -> T,
+) -> BTreeMap<K, T> {
+    map.iter()
+        .map(|entry| (entry.key().clone(), convert(entry.value())))
+        .collect()
+}
+
+impl ProfileRepository for InMemoryProfileRepository {
+    fn current_time(&self) -> chrono::DateTime<chrono::Utc> {
+        (self.time_provider)()
+    }
+
+    fn get_profiles(&self, start: u64, count: usize) -> Vec<Profile> {
+        self.profiles
+            .read()
+            .unwrap()
+            .values()
+            .skip(start as usize)
+            .take(count)
+            .cloned()
+            .collect()
+    }
+
+    fn get_profile(&self, id: u64) -> Option<Profile> {
+        self.profiles.read().unwrap().get(&id).cloned()
+    }
+
+    fn get_product_registrations_for_profile(
+        &self,
+        profile_id: u64,
+        status: Option<RegistrationStatus>,
+        as_of: Option<chrono::DateTime<chrono::Utc>>,
+        start: u64,
+        count: usize,
+    ) -> Vec<ProductRegistrationRecord> {
+        let product_registration_ids = self.get_profile_product_registration_ids(profile_id);
+
+        if status.is_some() || as_of.is_some() {
+            // Registrations created after as_of are skipped, so pages cannot be sliced upfront
+            return product_registration_ids
+                .iter()
+                .filter_map(|id| self.find_product_registration(*id, as_of))
+                .filter(|record| status.is_none_or(|status| record.registration.status == status))
+                .skip(start as usize)
+                .take(count)
+                .collect();
+        }
+
+        let start = start as usize;
+        let end = min(start + count, product_registration_ids.len());
+        if start >= product_registration_ids.len() {
+            return Vec::new();
+        }
+
+        product_registration_ids
+            .get(start..end)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|id| self.get_product_registration(*id))
+            .collect()
+    }
+
+    fn get_product_registration(&self, id: u64) -> Option<ProductRegistrationRecord> {
+        self.find_product_registration(id, None)
+    }
+
+    fn get_product_registration_by_serial(
+        &self,
+        serial_code: &str,
+    ) -> Option<ProductRegistrationRecord> {
+        let holder = self.find_serial_code_holder(serial_code)?;
+
+        self.find_product_registration(holder.parent_id.unwrap_or(holder.id), None)
+    }
+
+    fn get_product_registration_as_of(
+        &self,
+        id: u64,
+        as_of: chrono::DateTime<chrono::Utc>,
+    ) -> Option<ProductRegistrationRecord> {
+        self.find_product_registration(id, Some(as_of))
+    }
+
+    fn get_entitlements(
+        &self,
+        profile_id: u64,
+        as_of: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Vec<ProductEntitlement> {
+        let mut entitlements: Vec<ProductEntitlement> = self
+            .get_registered_products(profile_id, as_of, registration_is_active)
+            .into_values()
+            .collect();
+        entitlements.sort_by(|a, b| a.product.cmp(&b.product));
+
+        entitlements
+    }
+
+    fn get_leaf_products(&self, product: &str) -> HashSet<String> {
+        let mut visited_products = HashSet::new();
+        let mut leaf_products = HashSet::new();
+
+        find_subproduct_dfs(
+            product,
+            &self.products,
+            &mut visited_products,
+            &mut leaf_products,
+        );
+
+        leaf_products
+    }
+
+    fn product_exists(&self, product: &str) -> bool {
+        self.products.contains_key(product)
+    }
+
+    fn set_product_serial_format(
+        &self,
+        product: &str,
+        serial_format: &str,
+    ) -> Result<(), RepositoryError> {
+        self.commit(JournalEntry::ProductSerialFormatStored {
+            product: product.into(),
+            serial_format: serial_format.into(),
+        })
+    }
+
+    fn get_product_serial_format(&self, product: &str) -> Option<String> {
+        self.product_serial_formats
+            .get(product)
+            .map(|serial_format| serial_format.value().clone())
+    }
+
+    fn set_product_serial_template(
+        &self,
+        product: &str,
+        serial_template: SerialTemplate,
+    ) -> Result<(), RepositoryError> {
+        self.commit(JournalEntry::ProductSerialTemplateStored {
+            product: product.into(),
+            serial_template,
+        })
+    }
+
+    fn get_product_serial_template(&self, product: &str) -> Option<SerialTemplate> {
+        self.product_serial_templates
+            .get(product)
+            .map(|serial_template| serial_template.value().clone())
+    }
+
+    fn insert_serial_pool(
+        &self,
+        product: &str,
+        serial_codes: Vec<PooledSerialCode>,
+    ) -> Result<Vec<String>, RepositoryError> {
+        // registrations take serial codes from the pool while holding this lock
+        let _serial_code_guard = self.serial_code_lock.lock().unwrap();
+
+        let mut pooled_serial_codes = Vec::new();
+        let mut seen = HashSet::new();
+        let mut skipped = Vec::new();
+        for pooled in serial_codes {
+            if self.serial_codes.contains_key(&pooled.serial_code)
+                || self.serial_code_lots.contains_key(&pooled.serial_code)
+                || !seen.insert(pooled.serial_code.clone())
+            {
+                skipped.push(pooled.serial_code);
+                continue;
+            }
+
+            pooled_serial_codes.push(pooled);
+        }
+        self.commit(JournalEntry::SerialCodesPooled {
+            product: product.into(),
+            serial_codes: pooled_serial_codes,
+        })?;
+
+        Ok(skipped)
+    }
+
+    fn get_serial_pool(&self, product: &str) -> Vec<SerialPoolLot> {
+        let Some(serial_pool) = self.serial_pools.get(product) else {
+            return Vec::new();
+        };
+
+        let mut remaining: HashMap<&str, usize> = HashMap::new();
+        for pooled in serial_pool.available.iter() {
+            *remaining.entry(pooled.lot.as_str()).or_default() += 1;
+        }
+
+        let mut lots: Vec<SerialPoolLot> = serial_pool
+            .imported
+            .iter()
+            .map(|(lot, imported)| SerialPoolLot {
+                lot: lot.clone(),
+                imported: *imported,
+                remaining: remaining.get(lot.as_str()).copied().unwrap_or_default(),
+            })
+            .collect();
+        lots.sort_by(|a, b| a.lot.cmp(&b.lot));
+
+        lots
+    }
+
+    fn get_product_registrations_for_lot(
+        &self,
+        lot: &str,
+    ) -> Option<Vec<ProductRegistrationRecord>> {
+        let serial_codes = self
+            .lot_serial_codes
+            .get(lot)
+            .map(|serial_codes| serial_codes.value().clone())?;
+
+        let mut seen = HashSet::new();
+        let records = serial_codes
+            .iter()
+            .filter_map(|serial_code| self.find_serial_code_holder(serial_code))
+            .filter(|holder| holder.lot.as_deref() == Some(lot))
+            .map(|holder| holder.parent_id.unwrap_or(holder.id))
+            .filter(|id| seen.insert(*id))
+            .filter_map(|id| self.find_product_registration(id, None))
+            .collect();
+
+        Some(records)
+    }
+
+    fn insert_product(
+        &self,
+        product: &str,
+        subproducts: &[String],
+        active_for: Option<u64>,
+    ) -> Result<HashSet<String>, RepositoryError> {
+        let mut products_to_add = HashSet::new();
+        let mut visited_products = HashSet::new();
+
+        for subproduct in subproducts {
+            find_subproduct_dfs(
+                subproduct,
+                &self.products,
+                &mut visited_products,
+                &mut products_to_add,
+            );
+        }
+
+        self.commit(JournalEntry::ProductStored {
+            product: product.to_owned(),
+            leaf_products: products_to_add.iter().cloned().collect(),
+            active_for,
+        })?;
+
+        Ok(products_to_add)
+    }
+
+    fn insert_product_registration(
+        &self,
+        profile_id: u64,
+        product_sku: &str,
+        purchase_date: Option<chrono::DateTime<chrono::Utc>>,
+        purchase_details: PurchaseDetails,
+        serial_code: Option<String>,
+    ) -> Result<ProductRegistrationRecord, RepositoryError> {
+        let profile_lock = self.profile_lock(profile_id);
+        let _profile_guard = profile_lock.lock().unwrap();
+
+        let mut visited_products = HashSet::new();
+        let mut products_to_add = HashSet::new();
+
+        find_subproduct_dfs(
+            product_sku,
+            &self.products,
+            &mut visited_products,
+            &mut products_to_add,
+        );
+
+        let intersection = self.get_conflicting_products(profile_id, &products_to_add, None);
+        if !intersection.is_empty() {
+            return Err(RepositoryError::Conflict(intersection));
+        }
+
+        let purchase_date = purchase_date.unwrap_or_else(self.time_provider);
+        let _serial_code_guard = self.serial_code_lock.lock().unwrap();
+        let mut reserved_serial_codes = HashSet::new();
+        if let Some(serial_code) = serial_code.as_deref() {
+            let holder = self
+                .find_serial_code_holder(serial_code)
+                .filter(|holder| holder.status != RegistrationStatus::Revoked);
+            if let Some(holder) = holder {
+                return Err(RepositoryError::SerialCodeTaken(holder.profile_id));
+            }
+            reserved_serial_codes.insert(serial_code.to_owned());
+        }
+
+        // serial codes are generated upfront, so running out of them leaves nothing half registered
+        let parent_serial_code = match serial_code.clone() {
+            Some(serial_code) => (serial_code, None),
+            None => self.generate_serial_code(product_sku, &mut reserved_serial_codes)?,
+        };
+        let mut child_serial_codes = HashMap::new();
+        for child in products_to_add {
+            // a leaf product is registered along with itself, both carry the supplied serial
+            let child_serial_code = match serial_code.clone().filter(|_| child == product_sku) {
+                Some(serial_code) => (serial_code, None),
+                None => self.generate_serial_code(&child, &mut reserved_serial_codes)?,
+            };
+            child_serial_codes.insert(child, child_serial_code);
+        }
+
+        let mut ids = self.next_product_registration_ids(child_serial_codes.len() + 1);
+        let parent_registration = self.new_product_registration(
+            ids.next().unwrap(),
+            profile_id,
+            None,
+            purchase_date,
+            product_sku,
+            parent_serial_code,
+        );
+        let child_registrations: Vec<ProductRegistration> = child_serial_codes
+            .into_iter()
+            .zip(ids)
+            .map(|((child, child_serial_code), child_id)| {
+                self.new_product_registration(
+                    child_id,
+                    profile_id,
+                    Some(parent_registration.id),
+                    purchase_date,
+                    &child,
+                    child_serial_code,
+                )
+            })
+            .collect();
+        let mut record = ProductRegistrationRecord {
+            registration: parent_registration,
+            children: child_registrations,
+        };
+        for registration in
+            std::iter::once(&mut record.registration).chain(record.children.iter_mut())
+        {
+            registration.purchase_details = purchase_details.clone();
+        }
+        self.commit(JournalEntry::ProductRegistrationsStored {
+            registrations: children_first(&record),
+            renewals: Vec::new(),
+        })?;
+
+        // a backdated registration can be expired from the start
+        let now = (self.time_provider)();
+        Ok(ProductRegistrationRecord {
+            registration: with_effective_status(record.registration, now),
+            children: record
+                .children
+                .into_iter()
+                .map(|child| with_effective_status(child, now))
+                .collect(),
+        })
+    }
+
+    fn renew_product_registration(
+        &self,
+        id: u64,
+        period: Option<u64>,
+    ) -> Result<ProductRegistrationRecord, RepositoryError> {
+        let profile_lock = self.profile_lock(self.get_product_registration_profile_id(id)?);
+        let _profile_guard = profile_lock.lock().unwrap();
+        let record = self
+            .get_product_registration(id)
+            .ok_or(RepositoryError::NotFound)?;
+        let registration = &record.registration;
+
+        if registration.parent_id.is_some() {
+            return Err(RepositoryError::InvalidOperation(String::from(
+                "registration is part of another registration, renew the parent instead",
+            )));
+        }
+
+        if !matches!(
+            registration.status,
+            RegistrationStatus::Active | RegistrationStatus::Expired
+        ) {
+            return Err(RepositoryError::InvalidOperation(format!(
+                "registration is {:?} and cannot be renewed",
+                registration.status
+            )));
+        }
+
+        if registration.expiry_at.is_none() {
+            return Err(RepositoryError::InvalidOperation(String::from(
+                "registration does not expire",
+            )));
+        }
+
+        let Some(period) = period.or_else(|| {
+            self.product_active_for
+                .get(&registration.product)
+                .map(|active_for| *active_for.value())
+        }) else {
+            return Err(RepositoryError::InvalidOperation(format!(
+                "product:{} has no renewal period",
+                registration.product
+            )));
+        };
+
+        let now = (self.time_provider)();
+        if !registration_is_active(registration, now) {
+            // An expired registration does not count towards the active products of a profile,
+            // so the same products could have been registered again in the meantime
+            let intersection = self.get_conflicting_products(
+                registration.profile_id,
+                &registered_leaf_products(&record),
+                Some(id),
+            );
+            if !intersection.is_empty() {
+                return Err(RepositoryError::Conflict(intersection));
+            }
+        }
+
+        let Some(extension) = i64::try_from(period)
+            .ok()
+            .and_then(chrono::Duration::try_seconds)
+        else {
+            return Err(RepositoryError::InvalidOperation(format!(
+                "renewal period of {} seconds is too long",
+                period
+            )));
+        };
+        let renewed_ids = std::iter::once(id).chain(record.children.iter().map(|child| child.id));
+        let mut renewed_registrations = Vec::new();
+        let mut renewals = Vec::new();
+        for mut renewed in renewed_ids.filter_map(|id| self.get_stored_product_registration(id)) {
+            let Some(expiry_at) = renewed.expiry_at else {
+                continue;
+            };
+            if registration_status(&renewed, now) == RegistrationStatus::Expired {
+                set_registration_status(
+                    &mut renewed,
+                    RegistrationStatus::Active,
+                    now,
+                    Some("renewed"),
+                );
+            }
+            let Some(renewed_expiry_at) = max(expiry_at, now).checked_add_signed(extension) else {
+                return Err(RepositoryError::InvalidOperation(format!(
+                    "renewal period of {} seconds is too long",
+                    period
+                )));
+            };
+            renewed.expiry_at = Some(renewed_expiry_at);
+
+            // Recorded for children too, so their expiry can be looked up at any point in time
+            renewals.push(ProductRegistrationRenewal {
+                registration_id: renewed.id,
+                renewed_at: now,
+                period,
+                previous_expiry_at: expiry_at,
+                expiry_at: renewed_expiry_at,
+            });
+            renewed_registrations.push(renewed);
+        }
+        self.commit(JournalEntry::ProductRegistrationsStored {
+            registrations: renewed_registrations,
+            renewals,
+        })?;
+
+        self.get_product_registration(id)
+            .ok_or(RepositoryError::NotFound)
+    }
+
+    fn update_product_registration_status(
+        &self,
+        id: u64,
+        status: RegistrationStatus,
+        reason: Option<&str>,
+    ) -> Result<ProductRegistrationRecord, RepositoryError> {
+        let profile_lock = self.profile_lock(self.get_product_registration_profile_id(id)?);
+        let _profile_guard = profile_lock.lock().unwrap();
+        let record = self
+            .get_product_registration(id)
+            .ok_or(RepositoryError::NotFound)?;
+
+        if record.registration.parent_id.is_some() {
+            return Err(RepositoryError::InvalidOperation(String::from(
+                "registration is part of another registration, update the parent instead",
+            )));
+        }
+
+        if !record.registration.status.can_transition_to(status) {
+            return Err(RepositoryError::InvalidOperation(format!(
+                "registration cannot go from {:?} to {:?}",
+                record.registration.status, status
+            )));
+        }
+
+        // Expiry follows from expiry_at, moving in or out of it is done by time or renewal
+        let changes_expiry = record.registration.status == RegistrationStatus::Expired
+            || status == RegistrationStatus::Expired;
+        if changes_expiry && status != RegistrationStatus::Revoked {
+            return Err(RepositoryError::InvalidOperation(String::from(
+                "registration expiry can only be changed by renewing it",
+            )));
+        }
+
+        let now = (self.time_provider)();
+        let updated_ids = std::iter::once(id).chain(record.children.iter().map(|child| child.id));
+        let updated = self.updated_product_registrations(updated_ids, |updated| {
+            // Children can expire separately from their parent, these are left as they are
+            if registration_status(updated, now).can_transition_to(status) {
+                set_registration_status(updated, status, now, reason);
+            }
+        });
+        self.commit(JournalEntry::ProductRegistrationsStored {
+            registrations: updated,
+            renewals: Vec::new(),
+        })?;
+
+        self.get_product_registration(id)
+            .ok_or(RepositoryError::NotFound)
+    }
+
+    fn transfer_product_registration(
+        &self,
+        id: u64,
+        target_profile_id: u64,
+    ) -> Result<ProductRegistrationRecord, RepositoryError> {
+        let now = (self.time_provider)();
+        // Both profiles are held for the whole transfer, lowest id first so transfers between the same two profiles
+        // can't wait on each other
+        let profile_id = self.get_product_registration_profile_id(id)?;
+        let first_profile_lock = self.profile_lock(min(profile_id, target_profile_id));
+        let second_profile_lock = self.profile_lock(max(profile_id, target_profile_id));
+        let _first_profile_guard = first_profile_lock.lock().unwrap();
+        let _second_profile_guard =
+            (profile_id != target_profile_id).then(|| second_profile_lock.lock().unwrap());
+        let record = self
+            .find_product_registration(id, None)
+            .ok_or(RepositoryError::NotFound)?;
+        let previous = &record.registration;
+
+        if previous.parent_id.is_some() {
+            return Err(RepositoryError::InvalidOperation(String::from(
+                "registration is part of another registration, transfer the parent instead",
+            )));
+        }
+
+        if previous.profile_id == target_profile_id {
+            return Err(RepositoryError::InvalidOperation(String::from(
+                "registration already belongs to this profile",
+            )));
+        }
+
+        if previous.status != RegistrationStatus::Active {
+            return Err(RepositoryError::InvalidOperation(format!(
+                "registration is {:?} and cannot be transferred",
+                previous.status
+            )));
+        }
+
+        let intersection = self.get_conflicting_products(
+            target_profile_id,
+            &registered_leaf_products(&record),
+            None,
+        );
+        if !intersection.is_empty() {
+            return Err(RepositoryError::Conflict(intersection));
+        }
+
+        let reason = String::from("transferred out");
+        let mut ids = self.next_product_registration_ids(record.children.len() + 1);
+        let parent_registration = self.new_transferred_product_registration(
+            ids.next().unwrap(),
+            previous,
+            target_profile_id,
+            None,
+            now,
+        );
+        let child_registrations: Vec<ProductRegistration> = record
+            .children
+            .iter()
+            .zip(ids)
+            .map(|(child, child_id)| {
+                self.new_transferred_product_registration(
+                    child_id,
+                    child,
+                    target_profile_id,
+                    Some(parent_registration.id),
+                    now,
+                )
+            })
+            .collect();
+        let transferred = ProductRegistrationRecord {
+            registration: parent_registration,
+            children: child_registrations,
+        };
+        let transferred_ids: HashMap<u64, u64> = std::iter::once((id, transferred.registration.id))
+            .chain(
+                record
+                    .children
+                    .iter()
+                    .zip(transferred.children.iter())
+                    .map(|(previous, next)| (previous.id, next.id)),
+            )
+            .collect();
+        // The previous owner keeps the transferred registration in their history
+        let closed = self.updated_product_registrations(
+            std::iter::once(id).chain(record.children.iter().map(|child| child.id)),
+            |previous| {
+                if registration_status(previous, now)
+                    .can_transition_to(RegistrationStatus::Transferred)
+                {
+                    set_registration_status(
+                        previous,
+                        RegistrationStatus::Transferred,
+                        now,
+                        Some(&reason),
+                    );
+                }
+                previous.transferred_to = transferred_ids.get(&previous.id).copied();
+            },
+        );
+        self.commit(JournalEntry::ProductRegistrationsStored {
+            registrations: children_first(&transferred)
+                .into_iter()
+                .chain(closed)
+                .collect(),
+            renewals: Vec::new(),
+        })?;
+
+        Ok(transferred)
+    }
+
+    fn insert_product_upgrade(
+        &self,
+        product: &str,
+        upgrade: &str,
+    ) -> Result<HashSet<String>, RepositoryError> {
+        self.commit(JournalEntry::ProductUpgradeStored {
+            product: product.to_owned(),
+            upgrade: upgrade.to_owned(),
+        })?;
+
+        Ok(self
+            .product_upgrades
+            .get(product)
+            .map(|upgrades| upgrades.value().clone())
+            .unwrap_or_default())
+    }
+
+    fn upgrade_product_registration(
+        &self,
+        id: u64,
+        product_sku: &str,
+        prorate: bool,
+    ) -> Result<ProductRegistrationRecord, RepositoryError> {
+        let now = (self.time_provider)();
+        // Held for the whole upgrade, so nothing else can take the products in between
+        let profile_lock = self.profile_lock(self.get_product_registration_profile_id(id)?);
+        let _profile_guard = profile_lock.lock().unwrap();
+        let record = self
+            .find_product_registration(id, None)
+            .ok_or(RepositoryError::NotFound)?;
+        let previous = &record.registration;
+
+        if previous.parent_id.is_some() {
+            return Err(RepositoryError::InvalidOperation(String::from(
+                "registration is part of another registration, upgrade the parent instead",
+            )));
+        }
+
+        if previous.status != RegistrationStatus::Active {
+            return Err(RepositoryError::InvalidOperation(format!(
+                "registration is {:?} and cannot be upgraded",
+                previous.status
+            )));
+        }
+
+        let upgrade_declared = self
+            .product_upgrades
+            .get(&previous.product)
+            .is_some_and(|upgrades| upgrades.contains(product_sku));
+        if !upgrade_declared {
+            return Err(RepositoryError::InvalidOperation(format!(
+                "product:{} cannot be upgraded to product:{}",
+                previous.product, product_sku
+            )));
+        }
+
+        let mut visited_products = HashSet::new();
+        let mut products_to_add = HashSet::new();
+        find_subproduct_dfs(
+            product_sku,
+            &self.products,
+            &mut visited_products,
+            &mut products_to_add,
+        );
+
+        // The upgraded registration is closed, so its own products do not conflict
+        let intersection =
+            self.get_conflicting_products(previous.profile_id, &products_to_add, Some(id));
+        if !intersection.is_empty() {
+            return Err(RepositoryError::Conflict(intersection));
+        }
+
+        // carried over serial codes keep the lot they were pooled from
+        let mut leaf_serial_codes: HashMap<String, (String, Option<String>)> = record
+            .children
+            .iter()
+            .map(|child| {
+                (
+                    child.product.clone(),
+                    (child.serial_code.clone(), child.lot.clone()),
+                )
+            })
+            .collect();
+        if record.children.is_empty() {
+            leaf_serial_codes.insert(
+                previous.product.clone(),
+                (previous.serial_code.clone(), previous.lot.clone()),
+            );
+        }
+
+        let remaining = match (prorate, previous.expiry_at) {
+            (true, Some(expiry_at)) => expiry_at - now,
+            _ => chrono::Duration::zero(),
+        };
+
+        let _serial_code_guard = self.serial_code_lock.lock().unwrap();
+        let mut reserved_serial_codes = HashSet::new();
+        let parent_serial_code =
+            self.generate_serial_code(product_sku, &mut reserved_serial_codes)?;
+        let mut child_serial_codes = HashMap::new();
+        for child in products_to_add {
+            let child_serial_code = match leaf_serial_codes.remove(&child) {
+                Some(serial_code) => serial_code,
+                None => self.generate_serial_code(&child, &mut reserved_serial_codes)?,
+            };
+            child_serial_codes.insert(child, child_serial_code);
+        }
+
+        let mut ids = self.next_product_registration_ids(child_serial_codes.len() + 1);
+        let mut parent_registration = self.new_product_registration(
+            ids.next().unwrap(),
+            previous.profile_id,
+            None,
+            now,
+            product_sku,
+            parent_serial_code,
+        );
+        parent_registration.upgraded_from = Some(id);
+        let child_registrations: Vec<ProductRegistration> = child_serial_codes
+            .into_iter()
+            .zip(ids)
+            .map(|((child, serial_code), child_id)| {
+                self.new_product_registration(
+                    child_id,
+                    previous.profile_id,
+                    Some(parent_registration.id),
+                    now,
+                    &child,
+                    serial_code,
+                )
+            })
+            .collect();
+        let mut upgraded = ProductRegistrationRecord {
+            registration: parent_registration,
+            children: child_registrations,
+        };
+        for registration in
+            std::iter::once(&mut upgraded.registration).chain(upgraded.children.iter_mut())
+        {
+            registration.expiry_at = registration
+                .expiry_at
+                .map(|expiry_at| expiry_at + remaining);
+        }
+
+        // The old registration is closed first, so its products are never held twice
+        let reason = format!("upgraded to product:{}", product_sku);
+        let closed_ids = std::iter::once(id).chain(record.children.iter().map(|child| child.id));
+        let closed = self.updated_product_registrations(closed_ids, |closed| {
+            if registration_status(closed, now).can_transition_to(RegistrationStatus::Upgraded) {
+                set_registration_status(closed, RegistrationStatus::Upgraded, now, Some(&reason));
+            }
+            closed.upgraded_to = Some(upgraded.registration.id);
+        });
+        self.commit(JournalEntry::ProductRegistrationsStored {
+            registrations: closed
+                .into_iter()
+                .chain(children_first(&upgraded))
+                .collect(),
+            renewals: Vec::new(),
+        })?;
+
+        Ok(upgraded)
+    }
+
+    fn insert_api_key(
+        &self,
+        name: &str,
+        role: ApiKeyRole,
+        prefix: &str,
+        key_hash: &str,
+    ) -> Result<ApiKey, RepositoryError> {
+        let _api_key_guard = self.api_key_lock.lock().unwrap();
+        let id = loop {
+            let id = self.id_generator.next_id();
+            if self.get_api_keys().iter().all(|api_key| api_key.id != id) {
+                break id;
+            }
+        };
+        let api_key = ApiKey {
+            id,
+            name: name.into(),
+            prefix: prefix.into(),
+            key_hash: key_hash.into(),
+            role,
+            created_at: self.current_time(),
+            revoked_at: None,
+        };
+        self.commit(JournalEntry::ApiKeyStored(api_key.clone()))?;
+
+        Ok(api_key)
+    }
+
+    fn get_api_key_by_hash(&self, key_hash: &str) -> Option<ApiKey> {
+        let id = *self.api_key_hashes.get(key_hash)?;
+        let api_keys = self.api_keys.lock().unwrap();
+
+        api_keys
+            .iter()
+            .find(|api_key| api_key.id == id && api_key.key_hash == key_hash)
+            .cloned()
+    }
+
+    fn get_api_keys(&self) -> Vec<ApiKey> {
+        self.api_keys.lock().unwrap().clone()
+    }
+
+    fn revoke_api_key(&self, id: u64) -> Result<ApiKey, RepositoryError> {
+        let _api_key_guard = self.api_key_lock.lock().unwrap();
+        let Some(mut api_key) = self
+            .get_api_keys()
+            .into_iter()
+            .find(|api_key| api_key.id == id)
+        else {
+            return Err(RepositoryError::NotFound);
+        };
+        if api_key.revoked_at.is_some() {
+            return Err(RepositoryError::InvalidOperation(String::from(
+                "api key is already revoked",
+            )));
+        }
+
+        api_key.revoked_at = Some(self.current_time());
+        self.commit(JournalEntry::ApiKeyStored(api_key.clone()))?;
+
+        Ok(api_key)
+    }
+
+    fn get_product_registration_renewals(
+        &self,
+        id: u64,
+    ) -> Option<Vec<ProductRegistrationRenewal>> {
+        let _ = self.get_product_registration(id)?;
+
+        Some(
+            self.product_registration_renewals
+                .get(&id)
+                .map(|renewals| renewals.value().clone())
+                .unwrap_or_default(),
+        )
+    }
+
+    fn export_snapshot(&self) -> Snapshot {
+        let _changes_guard = self.changes_lock.write().unwrap();
+        self.snapshot()
+    }
+
+    fn import_snapshot(&self, mut snapshot: Snapshot) -> Result<(), RepositoryError> {
+        // Held until the snapshot is restored, so nothing is stored after the repository was found empty
+        let _changes_guard = self.changes_lock.write().unwrap();
+        let is_empty = self.profiles.read().unwrap().is_empty()
+            && self.products.is_empty()
+            && self.product_registrations.is_empty();
+        if !is_empty {
+            return Err(RepositoryError::InvalidOperation(
+                "snapshots can only be imported into a repository without profiles, products or registrations".into(),
+            ));
+        }
+
+        // The API keys already there are kept, so whoever imports the snapshot doesn't lock themselves out
+        let api_keys = self.get_api_keys();
+        snapshot.api_keys.retain(|imported| {
+            api_keys
+                .iter()
+                .all(|api_key| api_key.id != imported.id && api_key.key_hash != imported.key_hash)
+        });
+        snapshot.api_keys.splice(0..0, api_keys);
+
+        self.commit_holding_changes(JournalEntry::SnapshotRestored(Box::new(snapshot)))
+    }
+
+    fn check_integrity(&self, repair: bool) -> Result<Vec<IntegrityViolation>, RepositoryError> {
+        // Held until the repairs are applied, so no change made in between is undone by them
+        let _changes_guard = self.changes_lock.write().unwrap();
+        let mut snapshot = self.snapshot();
+        let violations = fsck::check(&mut snapshot, (self.time_provider)());
+
+        // Restoring the repaired snapshot also rebuilds the lookups which are not part of it
+        if repair && !violations.is_empty() {
+            self.commit_holding_changes(JournalEntry::SnapshotRestored(Box::new(snapshot)))?;
+        }
+
+        Ok(violations)
+    }
+}
+
+// Products a registration holds, children if it is a bundle, or the product itself otherwise
+fn registered_leaf_products(record: &ProductRegistrationRecord) -> HashSet<String> {
+    if record.children.is_empty() {
+        return HashSet::from([record.registration.product.clone()]);
+    }
+
+    record
+        .children
+        .iter()
+        .map(|child| child.product.clone())
+        .collect()
+}
+
+fn find_subproduct_dfs(
+    product: &str,
+    existing_products: &DashMap<String, HashSet<String>>,
+    visited_products: &mut HashSet<String>,
+    products_to_add: &mut HashSet<String>,
+) {
+    if visited_products.contains(product) {
+        return;
+    }
+
+    visited_products.insert(product.to_owned());
+
+    let Some(subproducts) = existing_products.get(product) else {
         tracing::error!("Unable to find {} in existing products", product);
         return;
     };
 
-    if subproducts.is_empty() {
-        products_to_add.insert(product.to_owned());
-    } else {
-        for p in subproducts.value() {
-            find_subproduct_dfs(p, existing_products, visited_products, products_to_add);
+    if subproducts.is_empty() {
+        products_to_add.insert(product.to_owned());
+    } else {
+        for p in subproducts.value() {
+            find_subproduct_dfs(p, existing_products, visited_products, products_to_add);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository::id::{SnowflakeIdGenerator, UlidIdGenerator};
+    use crate::repository::model::IntegrityViolationKind;
+    use crate::repository::serial;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    // Serial codes cannot repeat, so the generator is shared by all tests
+    fn sequential_serial_generator() -> String {
+        static NEXT: AtomicU64 = AtomicU64::new(1);
+        format!("{:015}", NEXT.fetch_add(1, Ordering::Relaxed))
+    }
+
+    // Hands out the given serial codes, last one first
+    struct QueuedSerialGenerator(Mutex<Vec<String>>);
+
+    impl SerialGenerator for QueuedSerialGenerator {
+        fn generate(&self, _template: &SerialTemplate) -> String {
+            self.0.lock().unwrap().pop().unwrap_or_default()
+        }
+    }
+
+    fn setup() -> InMemoryProfileRepository {
+        InMemoryProfileRepository::with_example_data(sequential_serial_generator, || {
+            chrono::DateTime::<chrono::Utc>::MIN_UTC
+        })
+    }
+
+    fn setup_after_example_expiry() -> InMemoryProfileRepository {
+        InMemoryProfileRepository::with_example_data(sequential_serial_generator, || {
+            chrono::DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+                .unwrap()
+                .into()
+        })
+    }
+
+    #[test]
+    fn static_data_is_valid() {
+        setup();
+    }
+
+    #[test]
+    fn get_by_zero_or_unknown_id() {
+        let repo = setup();
+
+        assert!(repo.get_profile(0).is_none());
+        assert!(repo.get_profile(1337).is_none());
+        assert!(repo.get_product_registration(0).is_none());
+        assert!(repo.get_product_registration(1337).is_none());
+        assert_eq!(
+            Err(RepositoryError::NotFound),
+            repo.revoke_api_key(0).map(|_| ())
+        );
+        assert_eq!(2, repo.get_profiles(0, 10).len());
+        assert_eq!(1, repo.get_profiles(1, 10).len());
+    }
+
+    #[test]
+    fn insert_product_registration_with_snowflake_ids() {
+        let repo = setup().with_id_generator(Box::new(SnowflakeIdGenerator::new(7).unwrap()));
+        repo.insert_product("BUNDLE", &["SKE48".into(), "NMB48".into()], None)
+            .unwrap();
+
+        let first = repo
+            .insert_product_registration(1, "BUNDLE", None, PurchaseDetails::default(), None)
+            .unwrap();
+        let second = repo
+            .insert_product_registration(2, "BUNDLE", None, PurchaseDetails::default(), None)
+            .unwrap();
+
+        let ids: Vec<u64> = [&first, &second]
+            .iter()
+            .flat_map(|record| {
+                std::iter::once(record.registration.id)
+                    .chain(record.children.iter().map(|child| child.id))
+            })
+            .collect();
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(ids.iter().all(|id| (id >> 12) & 1023 == 7));
+        assert!(first
+            .children
+            .iter()
+            .all(|child| child.parent_id == Some(first.registration.id)));
+        assert_eq!(
+            Some(second.registration.id),
+            repo.get_product_registration(second.registration.id)
+                .map(|record| record.registration.id)
+        );
+    }
+
+    #[test]
+    fn sequential_ids_skip_taken_ids() {
+        let repo = setup().with_id_generator(Box::new(SequentialIdGenerator::new()));
+
+        let record = repo
+            .insert_product_registration(1, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+
+        assert!(record.registration.id > 1);
+        assert_eq!(
+            "ARIE4",
+            repo.get_product_registration(1)
+                .unwrap()
+                .registration
+                .product
+        );
+    }
+
+    #[test]
+    fn sequential_ids_carry_on_after_loaded_ids() {
+        let data_dir = test_data_dir("sequential_ids");
+        let repo = setup()
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        let record = repo
+            .insert_product_registration(1, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        drop(repo);
+
+        let restarted = InMemoryProfileRepository::new()
+            .with_id_generator(Box::new(SequentialIdGenerator::new()))
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        let api_key = restarted
+            .insert_api_key("support desk", ApiKeyRole::Support, "pbk_abc", "abc123")
+            .unwrap();
+        let last_id = record
+            .children
+            .iter()
+            .map(|child| child.id)
+            .chain(std::iter::once(record.registration.id))
+            .max()
+            .unwrap();
+        assert_eq!(last_id + 1, api_key.id);
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn snowflake_node_id_out_of_range() {
+        assert!(SnowflakeIdGenerator::new(1023).is_ok());
+        assert!(SnowflakeIdGenerator::new(1024).is_err());
+    }
+
+    #[test]
+    fn ulid_ids_are_sorted() {
+        let ids = UlidIdGenerator::new();
+
+        let generated: Vec<u64> = (0..1000).map(|_| ids.next_id()).collect();
+
+        assert!(generated.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn insert_product_dfs_ok() {
+        let expected = HashSet::from([
+            "AKBL1".into(),
+            "AKDS5".into(),
+            "ARAS1".into(),
+            "ARCS1".into(),
+            "ARCH1".into(),
+            "ARCM1".into(),
+        ]);
+
+        let repo = setup();
+        assert!(!repo.product_exists("foo"));
+        let actual = repo.insert_product("foo", &["ARIE4".into()], None).unwrap();
+
+        assert_eq!(expected, actual);
+        assert!(repo.product_exists("foo"));
+    }
+
+    #[test]
+    fn insert_product_repeated_insert_ok() {
+        let expected = HashSet::from([
+            "AKBL1".into(),
+            "AKDS5".into(),
+            "ARAS1".into(),
+            "ARCS1".into(),
+            "ARCH1".into(),
+            "ARCM1".into(),
+            "SKE48".into(),
+            "NMB48".into(),
+        ]);
+
+        let repo = setup();
+        let _ = repo.insert_product("foo", &["ARIE4".into()], None);
+        let actual = repo
+            .insert_product("bar", &["foo".into(), "AKB48".into()], None)
+            .unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn renew_product_registration_extends_from_expiry() {
+        let repo = setup();
+
+        let record = repo.renew_product_registration(1, Some(3600)).unwrap();
+
+        let previous_expiry_at: chrono::DateTime<chrono::Utc> =
+            chrono::DateTime::parse_from_rfc3339("2024-01-15T15:04:05Z")
+                .unwrap()
+                .into();
+        let expected_expiry_at = previous_expiry_at + chrono::Duration::seconds(3600);
+        assert_eq!(Some(expected_expiry_at), record.registration.expiry_at);
+
+        let renewals = repo.get_product_registration_renewals(1).unwrap();
+        assert_eq!(1, renewals.len());
+        assert_eq!(previous_expiry_at, renewals[0].previous_expiry_at);
+        assert_eq!(expected_expiry_at, renewals[0].expiry_at);
+    }
+
+    #[test]
+    fn renew_product_registration_period_too_long() {
+        let repo = setup();
+
+        for period in [u64::MAX, i64::MAX as u64, 10_000_000_000_000] {
+            let res = repo.renew_product_registration(1, Some(period));
+            assert!(matches!(res, Err(RepositoryError::InvalidOperation(_))));
+        }
+        // The profile is still usable afterwards
+        assert!(repo.renew_product_registration(1, Some(3600)).is_ok());
+    }
+
+    #[test]
+    fn renew_expired_product_registration_extends_from_now() {
+        let repo = setup_after_example_expiry();
+
+        let record = repo.renew_product_registration(3, Some(3600)).unwrap();
+
+        let expected_expiry_at = (repo.time_provider)() + chrono::Duration::seconds(3600);
+        assert_eq!(Some(expected_expiry_at), record.registration.expiry_at);
+    }
+
+    #[test]
+    fn renew_expired_product_registration_registered_again_conflicts() {
+        let repo = setup_after_example_expiry();
+        repo.insert_product_registration(2, "ARCM1", None, PurchaseDetails::default(), None)
+            .unwrap();
+
+        let res = repo.renew_product_registration(3, Some(3600));
+
+        assert_eq!(
+            Err(RepositoryError::Conflict(HashSet::from(["ARCM1".into()]))),
+            res.map(|record| record.registration.id)
+        );
+        assert!(repo
+            .get_product_registration_renewals(3)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn renew_non_expiring_product_registration_fails() {
+        let repo = setup();
+
+        let res = repo.renew_product_registration(2, Some(3600));
+
+        assert!(matches!(res, Err(RepositoryError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn renew_product_registration_without_period_fails() {
+        let repo = setup();
+
+        let res = repo.renew_product_registration(1, None);
+
+        assert!(matches!(res, Err(RepositoryError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn revoke_product_registration_allows_registering_again() {
+        let repo = setup();
+        let inserted = repo
+            .insert_product_registration(2, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        assert!(repo
+            .insert_product_registration(2, "AKB48", None, PurchaseDetails::default(), None)
+            .is_err());
+
+        let record = repo
+            .update_product_registration_status(
+                inserted.registration.id,
+                RegistrationStatus::Revoked,
+                Some("refund"),
+            )
+            .unwrap();
+        assert_eq!(RegistrationStatus::Revoked, record.registration.status);
+        let last_change = record.registration.status_history.last().unwrap();
+        assert_eq!(Some("refund".into()), last_change.reason);
+        assert!(record
+            .children
+            .iter()
+            .all(|child| child.status == RegistrationStatus::Revoked));
+
+        let reinserted = repo
+            .insert_product_registration(2, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        // revoked registrations are still part of the profile's history
+        assert_eq!(
+            vec![3, inserted.registration.id, reinserted.registration.id],
+            repo.get_product_registrations_for_profile(2, None, None, 0, 10)
+                .into_iter()
+                .map(|record| record.registration.id)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn update_child_product_registration_status_fails() {
+        let repo = setup();
+        let inserted = repo
+            .insert_product_registration(2, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+
+        let res = repo.update_product_registration_status(
+            inserted.children[0].id,
+            RegistrationStatus::Revoked,
+            Some("refund"),
+        );
+
+        assert!(matches!(res, Err(RepositoryError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn revoke_product_registration_twice_fails() {
+        let repo = setup();
+        repo.update_product_registration_status(1, RegistrationStatus::Revoked, Some("refund"))
+            .unwrap();
+
+        let res =
+            repo.update_product_registration_status(1, RegistrationStatus::Revoked, Some("refund"));
+
+        assert!(matches!(res, Err(RepositoryError::InvalidOperation(_))));
+        assert!(matches!(
+            repo.renew_product_registration(1, Some(3600)),
+            Err(RepositoryError::InvalidOperation(_))
+        ));
+    }
+
+    #[test]
+    fn suspended_product_registration_holds_products() {
+        let repo = setup();
+        let inserted = repo
+            .insert_product_registration(2, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        let id = inserted.registration.id;
+
+        let suspended = repo
+            .update_product_registration_status(id, RegistrationStatus::Suspended, Some("fraud"))
+            .unwrap();
+        assert_eq!(RegistrationStatus::Suspended, suspended.registration.status);
+        assert!(!registration_is_active(
+            &suspended.registration,
+            (repo.time_provider)()
+        ));
+        assert!(repo
+            .insert_product_registration(2, "SKE48", None, PurchaseDetails::default(), None)
+            .is_err());
+
+        let resumed = repo
+            .update_product_registration_status(id, RegistrationStatus::Active, None)
+            .unwrap();
+        assert_eq!(
+            vec![
+                RegistrationStatus::Active,
+                RegistrationStatus::Suspended,
+                RegistrationStatus::Active
+            ],
+            resumed
+                .registration
+                .status_history
+                .iter()
+                .map(|change| change.status)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn expired_product_registration_reports_expired_status() {
+        let repo = setup_after_example_expiry();
+
+        let record = repo.get_product_registration(3).unwrap();
+        assert_eq!(RegistrationStatus::Expired, record.registration.status);
+        assert_eq!(
+            record.registration.expiry_at,
+            record
+                .registration
+                .status_history
+                .last()
+                .map(|change| change.changed_at)
+        );
+        assert!(matches!(
+            repo.update_product_registration_status(3, RegistrationStatus::Suspended, None),
+            Err(RepositoryError::InvalidOperation(_))
+        ));
+        assert!(matches!(
+            repo.update_product_registration_status(3, RegistrationStatus::Active, None),
+            Err(RepositoryError::InvalidOperation(_))
+        ));
+
+        let renewed = repo.renew_product_registration(3, Some(3600)).unwrap();
+        assert_eq!(RegistrationStatus::Active, renewed.registration.status);
+        assert_eq!(
+            vec![
+                RegistrationStatus::Active,
+                RegistrationStatus::Expired,
+                RegistrationStatus::Active
+            ],
+            renewed
+                .registration
+                .status_history
+                .iter()
+                .map(|change| change.status)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn get_product_registrations_for_profile_by_status() {
+        let repo = setup_after_example_expiry();
+        repo.insert_product_registration(2, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+
+        let expired = repo.get_product_registrations_for_profile(
+            2,
+            Some(RegistrationStatus::Expired),
+            None,
+            0,
+            10,
+        );
+        let active = repo.get_product_registrations_for_profile(
+            2,
+            Some(RegistrationStatus::Active),
+            None,
+            0,
+            10,
+        );
+
+        assert_eq!(
+            vec![3],
+            expired
+                .iter()
+                .map(|record| record.registration.id)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![4],
+            active
+                .iter()
+                .map(|record| record.registration.id)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn transfer_product_registration_moves_to_target_profile() {
+        let repo = setup();
+        let inserted = repo
+            .insert_product_registration(1, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        let id = inserted.registration.id;
+
+        let transferred = repo.transfer_product_registration(id, 2).unwrap();
+
+        assert_eq!(2, transferred.registration.profile_id);
+        assert_eq!(Some(id), transferred.registration.transferred_from);
+        assert_eq!(
+            inserted.registration.serial_code,
+            transferred.registration.serial_code
+        );
+        assert_eq!(2, transferred.children.len());
+        assert!(transferred
+            .children
+            .iter()
+            .all(|child| child.parent_id == Some(transferred.registration.id)));
+
+        let previous = repo.get_product_registration(id).unwrap();
+        assert_eq!(
+            RegistrationStatus::Transferred,
+            previous.registration.status
+        );
+        assert_eq!(
+            Some(transferred.registration.id),
+            previous.registration.transferred_to
+        );
+        assert!(previous
+            .children
+            .iter()
+            .all(|child| child.status == RegistrationStatus::Transferred));
+
+        // the previous owner can register the products again, the new owner cannot
+        assert!(repo
+            .insert_product_registration(2, "AKB48", None, PurchaseDetails::default(), None)
+            .is_err());
+        assert!(repo
+            .insert_product_registration(1, "AKB48", None, PurchaseDetails::default(), None)
+            .is_ok());
+        assert_eq!(
+            vec![3, transferred.registration.id],
+            repo.get_product_registrations_for_profile(2, None, None, 0, 10)
+                .into_iter()
+                .map(|record| record.registration.id)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn transfer_product_registration_conflicts_with_target() {
+        let repo = setup();
+        let inserted = repo
+            .insert_product_registration(1, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        repo.insert_product_registration(2, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+
+        let res = repo.transfer_product_registration(inserted.registration.id, 2);
+
+        assert_eq!(
+            Err(RepositoryError::Conflict(HashSet::from(["SKE48".into()]))),
+            res.map(|record| record.registration.id)
+        );
+        let previous = repo
+            .get_product_registration(inserted.registration.id)
+            .unwrap();
+        assert_eq!(RegistrationStatus::Active, previous.registration.status);
+    }
+
+    #[test]
+    fn transfer_product_registration_twice_fails() {
+        let repo = setup();
+        repo.transfer_product_registration(1, 2).unwrap();
+
+        let res = repo.transfer_product_registration(1, 2);
+
+        assert!(matches!(res, Err(RepositoryError::InvalidOperation(_))));
+    }
+
+    #[test]
+    fn upgrade_product_registration_keeps_shared_serial_codes() {
+        let repo =
+            InMemoryProfileRepository::with_example_data(serial::RandomSerialGenerator, || {
+                chrono::DateTime::<chrono::Utc>::MIN_UTC
+            });
+        repo.insert_product("AKB49", &["AKB48".into(), "AKBL1".into()], None)
+            .unwrap();
+        let inserted = repo
+            .insert_product_registration(2, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        assert!(repo
+            .insert_product_registration(2, "AKB49", None, PurchaseDetails::default(), None)
+            .is_err());
+
+        let res = repo.upgrade_product_registration(inserted.registration.id, "AKB49", false);
+        assert!(matches!(res, Err(RepositoryError::InvalidOperation(_))));
+
+        repo.insert_product_upgrade("AKB48", "AKB49").unwrap();
+        let upgraded = repo
+            .upgrade_product_registration(inserted.registration.id, "AKB49", false)
+            .unwrap();
+
+        assert_eq!(
+            Some(inserted.registration.id),
+            upgraded.registration.upgraded_from
+        );
+        assert_eq!(3, upgraded.children.len());
+        for child in inserted.children.iter() {
+            let upgraded_child = upgraded
+                .children
+                .iter()
+                .find(|upgraded_child| upgraded_child.product == child.product)
+                .unwrap();
+            assert_eq!(child.serial_code, upgraded_child.serial_code);
         }
+
+        let previous = repo
+            .get_product_registration(inserted.registration.id)
+            .unwrap();
+        assert_eq!(RegistrationStatus::Upgraded, previous.registration.status);
+        assert_eq!(
+            Some(upgraded.registration.id),
+            previous.registration.upgraded_to
+        );
+        assert!(repo
+            .insert_product_registration(2, "SKE48", None, PurchaseDetails::default(), None)
+            .is_err());
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    #[test]
+    fn upgrade_product_registration_prorates_expiry() {
+        let repo = setup();
+        repo.insert_product("AKB49", &["AKB48".into(), "AKBL1".into()], Some(3600))
+            .unwrap();
+        repo.insert_product_upgrade("ARIE4", "AKB49").unwrap();
 
-    fn setup() -> InMemoryProfileRepository {
-        InMemoryProfileRepository::with_example_data(String::new, || {
-            chrono::DateTime::<chrono::Utc>::MIN_UTC
-        })
+        let upgraded = repo.upgrade_product_registration(1, "AKB49", true).unwrap();
+
+        let previous_expiry_at: chrono::DateTime<chrono::Utc> =
+            chrono::DateTime::parse_from_rfc3339("2024-01-15T15:04:05Z")
+                .unwrap()
+                .into();
+        assert_eq!(
+            Some(previous_expiry_at + chrono::Duration::seconds(3600)),
+            upgraded.registration.expiry_at
+        );
+        assert_eq!(
+            upgraded.registration.expiry_at,
+            repo.get_product_registration(upgraded.registration.id)
+                .unwrap()
+                .registration
+                .expiry_at
+        );
     }
 
     #[test]
-    fn static_data_is_valid() {
-        setup();
+    fn get_entitlements_excludes_inactive_registrations() {
+        let repo = setup_after_example_expiry();
+        let inserted = repo
+            .insert_product_registration(2, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        repo.insert_product_registration(2, "AKBL1", None, PurchaseDetails::default(), None)
+            .unwrap();
+        repo.update_product_registration_status(
+            inserted.registration.id,
+            RegistrationStatus::Suspended,
+            Some("fraud"),
+        )
+        .unwrap();
+
+        let entitlements = repo.get_entitlements(2, None);
+
+        assert_eq!(
+            vec![("AKBL1".to_owned(), 7)],
+            entitlements
+                .into_iter()
+                .map(|entitlement| (entitlement.product, entitlement.registration_id))
+                .collect::<Vec<_>>()
+        );
     }
 
     #[test]
-    fn insert_product_dfs_ok() {
-        let expected = HashSet::from([
-            "AKBL1".into(),
-            "AKDS5".into(),
-            "ARAS1".into(),
-            "ARCS1".into(),
-            "ARCH1".into(),
-            "ARCM1".into(),
-        ]);
+    fn get_product_registrations_for_profile_as_of() {
+        let repo = setup_after_example_expiry();
+        repo.renew_product_registration(1, Some(3600)).unwrap();
+        repo.update_product_registration_status(2, RegistrationStatus::Revoked, Some("refund"))
+            .unwrap();
+        let as_of = |timestamp: &str| -> Option<chrono::DateTime<chrono::Utc>> {
+            chrono::DateTime::parse_from_rfc3339(timestamp)
+                .ok()
+                .map(|timestamp| timestamp.into())
+        };
+        let statuses = |as_of| -> Vec<(u64, RegistrationStatus)> {
+            repo.get_product_registrations_for_profile(1, None, as_of, 0, 10)
+                .into_iter()
+                .map(|record| (record.registration.id, record.registration.status))
+                .collect()
+        };
+
+        assert_eq!(
+            Vec::<(u64, RegistrationStatus)>::new(),
+            statuses(as_of("2022-06-01T00:00:00Z"))
+        );
+        assert_eq!(
+            vec![(1, RegistrationStatus::Active)],
+            statuses(as_of("2023-02-01T00:00:00Z"))
+        );
+        assert_eq!(
+            vec![
+                (1, RegistrationStatus::Expired),
+                (2, RegistrationStatus::Active)
+            ],
+            statuses(as_of("2024-06-01T00:00:00Z"))
+        );
+        assert_eq!(
+            vec![
+                (1, RegistrationStatus::Active),
+                (2, RegistrationStatus::Revoked)
+            ],
+            statuses(None)
+        );
+
+        let before_renewal = repo
+            .get_product_registration_as_of(1, as_of("2024-06-01T00:00:00Z").unwrap())
+            .unwrap();
+        assert_eq!(
+            as_of("2024-01-15T15:04:05Z"),
+            before_renewal.registration.expiry_at
+        );
+    }
+
+    #[test]
+    fn get_entitlements_as_of_before_transfer() {
+        let repo = setup_after_example_expiry();
+        repo.transfer_product_registration(2, 2).unwrap();
+        let as_of = chrono::DateTime::parse_from_rfc3339("2023-06-01T00:00:00Z")
+            .unwrap()
+            .into();
+
+        let products = |profile_id, as_of| -> Vec<String> {
+            repo.get_entitlements(profile_id, as_of)
+                .into_iter()
+                .map(|entitlement| entitlement.product)
+                .collect()
+        };
+
+        assert_eq!(
+            vec!["ARCC4".to_owned(), "ARIE4".to_owned()],
+            products(1, Some(as_of))
+        );
+        assert_eq!(Vec::<String>::new(), products(1, None));
+        assert_eq!(vec!["ARCM1".to_owned()], products(2, Some(as_of)));
+        assert_eq!(vec!["ARCC4".to_owned()], products(2, None));
+    }
+
+    #[test]
+    fn insert_backdated_product_registration_can_already_be_expired() {
+        let repo = setup_after_example_expiry();
+        repo.insert_product("WKMN1", &[], Some(3600)).unwrap();
+        let purchase_date = repo.current_time() - chrono::Duration::hours(2);
+
+        let expired = repo
+            .insert_product_registration(
+                2,
+                "WKMN1",
+                Some(purchase_date),
+                PurchaseDetails::default(),
+                None,
+            )
+            .unwrap();
+
+        assert_eq!(purchase_date, expired.registration.purchase_date);
+        assert_eq!(
+            Some(purchase_date + chrono::Duration::hours(1)),
+            expired.registration.expiry_at
+        );
+        assert_eq!(RegistrationStatus::Expired, expired.registration.status);
+        assert!(repo
+            .insert_product_registration(2, "WKMN1", None, PurchaseDetails::default(), None)
+            .is_ok());
+    }
+
+    #[test]
+    fn insert_product_registration_with_taken_serial_code_fails() {
+        let repo = setup_after_example_expiry();
+        let insert = |profile_id, product_sku| {
+            repo.insert_product_registration(
+                profile_id,
+                product_sku,
+                None,
+                PurchaseDetails::default(),
+                Some("Q1W2E3R4".into()),
+            )
+        };
+
+        let inserted = insert(2, "SKE48").unwrap();
+        assert_eq!("Q1W2E3R4", inserted.registration.serial_code);
+        assert_eq!(
+            vec!["Q1W2E3R4"],
+            inserted
+                .children
+                .iter()
+                .map(|child| child.serial_code.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Err(RepositoryError::SerialCodeTaken(2)),
+            insert(1, "NMB48").map(|record| record.registration.id)
+        );
+
+        repo.update_product_registration_status(
+            inserted.registration.id,
+            RegistrationStatus::Revoked,
+            None,
+        )
+        .unwrap();
+        assert!(insert(1, "NMB48").is_ok());
+    }
+
+    #[test]
+    fn luhn_mod_n_matches_luhn_for_digits() {
+        assert_eq!(
+            Some('3'),
+            serial::luhn_mod_n_check_character("7992739871", "0123456789")
+        );
+        assert_eq!(
+            None,
+            serial::luhn_mod_n_check_character("79927398A1", "0123456789")
+        );
+    }
+
+    #[test]
+    fn insert_product_registration_generates_serial_code_from_template() {
+        let repo = InMemoryProfileRepository::with_example_data(
+            serial::RandomSerialGenerator,
+            default_time_provider,
+        );
+        let serial_template = SerialTemplate {
+            prefix: "AK".into(),
+            groups: Vec::from([4, 4]),
+            separator: "-".into(),
+            alphabet: serial::UNAMBIGUOUS.into(),
+            check_digit: true,
+        };
+        repo.set_product_serial_template("SKE48", serial_template.clone())
+            .unwrap();
+
+        let inserted = repo
+            .insert_product_registration(2, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        let serial_code = &inserted.registration.serial_code;
+
+        assert_eq!(12, serial_code.len());
+        assert!(serial_code.starts_with("AK-"));
+        assert!(serial::is_serial_code_valid(&serial_template, serial_code));
 
+        // changing any character breaks the check digit
+        let mut mistyped: Vec<char> = serial_code.chars().collect();
+        mistyped[3] = if mistyped[3] == 'A' { 'B' } else { 'A' };
+        let mistyped: String = mistyped.into_iter().collect();
+        assert!(!serial::is_serial_code_valid(&serial_template, &mistyped));
+        assert!(!serial::is_serial_code_valid(
+            &serial_template,
+            &serial_code.replace('-', "")
+        ));
+    }
+
+    #[test]
+    fn insert_product_registration_retries_taken_serial_codes() {
+        let repo = InMemoryProfileRepository::with_example_data(
+            QueuedSerialGenerator(Mutex::new(Vec::from([
+                "FRESH002".into(),
+                "FRESH001".into(),
+                "A1B2C3D4".into(),
+                "A1B2C3D4".into(),
+            ]))),
+            default_time_provider,
+        );
+
+        let inserted = repo
+            .insert_product_registration(2, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        assert_eq!("FRESH001", inserted.registration.serial_code);
+        assert_eq!("FRESH002", inserted.children[0].serial_code);
+
+        // the queue is empty now, so every attempt gives the same empty serial code,
+        // which the leaf product and its registration of itself cannot share
+        assert_eq!(
+            Err(RepositoryError::SerialCodesExhausted("NMB48".into())),
+            repo.insert_product_registration(2, "NMB48", None, PurchaseDetails::default(), None)
+                .map(|record| record.registration.id)
+        );
+        assert!(repo.get_product_registration(6).is_none());
+    }
+
+    #[test]
+    fn get_product_registration_by_serial_follows_transfers() {
+        let repo = setup_after_example_expiry();
+        let inserted = repo
+            .insert_product_registration(1, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        let child_serial_code = inserted.children[0].serial_code.clone();
+
+        assert_eq!(
+            Some(inserted.registration.id),
+            repo.get_product_registration_by_serial(&child_serial_code)
+                .map(|record| record.registration.id)
+        );
+        assert_eq!(
+            Some(3),
+            repo.get_product_registration_by_serial("Z5X6C7V8")
+                .map(|record| record.registration.id)
+        );
+        assert!(repo
+            .get_product_registration_by_serial("NOTASERIAL")
+            .is_none());
+
+        let transferred = repo
+            .transfer_product_registration(inserted.registration.id, 2)
+            .unwrap();
+        assert_eq!(
+            Some(transferred.registration.id),
+            repo.get_product_registration_by_serial(&child_serial_code)
+                .map(|record| record.registration.id)
+        );
+    }
+
+    fn pooled(serial_code: &str, lot: &str) -> PooledSerialCode {
+        PooledSerialCode {
+            serial_code: serial_code.into(),
+            lot: lot.into(),
+        }
+    }
+
+    #[test]
+    fn insert_product_registration_takes_serial_codes_from_pool() {
+        let repo = setup_after_example_expiry();
+        let skipped = repo
+            .insert_serial_pool(
+                "SKE48",
+                Vec::from([
+                    pooled("POOL0001", "LOT-A"),
+                    pooled("POOL0002", "LOT-A"),
+                    pooled("A1B2C3D4", "LOT-A"),
+                    pooled("POOL0001", "LOT-B"),
+                    pooled("POOL0003", "LOT-B"),
+                ]),
+            )
+            .unwrap();
+        assert_eq!(Vec::from(["A1B2C3D4", "POOL0001"]), skipped);
+
+        let inserted = repo
+            .insert_product_registration(1, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        assert_eq!("POOL0001", inserted.registration.serial_code);
+        assert_eq!(Some("LOT-A".into()), inserted.registration.lot);
+        assert_eq!("POOL0002", inserted.children[0].serial_code);
+        assert_eq!(
+            Vec::from([("LOT-A".into(), 2, 0), ("LOT-B".into(), 1, 1)]),
+            repo.get_serial_pool("SKE48")
+                .into_iter()
+                .map(|lot| (lot.lot, lot.imported, lot.remaining))
+                .collect::<Vec<(String, usize, usize)>>()
+        );
+
+        // the pool runs out half way, so the generator covers the rest
+        repo.update_product_registration_status(
+            inserted.registration.id,
+            RegistrationStatus::Revoked,
+            None,
+        )
+        .unwrap();
+        let inserted = repo
+            .insert_product_registration(2, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        assert_eq!("POOL0003", inserted.registration.serial_code);
+        assert_eq!(Some("LOT-B".into()), inserted.registration.lot);
+        assert_eq!(None, inserted.children[0].lot);
+        assert!(repo
+            .get_serial_pool("SKE48")
+            .iter()
+            .all(|lot| lot.remaining == 0));
+    }
+
+    #[test]
+    fn insert_product_registration_sets_lot_of_pooled_serial_codes_only() {
+        let repo = InMemoryProfileRepository::with_example_data(
+            QueuedSerialGenerator(Mutex::new(Vec::from([
+                "FRESH002".into(),
+                "FRESH001".into(),
+                "POOL2001".into(),
+            ]))),
+            default_time_provider,
+        );
+        repo.insert_serial_pool(
+            "NMB48",
+            Vec::from([pooled("POOL2001", "LOT-F"), pooled("POOL2002", "LOT-F")]),
+        )
+        .unwrap();
+
+        // the generator never hands out a pooled serial code, not even one of another product
+        let inserted = repo
+            .insert_product_registration(2, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        assert_eq!("FRESH001", inserted.registration.serial_code);
+        assert_eq!("FRESH002", inserted.children[0].serial_code);
+        assert_eq!(None, inserted.registration.lot);
+
+        // a pooled serial code given by the customer was not taken from the pool
+        let inserted = repo
+            .insert_product_registration(
+                2,
+                "NMB48",
+                None,
+                PurchaseDetails::default(),
+                Some("POOL2002".into()),
+            )
+            .unwrap();
+        assert_eq!("POOL2002", inserted.registration.serial_code);
+        assert!(std::iter::once(&inserted.registration)
+            .chain(inserted.children.iter())
+            .all(|registration| registration.lot.is_none()));
+        assert!(repo
+            .get_product_registrations_for_lot("LOT-F")
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn get_product_registrations_for_lot_follows_transfers() {
+        let repo = setup_after_example_expiry();
+        repo.insert_serial_pool(
+            "SKE48",
+            Vec::from([pooled("POOL1001", "LOT-C"), pooled("POOL1002", "LOT-D")]),
+        )
+        .unwrap();
+        let inserted = repo
+            .insert_product_registration(1, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        assert!(repo.get_product_registrations_for_lot("LOT-E").is_none());
+
+        let transferred = repo
+            .transfer_product_registration(inserted.registration.id, 2)
+            .unwrap();
+        assert_eq!(Some("LOT-C".into()), transferred.registration.lot);
+        for lot in ["LOT-C", "LOT-D"] {
+            let records = repo.get_product_registrations_for_lot(lot).unwrap();
+            assert_eq!(
+                Vec::from([transferred.registration.id]),
+                records
+                    .iter()
+                    .map(|record| record.registration.id)
+                    .collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[test]
+    fn revoke_api_key_keeps_it_listed() {
         let repo = setup();
-        assert!(!repo.product_exists("foo"));
-        let actual = repo.insert_product("foo", &["ARIE4".into()], None);
+        let api_key = repo
+            .insert_api_key("support desk", ApiKeyRole::Support, "pbk_abc", "abc123")
+            .unwrap();
+        assert_eq!(
+            Some(api_key.id),
+            repo.get_api_key_by_hash("abc123").map(|api_key| api_key.id)
+        );
+        assert!(repo.get_api_key_by_hash("def456").is_none());
 
-        assert_eq!(expected, actual);
-        assert!(repo.product_exists("foo"));
+        let revoked = repo.revoke_api_key(api_key.id).unwrap();
+        assert!(revoked.revoked_at.is_some());
+        assert!(matches!(
+            repo.revoke_api_key(api_key.id),
+            Err(RepositoryError::InvalidOperation(_))
+        ));
+        assert_eq!(
+            Err(RepositoryError::NotFound),
+            repo.revoke_api_key(0).map(|_| ())
+        );
+        assert_eq!(1, repo.get_api_keys().len());
     }
 
     #[test]
-    fn insert_product_repeated_insert_ok() {
-        let expected = HashSet::from([
-            "AKBL1".into(),
-            "AKDS5".into(),
-            "ARAS1".into(),
-            "ARCS1".into(),
-            "ARCH1".into(),
-            "ARCM1".into(),
-            "SKE48".into(),
-            "NMB48".into(),
-        ]);
+    fn concurrent_product_registrations_do_not_duplicate_leaf_products() {
+        for _ in 0..200 {
+            let repo = setup();
+            let transferable = repo
+                .insert_product_registration(2, "AKB48", None, PurchaseDetails::default(), None)
+                .unwrap();
+            let start = std::sync::Barrier::new(8);
+
+            std::thread::scope(|scope| {
+                for thread in 0..8 {
+                    let (repo, start) = (&repo, &start);
+                    scope.spawn(move || {
+                        start.wait();
+                        let _ = match thread % 4 {
+                            0 => {
+                                repo.transfer_product_registration(transferable.registration.id, 1)
+                            }
+                            1 => repo.insert_product_registration(
+                                1,
+                                "AKB48",
+                                None,
+                                PurchaseDetails::default(),
+                                None,
+                            ),
+                            2 => repo.insert_product_registration(
+                                1,
+                                "SKE48",
+                                None,
+                                PurchaseDetails::default(),
+                                None,
+                            ),
+                            _ => repo.insert_product_registration(
+                                1,
+                                "NMB48",
+                                None,
+                                PurchaseDetails::default(),
+                                None,
+                            ),
+                        };
+                        repo.get_product_registrations_for_profile(1, None, None, 0, 100);
+                    });
+                }
+            });
+
+            let active_leaf_products: Vec<String> = repo
+                .get_product_registrations_for_profile(
+                    1,
+                    Some(RegistrationStatus::Active),
+                    None,
+                    0,
+                    100,
+                )
+                .iter()
+                .flat_map(registered_leaf_products)
+                .collect();
+            let unique_leaf_products: HashSet<&String> = active_leaf_products.iter().collect();
+            assert_eq!(unique_leaf_products.len(), active_leaf_products.len());
+            assert!(unique_leaf_products.contains(&String::from("SKE48")));
+            assert!(unique_leaf_products.contains(&String::from("NMB48")));
+        }
+    }
 
+    fn violation_kinds(violations: &[IntegrityViolation]) -> Vec<IntegrityViolationKind> {
+        violations.iter().map(|violation| violation.kind).collect()
+    }
+
+    #[test]
+    fn check_integrity_of_consistent_data_finds_nothing() {
         let repo = setup();
-        let _ = repo.insert_product("foo", &["ARIE4".into()], None);
-        let actual = repo.insert_product("bar", &["foo".into(), "AKB48".into()], None);
+        repo.insert_product_upgrade("SKE48", "AKB48").unwrap();
+        let bundle = repo
+            .insert_product_registration(1, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        repo.transfer_product_registration(bundle.registration.id, 2)
+            .unwrap();
+        let single = repo
+            .insert_product_registration(1, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        repo.upgrade_product_registration(single.registration.id, "AKB48", false)
+            .unwrap();
 
-        assert_eq!(expected, actual);
+        assert!(repo.check_integrity(true).unwrap().is_empty());
+        assert!(setup_after_example_expiry()
+            .check_integrity(false)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn check_integrity_reports_and_repairs_drifted_data() {
+        let repo = setup();
+        let bundle = repo
+            .insert_product_registration(1, "AKB48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        let bundle_id = bundle.registration.id;
+        let child = bundle.children[0].clone();
+
+        // a second registration of the same bundle which got past the conflict check
+        let mut overlapping = bundle.registration.clone();
+        overlapping.id = 1001;
+        overlapping.serial_code = "OVERLAP1".into();
+        repo.product_registrations.insert(1001, overlapping);
+        repo.profile_to_product_registrations
+            .get_mut(&1)
+            .unwrap()
+            .push(1001);
+        repo.serial_codes
+            .insert("OVERLAP1".into(), Vec::from([1001]));
+        // a registration of a profile which does not exist, and a child of a parent which does not exist
+        let mut orphan = child.clone();
+        (orphan.id, orphan.profile_id, orphan.parent_id) = (1002, 42, None);
+        repo.product_registrations.insert(1002, orphan);
+        let mut stray_child = child.clone();
+        (stray_child.id, stray_child.parent_id) = (1003, Some(999));
+        repo.product_registrations.insert(1003, stray_child);
+        // lookups which drifted from the registrations
+        repo.products.remove("AKB48");
+        repo.product_active_for.insert("GONE".into(), 60);
+        repo.product_registrations_children
+            .get_mut(&bundle_id)
+            .unwrap()
+            .retain(|&id| id != child.id);
+        repo.profile_to_product_registrations
+            .get_mut(&2)
+            .unwrap()
+            .push(bundle_id);
+        let drifted = snapshot_json(&repo);
+
+        let violations = repo.check_integrity(false).unwrap();
+        assert_eq!(drifted, snapshot_json(&repo));
+        let kinds = violation_kinds(&violations);
+        for kind in [
+            IntegrityViolationKind::MissingProfile,
+            IntegrityViolationKind::MissingProduct,
+            IntegrityViolationKind::InvalidParent,
+            IntegrityViolationKind::OverlappingRegistrations,
+            IntegrityViolationKind::IndexMismatch,
+        ] {
+            assert!(kinds.contains(&kind), "{:?} not found", kind);
+        }
+        assert!(violations.iter().any(|violation| {
+            violation.kind == IntegrityViolationKind::OverlappingRegistrations
+                && violation.registration_id == Some(1001)
+        }));
+
+        assert_eq!(violations, repo.check_integrity(true).unwrap());
+        assert!(repo.check_integrity(false).unwrap().is_empty());
+        assert!(repo.get_product_registration(1002).is_none());
+        assert!(repo.get_product_registration(1003).is_none());
+        assert_eq!(
+            Some(RegistrationStatus::Revoked),
+            repo.get_product_registration(1001)
+                .map(|record| record.registration.status)
+        );
+        assert_eq!(
+            Some(2),
+            repo.get_product_registration(bundle_id)
+                .map(|record| record.children.len())
+        );
+        assert_eq!(
+            HashSet::from(["SKE48".into(), "NMB48".into()]),
+            repo.get_leaf_products("AKB48")
+        );
+        assert!(repo
+            .get_profile_product_registration_ids(2)
+            .iter()
+            .all(|&id| id != bundle_id));
+        // products held are rebuilt, the bundle still holds its products
+        assert!(matches!(
+            repo.insert_product_registration(1, "AKB48", None, PurchaseDetails::default(), None),
+            Err(RepositoryError::Conflict(_))
+        ));
+    }
+
+    #[test]
+    fn check_integrity_repairs_are_journaled() {
+        let data_dir = test_data_dir("fsck");
+        let repo = setup()
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        repo.profile_to_product_registrations.remove(&2);
+        assert_eq!(1, repo.check_integrity(true).unwrap().len());
+        let expected = snapshot_json(&repo);
+        drop(repo);
+
+        let restarted = setup()
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        assert_eq!(expected, snapshot_json(&restarted));
+        assert!(restarted.check_integrity(false).unwrap().is_empty());
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    // Fresh directory for a test's data
+    fn test_data_dir(name: &str) -> std::path::PathBuf {
+        let data_dir =
+            std::env::temp_dir().join(format!("profile_backend-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        data_dir
+    }
+
+    fn snapshot_json(repo: &InMemoryProfileRepository) -> String {
+        serde_json::to_string(&repo.snapshot()).unwrap()
+    }
+
+    #[test]
+    fn concurrent_changes_share_journal_syncs() {
+        let data_dir = test_data_dir("group_sync");
+        let repo = setup()
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        std::thread::scope(|scope| {
+            for thread in 0..8 {
+                let repo = &repo;
+                scope.spawn(move || {
+                    for product in 0..10 {
+                        repo.insert_product(&format!("GS{}X{}", thread, product), &[], None)
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        let expected = snapshot_json(&repo);
+        drop(repo);
+
+        let restarted = setup()
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        assert_eq!(expected, snapshot_json(&restarted));
+        assert!(restarted.product_exists("GS7X9"));
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn journal_is_replayed_on_restart() {
+        let data_dir = test_data_dir("replayed");
+        let repo = setup()
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        repo.insert_product("BUNDLE", &["SKE48".into(), "NMB48".into()], Some(3600))
+            .unwrap();
+        repo.insert_product_upgrade("ARCM1", "BUNDLE").unwrap();
+        repo.set_product_serial_format("SKE48", "POOL[0-9]+")
+            .unwrap();
+        repo.insert_serial_pool(
+            "SKE48",
+            Vec::from([pooled("POOL2001", "LOT-E"), pooled("POOL2002", "LOT-E")]),
+        )
+        .unwrap();
+        let inserted = repo
+            .insert_product_registration(1, "BUNDLE", None, PurchaseDetails::default(), None)
+            .unwrap();
+        repo.renew_product_registration(inserted.registration.id, None)
+            .unwrap();
+        repo.transfer_product_registration(inserted.registration.id, 2)
+            .unwrap();
+        repo.update_product_registration_status(
+            2,
+            RegistrationStatus::Suspended,
+            Some("chargeback"),
+        )
+        .unwrap();
+        let api_key = repo
+            .insert_api_key("support desk", ApiKeyRole::Support, "pbk_abc", "abc123")
+            .unwrap();
+        repo.revoke_api_key(api_key.id).unwrap();
+        let expected = snapshot_json(&repo);
+        drop(repo);
+
+        let restarted = setup()
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        assert_eq!(expected, snapshot_json(&restarted));
+        // the serial code handed out of the pool stays taken
+        assert_eq!(1, restarted.get_serial_pool("SKE48")[0].remaining);
+        // products held are rebuilt, the transferred bundle still conflicts
+        assert!(matches!(
+            restarted.insert_product_registration(
+                2,
+                "SKE48",
+                None,
+                PurchaseDetails::default(),
+                None
+            ),
+            Err(RepositoryError::Conflict(_))
+        ));
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_replaces_journals_with_a_snapshot() {
+        let data_dir = test_data_dir("compaction");
+        let repo = setup()
+            .with_journal(&data_dir, FsyncPolicy::EverySecond)
+            .unwrap();
+        let first = repo
+            .insert_product_registration(1, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        repo.compact().unwrap();
+        let second = repo
+            .insert_product_registration(2, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        repo.sync_journal().unwrap();
+        drop(repo);
+
+        assert!(!data_dir.join("journal-0.log").exists());
+        assert!(data_dir.join("journal-1.log").exists());
+        let restarted = setup()
+            .with_journal(&data_dir, FsyncPolicy::EverySecond)
+            .unwrap();
+        for id in [first.registration.id, second.registration.id] {
+            assert!(restarted.get_product_registration(id).is_some());
+        }
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn journal_torn_mid_record_is_cut_off_on_restart() {
+        let data_dir = test_data_dir("torn");
+        let repo = setup()
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        let first = repo
+            .insert_product_registration(1, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        let second = repo
+            .insert_product_registration(2, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        drop(repo);
+
+        // the process died halfway through appending the second registration
+        let journal_path = data_dir.join("journal-0.log");
+        let journal = std::fs::read(&journal_path).unwrap();
+        let second_record_start = journal[..journal.len() - 1]
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .unwrap()
+            + 1;
+        let torn_length = second_record_start + (journal.len() - second_record_start) / 2;
+        std::fs::write(&journal_path, &journal[..torn_length]).unwrap();
+
+        let restarted = setup()
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        assert_eq!(
+            Some(1),
+            restarted
+                .get_product_registration(first.registration.id)
+                .map(|record| record.registration.profile_id)
+        );
+        assert!(restarted
+            .get_product_registrations_for_profile(2, None, None, 0, 10)
+            .iter()
+            .all(|record| record.registration.serial_code != second.registration.serial_code));
+
+        // the lost registration can be made again, and is kept from then on
+        let again = restarted
+            .insert_product_registration(2, "SKE48", None, PurchaseDetails::default(), None)
+            .unwrap();
+        drop(restarted);
+        let restarted = setup()
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        assert_eq!(
+            Some(again.registration.serial_code),
+            restarted
+                .get_product_registration(again.registration.id)
+                .map(|record| record.registration.serial_code)
+        );
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[test]
+    fn journal_corrupt_before_its_end_is_an_error() {
+        let data_dir = test_data_dir("corrupt");
+        let repo = setup()
+            .with_journal(&data_dir, FsyncPolicy::Always)
+            .unwrap();
+        for profile_id in [1, 2] {
+            repo.insert_product_registration(
+                profile_id,
+                "SKE48",
+                None,
+                PurchaseDetails::default(),
+                None,
+            )
+            .unwrap();
+        }
+        drop(repo);
+
+        let journal_path = data_dir.join("journal-0.log");
+        let mut journal = std::fs::read(&journal_path).unwrap();
+        journal[20] ^= 1;
+        std::fs::write(&journal_path, &journal).unwrap();
+
+        assert_eq!(
+            Some(io::ErrorKind::InvalidData),
+            setup()
+                .with_journal(&data_dir, FsyncPolicy::Always)
+                .err()
+                .map(|err| err.kind())
+        );
+
+        std::fs::remove_dir_all(&data_dir).unwrap();
     }
 }
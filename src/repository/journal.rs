@@ -0,0 +1,383 @@
+use std::{
+    cmp::max,
+    collections::BTreeSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+};
+
+use sha2::{Digest, Sha256};
+
+use super::model::{
+    ApiKey, PooledSerialCode, ProductRegistration, ProductRegistrationRenewal, SerialTemplate,
+    Snapshot,
+};
+
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.json.tmp";
+
+///
+/// When appended journal records are flushed to disk
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    // before the change is acknowledged, nothing acknowledged is lost. Appends made while a sync runs share the next one
+    Always,
+    // by a background task, up to a second of changes can be lost if the machine goes down
+    EverySecond,
+    // left to the operating system, changes survive the process crashing but not the machine
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "always" => Ok(FsyncPolicy::Always),
+            "every_second" => Ok(FsyncPolicy::EverySecond),
+            "never" => Ok(FsyncPolicy::Never),
+            _ => Err(format!(
+                "Unknown fsync policy:{}, expected always, every_second or never",
+                value
+            )),
+        }
+    }
+}
+
+///
+/// A change to the repository, applied the same way when it is made and when the journal is replayed
+///
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum JournalEntry {
+    ProductStored {
+        product: String,
+        leaf_products: BTreeSet<String>,
+        active_for: Option<u64>,
+    },
+    ProductUpgradeStored {
+        product: String,
+        upgrade: String,
+    },
+    ProductSerialFormatStored {
+        product: String,
+        serial_format: String,
+    },
+    ProductSerialTemplateStored {
+        product: String,
+        serial_template: SerialTemplate,
+    },
+    SerialCodesPooled {
+        product: String,
+        serial_codes: Vec<PooledSerialCode>,
+    },
+    // New and changed registrations as they are stored, children before their parent
+    ProductRegistrationsStored {
+        registrations: Vec<ProductRegistration>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        renewals: Vec<ProductRegistrationRenewal>,
+    },
+    ApiKeyStored(ApiKey),
+    // Replaces everything stored
+    SnapshotRestored(Box<Snapshot>),
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SnapshotFile {
+    // journals from this generation on were written after the snapshot was taken
+    generation: u64,
+    state: Snapshot,
+}
+
+fn journal_path(data_dir: &Path, generation: u64) -> PathBuf {
+    data_dir.join(format!("journal-{}.log", generation))
+}
+
+// First 4 bytes of the SHA-256, hex encoded
+fn checksum(record: &str) -> String {
+    Sha256::digest(record.as_bytes())[..4]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// A rename is only durable once the directory is synced
+fn sync_dir(data_dir: &Path) -> io::Result<()> {
+    File::open(data_dir)?.sync_all()
+}
+
+///
+/// Syncs a journal file for FsyncPolicy::Always outside of the journal, so one fsync covers every record appended
+/// while the previous one was running
+///
+struct GroupSync {
+    file: File,
+    // end of the last complete record, kept up to date by the journal
+    appended_len: AtomicU64,
+    state: Mutex<GroupSyncState>,
+    synced: Condvar,
+}
+
+struct GroupSyncState {
+    synced_len: u64,
+    syncing: bool,
+}
+
+impl GroupSync {
+    fn new(file: &File, len: u64) -> io::Result<Self> {
+        Ok(Self {
+            file: file.try_clone()?,
+            appended_len: AtomicU64::new(len),
+            state: Mutex::new(GroupSyncState {
+                synced_len: len,
+                syncing: false,
+            }),
+            synced: Condvar::new(),
+        })
+    }
+
+    fn wait_for(&self, len: u64) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        while state.synced_len < len {
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+
+            state.syncing = true;
+            drop(state);
+            // everything appended by now is covered, not just the record waited for
+            let appended_len = self.appended_len.load(Ordering::Acquire);
+            let synced = self.file.sync_data();
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            self.synced.notify_all();
+            synced?;
+            state.synced_len = max(state.synced_len, appended_len);
+        }
+
+        Ok(())
+    }
+}
+
+///
+/// A record which is appended but, with FsyncPolicy::Always, not synced yet
+///
+#[must_use]
+pub(crate) struct PendingSync(Option<(Arc<GroupSync>, u64)>);
+
+impl PendingSync {
+    // Blocks until the record is on disk
+    pub fn wait(self) -> io::Result<()> {
+        match self.0 {
+            Some((sync, len)) => sync.wait_for(len),
+            None => Ok(()),
+        }
+    }
+}
+
+///
+/// Append only log of the changes made since the last snapshot, one `<checksum> <json>` line per entry
+///
+pub(crate) struct Journal {
+    data_dir: PathBuf,
+    generation: u64,
+    file: File,
+    // end of the last complete record, a failed append is cut back to it
+    len: u64,
+    fsync_policy: FsyncPolicy,
+    // with FsyncPolicy::EverySecond, whether anything was appended since the last sync
+    unsynced: bool,
+    group_sync: Arc<GroupSync>,
+}
+
+impl Journal {
+    pub fn open(data_dir: &Path, generation: u64, fsync_policy: FsyncPolicy) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(journal_path(data_dir, generation))?;
+        let len = file.metadata()?.len();
+        sync_dir(data_dir)?;
+        let group_sync = Arc::new(GroupSync::new(&file, len)?);
+
+        Ok(Self {
+            data_dir: data_dir.to_owned(),
+            generation,
+            file,
+            len,
+            fsync_policy,
+            unsynced: false,
+            group_sync,
+        })
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    // No entries in this generation, including ones replayed on startup
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // The record is only known to be on disk once the returned PendingSync is waited for
+    pub fn append(&mut self, entry: &JournalEntry) -> io::Result<PendingSync> {
+        let record = serde_json::to_string(entry)?;
+        let line = format!("{} {}\n", checksum(&record), record);
+
+        if let Err(err) = self.file.write_all(line.as_bytes()) {
+            // a torn record would otherwise end up in the middle of the journal once the next one is appended
+            if let Err(truncate_err) = self.file.set_len(self.len) {
+                tracing::error!(
+                    "Unable to cut journal-{} back after a failed append: {}",
+                    self.generation,
+                    truncate_err
+                );
+            }
+            return Err(err);
+        }
+
+        self.len += line.len() as u64;
+        match self.fsync_policy {
+            FsyncPolicy::Always => {
+                self.group_sync
+                    .appended_len
+                    .store(self.len, Ordering::Release);
+                Ok(PendingSync(Some((self.group_sync.clone(), self.len))))
+            }
+            FsyncPolicy::EverySecond => {
+                self.unsynced = true;
+                Ok(PendingSync(None))
+            }
+            FsyncPolicy::Never => Ok(PendingSync(None)),
+        }
+    }
+
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+
+        Ok(())
+    }
+
+    // Carries on in a new generation, the entries of the current one will be covered by the next snapshot
+    pub fn rotate(&mut self) -> io::Result<u64> {
+        self.file.sync_data()?;
+        *self = Self::open(&self.data_dir, self.generation + 1, self.fsync_policy)?;
+
+        Ok(self.generation)
+    }
+}
+
+// Entries of a journal, a torn or corrupt last record is what a crash mid-append leaves behind, so it is cut off.
+// A corrupt record followed by others means the file was damaged and is an error.
+pub(crate) fn read_journal(data_dir: &Path, generation: u64) -> io::Result<Vec<JournalEntry>> {
+    let path = journal_path(data_dir, generation);
+    let content = fs::read(&path)?;
+
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < content.len() {
+        let end = content[offset..]
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map(|position| offset + position);
+        let entry = end.and_then(|end| {
+            let line = std::str::from_utf8(&content[offset..end]).ok()?;
+            let (line_checksum, record) = line.split_once(' ')?;
+            if checksum(record) != line_checksum {
+                return None;
+            }
+            serde_json::from_str::<JournalEntry>(record).ok()
+        });
+
+        match (entry, end) {
+            (Some(entry), Some(end)) => {
+                entries.push(entry);
+                offset = end + 1;
+            }
+            (_, end) if end.is_none_or(|end| end + 1 == content.len()) => {
+                tracing::warn!(
+                    "Cutting off the torn last record of journal-{} at byte {}",
+                    generation,
+                    offset
+                );
+                let file = OpenOptions::new().write(true).open(&path)?;
+                file.set_len(offset as u64)?;
+                file.sync_all()?;
+                break;
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "journal-{} has a corrupt record at byte {}",
+                        generation, offset
+                    ),
+                ))
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+// Generations of the journals in the data dir, oldest first
+pub(crate) fn journal_generations(data_dir: &Path) -> io::Result<Vec<u64>> {
+    let mut generations = Vec::new();
+    for dir_entry in fs::read_dir(data_dir)? {
+        let file_name = dir_entry?.file_name();
+        let generation = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix("journal-"))
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|generation| generation.parse::<u64>().ok());
+        generations.extend(generation);
+    }
+    generations.sort();
+
+    Ok(generations)
+}
+
+// The snapshot and the generation of the first journal written after it, None if no snapshot was taken yet
+pub(crate) fn read_snapshot(data_dir: &Path) -> io::Result<Option<(u64, Snapshot)>> {
+    let content = match fs::read(data_dir.join(SNAPSHOT_FILE)) {
+        Ok(content) => content,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let snapshot: SnapshotFile = serde_json::from_slice(&content)?;
+
+    Ok(Some((snapshot.generation, snapshot.state)))
+}
+
+// Written next to the current one and renamed over it, so a crash leaves either the old or the new snapshot
+pub(crate) fn write_snapshot(data_dir: &Path, generation: u64, state: Snapshot) -> io::Result<()> {
+    let tmp_path = data_dir.join(SNAPSHOT_TMP_FILE);
+    let mut file = File::create(&tmp_path)?;
+    serde_json::to_writer(&mut file, &SnapshotFile { generation, state })?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, data_dir.join(SNAPSHOT_FILE))?;
+    sync_dir(data_dir)
+}
+
+// Journals covered by the snapshot of `generation`
+pub(crate) fn remove_journals_before(data_dir: &Path, generation: u64) -> io::Result<()> {
+    for old_generation in journal_generations(data_dir)? {
+        if old_generation < generation {
+            fs::remove_file(journal_path(data_dir, old_generation))?;
+        }
+    }
+
+    Ok(())
+}
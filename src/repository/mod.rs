@@ -1,36 +1,213 @@
 use std::collections::HashSet;
 
-use model::{ProductRegistrationRecord, Profile};
+use model::{
+    ApiKey, ApiKeyRole, IntegrityViolation, PooledSerialCode, ProductEntitlement,
+    ProductRegistrationRecord, ProductRegistrationRenewal, Profile, PurchaseDetails,
+    RegistrationStatus, SerialPoolLot, SerialTemplate, Snapshot,
+};
 
+pub mod fsck;
+pub mod id;
 pub mod inram;
+pub mod journal;
 pub mod model;
+pub mod serial;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RepositoryError {
+    NotFound,
+    // Products which would end up with more than one active registration
+    Conflict(HashSet<String>),
+    // Serial code is already held by a registration of the profile with this id, which may be a different profile
+    SerialCodeTaken(u64),
+    // No unused serial code could be generated for this product
+    SerialCodesExhausted(String),
+    InvalidOperation(String),
+    // The change could not be persisted. It was not made, unless only syncing it to disk failed
+    Storage(String),
+}
 
 ///
 /// Interface for accessing data
-/// An in-memory implementation is provided
-/// This can be replaced with a database model too, but the interface will require some slight
-/// modification to account for the fact that a db is a remote connection, and can fail
+/// An in-memory implementation is provided, which can persist its changes to local disk
+/// This can be replaced with a database model too, changes already report RepositoryError::Storage
+/// when they could not be stored, but reads would need some slight modification as they can fail too
 ///
 pub trait ProfileRepository {
+    ///
+    /// Clock every change made by the repository is stamped with
+    ///
+    fn current_time(&self) -> chrono::DateTime<chrono::Utc>;
     fn get_profiles(&self, start: u64, count: usize) -> Vec<Profile>;
     fn get_profile(&self, id: u64) -> Option<Profile>;
     fn get_product_registrations_for_profile(
         &self,
         profile_id: u64,
+        status: Option<RegistrationStatus>,
+        // Lists registrations as they were at this point in time
+        as_of: Option<chrono::DateTime<chrono::Utc>>,
         start: u64,
         count: usize,
     ) -> Vec<ProductRegistrationRecord>;
     fn get_product_registration(&self, id: u64) -> Option<ProductRegistrationRecord>;
+    ///
+    /// Top level registration holding the serial code, the latest one if the serial code was passed on
+    ///
+    fn get_product_registration_by_serial(
+        &self,
+        serial_code: &str,
+    ) -> Option<ProductRegistrationRecord>;
+    ///
+    /// A registration as it was at `as_of`, with later status changes and renewals undone,
+    /// None if it did not exist yet
+    ///
+    fn get_product_registration_as_of(
+        &self,
+        id: u64,
+        as_of: chrono::DateTime<chrono::Utc>,
+    ) -> Option<ProductRegistrationRecord>;
+    ///
+    /// Registers the product, dated at `purchase_date` or now.
+    /// Expiry is counted from the purchase date, so a backdated registration can already be expired.
+    /// A supplied `serial_code` has to be unique across all registrations which are not revoked,
+    /// otherwise a serial code is generated.
+    ///
     fn insert_product_registration(
         &self,
         profile_id: u64,
         product_sku: &str,
-    ) -> Result<ProductRegistrationRecord, HashSet<String>>;
+        purchase_date: Option<chrono::DateTime<chrono::Utc>>,
+        purchase_details: PurchaseDetails,
+        serial_code: Option<String>,
+    ) -> Result<ProductRegistrationRecord, RepositoryError>;
+    ///
+    /// Products a profile is entitled to, bundles and the leaf products in them,
+    /// either now or at `as_of`
+    ///
+    fn get_entitlements(
+        &self,
+        profile_id: u64,
+        as_of: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Vec<ProductEntitlement>;
+    fn get_leaf_products(&self, product: &str) -> HashSet<String>;
     fn product_exists(&self, product: &str) -> bool;
+    ///
+    /// Pattern customer supplied serial codes of a product have to match
+    ///
+    fn set_product_serial_format(
+        &self,
+        product: &str,
+        serial_format: &str,
+    ) -> Result<(), RepositoryError>;
+    fn get_product_serial_format(&self, product: &str) -> Option<String>;
+    ///
+    /// Template serial codes of a product are generated from, the default template is used if none was set
+    ///
+    fn set_product_serial_template(
+        &self,
+        product: &str,
+        serial_template: SerialTemplate,
+    ) -> Result<(), RepositoryError>;
+    fn get_product_serial_template(&self, product: &str) -> Option<SerialTemplate>;
+    ///
+    /// Adds serial codes to the product's pool, which new registrations take serial codes from before falling back to
+    /// generating them. Returns the serial codes which were skipped, as they are already pooled or registered.
+    ///
+    fn insert_serial_pool(
+        &self,
+        product: &str,
+        serial_codes: Vec<PooledSerialCode>,
+    ) -> Result<Vec<String>, RepositoryError>;
+    fn get_serial_pool(&self, product: &str) -> Vec<SerialPoolLot>;
+    ///
+    /// Top level registrations currently holding a serial code of the lot, None if no serial codes of the lot were imported
+    ///
+    fn get_product_registrations_for_lot(
+        &self,
+        lot: &str,
+    ) -> Option<Vec<ProductRegistrationRecord>>;
     fn insert_product(
         &self,
         product: &str,
         subproducts: &[String],
         active_for: Option<u64>,
-    ) -> HashSet<String>;
+    ) -> Result<HashSet<String>, RepositoryError>;
+    ///
+    /// Extends the expiry of a top level registration and its children by `period` seconds,
+    /// or by the product's `active_for` if no period is given
+    ///
+    fn renew_product_registration(
+        &self,
+        id: u64,
+        period: Option<u64>,
+    ) -> Result<ProductRegistrationRecord, RepositoryError>;
+    ///
+    /// Moves a top level registration and its children to `status`, only transitions allowed by
+    /// `RegistrationStatus::can_transition_to` are accepted
+    ///
+    fn update_product_registration_status(
+        &self,
+        id: u64,
+        status: RegistrationStatus,
+        reason: Option<&str>,
+    ) -> Result<ProductRegistrationRecord, RepositoryError>;
+    ///
+    /// Moves a top level registration and its children to another profile, the existing
+    /// registrations are marked as transferred and copies are created for the target profile
+    ///
+    fn transfer_product_registration(
+        &self,
+        id: u64,
+        target_profile_id: u64,
+    ) -> Result<ProductRegistrationRecord, RepositoryError>;
+    ///
+    /// Declares that registrations of `product` can be upgraded to `upgrade`, returns every
+    /// product `product` can be upgraded to
+    ///
+    fn insert_product_upgrade(
+        &self,
+        product: &str,
+        upgrade: &str,
+    ) -> Result<HashSet<String>, RepositoryError>;
+    ///
+    /// Replaces a top level registration with a registration of `product_sku`, serial codes of
+    /// leaf products present in both are kept. With `prorate`, the time left on the upgraded
+    /// registration is added to the new expiry
+    ///
+    fn upgrade_product_registration(
+        &self,
+        id: u64,
+        product_sku: &str,
+        prorate: bool,
+    ) -> Result<ProductRegistrationRecord, RepositoryError>;
+    ///
+    /// API keys are only stored hashed, `key_hash` has to be unique
+    ///
+    fn insert_api_key(
+        &self,
+        name: &str,
+        role: ApiKeyRole,
+        prefix: &str,
+        key_hash: &str,
+    ) -> Result<ApiKey, RepositoryError>;
+    fn get_api_key_by_hash(&self, key_hash: &str) -> Option<ApiKey>;
+    fn get_api_keys(&self) -> Vec<ApiKey>;
+    fn revoke_api_key(&self, id: u64) -> Result<ApiKey, RepositoryError>;
+    fn get_product_registration_renewals(&self, id: u64)
+        -> Option<Vec<ProductRegistrationRenewal>>;
+    ///
+    /// Everything stored, as of a single point in time
+    ///
+    fn export_snapshot(&self) -> Snapshot;
+    ///
+    /// Fills a repository without profiles, products or registrations with the snapshot, the API keys it already has
+    /// are kept alongside the ones of the snapshot
+    ///
+    fn import_snapshot(&self, snapshot: Snapshot) -> Result<(), RepositoryError>;
+    ///
+    /// Checks that registrations lead to existing profiles, products and parents, that no product is held by two
+    /// registrations of a profile and that the lookups match the registrations. With `repair`, everything found
+    /// is repaired in a single change
+    ///
+    fn check_integrity(&self, repair: bool) -> Result<Vec<IntegrityViolation>, RepositoryError>;
 }
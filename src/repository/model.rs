@@ -1,4 +1,6 @@
-#[derive(Clone)]
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct Profile {
     pub id: u64,
     pub email: String,
@@ -12,7 +14,7 @@ pub struct ProductRegistrationRecord {
     pub children: Vec<ProductRegistration>,
 }
 
-#[derive(Clone)]
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
 pub struct ProductRegistration {
     pub id: u64,
     // Foreign Key
@@ -20,7 +22,189 @@ pub struct ProductRegistration {
     // parent key
     pub parent_id: Option<u64>,
     pub purchase_date: chrono::DateTime<chrono::Utc>,
+    pub purchase_details: PurchaseDetails,
     pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
     pub product: String,
     pub serial_code: String,
+    // Manufacturing lot, when the serial code came from a serial pool
+    pub lot: Option<String>,
+    // Expiry is derived from expiry_at, so an active registration can still be expired
+    pub status: RegistrationStatus,
+    // Oldest first, the last entry is the current status
+    pub status_history: Vec<RegistrationStatusChange>,
+    // Registration of the previous owner, when this registration was transferred in
+    pub transferred_from: Option<u64>,
+    // Registration of the next owner, set once the registration is transferred out
+    pub transferred_to: Option<u64>,
+    // Registration this one replaced through an upgrade
+    pub upgraded_from: Option<u64>,
+    // Registration that replaced this one through an upgrade
+    pub upgraded_to: Option<u64>,
+}
+
+// Where the product was bought, as reported when registering it
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct PurchaseDetails {
+    pub retailer: Option<String>,
+    pub channel: Option<String>,
+    pub order_reference: Option<String>,
+    // in minor units of the currency, e.g. cents
+    pub price: Option<u64>,
+    // ISO 4217 code, set whenever price is
+    pub currency: Option<String>,
+}
+
+// Layout of generated serial codes, e.g. prefix AR with groups [4, 4] and separator - gives AR-XXXX-XXXX
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct SerialTemplate {
+    pub prefix: String,
+    // length of each group of characters, the check character is the last one of the last group
+    pub groups: Vec<usize>,
+    pub separator: String,
+    pub alphabet: String,
+    pub check_digit: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize)]
+pub enum RegistrationStatus {
+    Pending,
+    Active,
+    Suspended,
+    Expired,
+    Revoked,
+    Transferred,
+    Upgraded,
+}
+
+impl RegistrationStatus {
+    pub fn can_transition_to(&self, next: RegistrationStatus) -> bool {
+        use RegistrationStatus::*;
+
+        matches!(
+            (self, next),
+            (Pending, Active | Revoked)
+                | (
+                    Active,
+                    Suspended | Expired | Revoked | Transferred | Upgraded
+                )
+                | (Suspended, Active | Expired | Revoked)
+                | (Expired, Active | Revoked)
+        )
+    }
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct RegistrationStatusChange {
+    pub status: RegistrationStatus,
+    pub changed_at: chrono::DateTime<chrono::Utc>,
+    pub reason: Option<String>,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct ProductRegistrationRenewal {
+    // Foreign Key
+    pub registration_id: u64,
+    pub renewed_at: chrono::DateTime<chrono::Utc>,
+    pub period: u64,
+    pub previous_expiry_at: chrono::DateTime<chrono::Utc>,
+    pub expiry_at: chrono::DateTime<chrono::Utc>,
+}
+
+// Serial code pre-allocated to a manufacturing lot
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct PooledSerialCode {
+    pub serial_code: String,
+    pub lot: String,
+}
+
+#[derive(Clone)]
+pub struct SerialPoolLot {
+    pub lot: String,
+    pub imported: usize,
+    // imported serial codes which were not handed out yet
+    pub remaining: usize,
+}
+
+#[derive(Clone)]
+pub struct ProductEntitlement {
+    pub product: String,
+    // Top level registration the product is registered through
+    pub registration_id: u64,
+    pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ApiKeyRole {
+    Admin,
+    Support,
+    Partner,
+    ReadOnly,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct ApiKey {
+    pub id: u64,
+    pub name: String,
+    // Start of the key, so it can be recognised without storing it
+    pub prefix: String,
+    // SHA-256 of the key, hex encoded
+    pub key_hash: String,
+    pub role: ApiKeyRole,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+// Serial codes left in a product's pool and how many were imported per lot
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct SnapshotSerialPool {
+    pub available: Vec<PooledSerialCode>,
+    pub imported: BTreeMap<String, usize>,
+}
+
+///
+/// Everything stored by the repository at one point in time, the lookups which can be derived from it are left out
+///
+#[derive(Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    pub profiles: Vec<Profile>,
+    pub product_registrations: Vec<ProductRegistration>,
+    pub profile_to_product_registrations: BTreeMap<u64, Vec<u64>>,
+    pub product_registrations_children: BTreeMap<u64, Vec<u64>>,
+    pub products: BTreeMap<String, BTreeSet<String>>,
+    pub product_active_for: BTreeMap<String, u64>,
+    pub product_upgrades: BTreeMap<String, BTreeSet<String>>,
+    pub product_serial_formats: BTreeMap<String, String>,
+    pub product_serial_templates: BTreeMap<String, SerialTemplate>,
+    pub serial_codes: BTreeMap<String, Vec<u64>>,
+    pub serial_pools: BTreeMap<String, SnapshotSerialPool>,
+    pub lot_serial_codes: BTreeMap<String, Vec<String>>,
+    pub product_registration_renewals: BTreeMap<u64, Vec<ProductRegistrationRenewal>>,
+    pub api_keys: Vec<ApiKey>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntegrityViolationKind {
+    // Registration of a profile which does not exist
+    MissingProfile,
+    // Product which is registered, bundled or given an active_for but is not in the catalog
+    MissingProduct,
+    // Child registration whose parent does not exist, is a child itself or belongs to another profile
+    InvalidParent,
+    // Product held by more than one registration of a profile
+    OverlappingRegistrations,
+    // Lookup which does not match the registrations
+    IndexMismatch,
+}
+
+///
+/// Something found by the integrity check, along with how it is repaired
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IntegrityViolation {
+    pub kind: IntegrityViolationKind,
+    pub profile_id: Option<u64>,
+    pub registration_id: Option<u64>,
+    pub product: Option<String>,
+    pub description: String,
+    pub repair: String,
 }
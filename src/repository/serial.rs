@@ -0,0 +1,145 @@
+use rand::Rng;
+
+use super::model::SerialTemplate;
+
+pub const ALPHANUMERIC: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+// Without 0/O and 1/I, which are easily mistaken for each other when typed from the box
+pub const UNAMBIGUOUS: &str = "23456789ABCDEFGHJKLMNPQRSTUVWXYZ";
+
+///
+/// Generates serial codes for new registrations
+/// Any `fn() -> String` can be used as a generator, ignoring the template
+///
+pub trait SerialGenerator: Send + Sync {
+    fn generate(&self, template: &SerialTemplate) -> String;
+}
+
+impl<F: Fn() -> String + Send + Sync> SerialGenerator for F {
+    fn generate(&self, _template: &SerialTemplate) -> String {
+        self()
+    }
+}
+
+// Fills the template with random characters from its alphabet
+pub struct RandomSerialGenerator;
+
+impl SerialGenerator for RandomSerialGenerator {
+    fn generate(&self, template: &SerialTemplate) -> String {
+        let alphabet: Vec<char> = template.alphabet.chars().collect();
+        let length = template.groups.iter().sum::<usize>();
+        let random_length = if template.check_digit {
+            length.saturating_sub(1)
+        } else {
+            length
+        };
+
+        let mut rng = rand::thread_rng();
+        let mut payload: String = (0..random_length)
+            .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+            .collect();
+        if template.check_digit {
+            if let Some(check) = luhn_mod_n_check_character(&payload, &template.alphabet) {
+                payload.push(check);
+            }
+        }
+
+        format_serial_code(template, &payload)
+    }
+}
+
+impl Default for SerialTemplate {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            groups: Vec::from([15]),
+            separator: String::new(),
+            alphabet: ALPHANUMERIC.into(),
+            check_digit: false,
+        }
+    }
+}
+
+// Prefix and groups joined by the separator, e.g. AR-XXXX-XXXX
+fn format_serial_code(template: &SerialTemplate, payload: &str) -> String {
+    let mut chars = payload.chars();
+    let mut parts = Vec::new();
+    if !template.prefix.is_empty() {
+        parts.push(template.prefix.clone());
+    }
+    for group in template.groups.iter() {
+        parts.push(chars.by_ref().take(*group).collect::<String>());
+    }
+
+    parts.join(&template.separator)
+}
+
+///
+/// Luhn mod N over the template's alphabet, returns None if the payload has a character outside it
+///
+pub fn luhn_mod_n_check_character(payload: &str, alphabet: &str) -> Option<char> {
+    let alphabet: Vec<char> = alphabet.chars().collect();
+    let n = alphabet.len();
+
+    let mut factor = 2;
+    let mut sum = 0;
+    for c in payload.chars().rev() {
+        let code_point = alphabet.iter().position(|a| *a == c)?;
+        let addend = factor * code_point;
+        sum += addend / n + addend % n;
+        factor = if factor == 2 { 1 } else { 2 };
+    }
+
+    Some(alphabet[(n - sum % n) % n])
+}
+
+///
+/// Checks the serial code has the template's layout and, if it has one, a valid check character
+/// This only needs the template, so clients can do it without calling the service
+///
+pub fn is_serial_code_valid(template: &SerialTemplate, serial_code: &str) -> bool {
+    let Some(payload) = serial_code.strip_prefix(template.prefix.as_str()) else {
+        return false;
+    };
+    let payload = match template.prefix.is_empty() {
+        true => Some(payload),
+        false => payload.strip_prefix(template.separator.as_str()),
+    };
+    let Some(payload) = payload else {
+        return false;
+    };
+
+    let mut characters = String::new();
+    let mut rest = payload;
+    for (i, group) in template.groups.iter().enumerate() {
+        if i > 0 {
+            let Some(stripped) = rest.strip_prefix(template.separator.as_str()) else {
+                return false;
+            };
+            rest = stripped;
+        }
+
+        let end = rest
+            .char_indices()
+            .nth(*group)
+            .map_or(rest.len(), |(end, _)| end);
+        let part = &rest[..end];
+        if part.chars().count() != *group || !part.chars().all(|c| template.alphabet.contains(c)) {
+            return false;
+        }
+        characters.push_str(part);
+        rest = &rest[end..];
+    }
+    if !rest.is_empty() {
+        return false;
+    }
+
+    if !template.check_digit {
+        return true;
+    }
+
+    let mut payload = characters.chars();
+    let Some(check) = payload.next_back() else {
+        return false;
+    };
+    luhn_mod_n_check_character(payload.as_str(), &template.alphabet) == Some(check)
+}
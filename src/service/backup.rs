@@ -0,0 +1,67 @@
+use sha2::{Digest, Sha256};
+
+use crate::repository::model::Snapshot;
+
+const BACKUP_FORMAT: &str = "profile_backend_backup";
+// Bumped whenever Snapshot changes in a way older versions can't read
+const BACKUP_VERSION: u32 = 1;
+
+// First line of a backup, the snapshot follows as JSON
+#[derive(serde::Serialize, serde::Deserialize)]
+struct BackupHeader {
+    format: String,
+    version: u32,
+    created_at: chrono::DateTime<chrono::Utc>,
+    // SHA-256 of everything after the header line, hex encoded
+    sha256: String,
+}
+
+fn sha256_hex(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub(crate) fn write_backup(
+    snapshot: &Snapshot,
+    created_at: chrono::DateTime<chrono::Utc>,
+) -> Vec<u8> {
+    let state = serde_json::to_vec(snapshot).expect("snapshots only hold plain data");
+    let header = BackupHeader {
+        format: BACKUP_FORMAT.into(),
+        version: BACKUP_VERSION,
+        created_at,
+        sha256: sha256_hex(&state),
+    };
+
+    let mut backup = serde_json::to_vec(&header).expect("headers only hold plain data");
+    backup.push(b'\n');
+    backup.extend(state);
+    backup
+}
+
+// The reason is meant for whoever is restoring the backup
+pub(crate) fn read_backup(backup: &[u8]) -> Result<Snapshot, String> {
+    let header_end = backup
+        .iter()
+        .position(|&byte| byte == b'\n')
+        .ok_or("not a backup")?;
+    let (header, state) = (&backup[..header_end], &backup[header_end + 1..]);
+    let header: BackupHeader = serde_json::from_slice(header).map_err(|_| "not a backup")?;
+
+    if header.format != BACKUP_FORMAT {
+        return Err("not a backup".into());
+    }
+    if header.version > BACKUP_VERSION {
+        return Err(format!(
+            "backup is version {}, only versions up to {} can be restored",
+            header.version, BACKUP_VERSION
+        ));
+    }
+    if sha256_hex(state) != header.sha256 {
+        return Err("backup does not match its checksum, it is damaged".into());
+    }
+
+    serde_json::from_slice(state).map_err(|err| format!("backup cannot be read: {}", err))
+}
@@ -1,13 +1,25 @@
+use crate::license::{LicenseSigningKey, LicenseVerifyingKey};
+
 pub struct ProfileServiceConfig {
+    pub public_url: String,
     pub profile_per_page: usize,
     pub product_registrations_per_page: usize,
+    // How far back a purchase date can be when registering a product
+    pub max_purchase_backdate_days: u32,
+    pub license_signing_key: Option<LicenseSigningKey>,
+    // Keys which were rotated out, license keys they signed are still valid
+    pub license_verifying_keys: Vec<LicenseVerifyingKey>,
 }
 
 impl Default for ProfileServiceConfig {
     fn default() -> Self {
         Self {
+            public_url: String::from("http://localhost:3000"),
             profile_per_page: 30,
             product_registrations_per_page: 30,
+            max_purchase_backdate_days: 365,
+            license_signing_key: None,
+            license_verifying_keys: Vec::new(),
         }
     }
 }
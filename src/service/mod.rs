@@ -1,3 +1,4 @@
+mod backup;
 pub mod config;
 pub mod model;
 mod profile_service;
@@ -24,9 +24,17 @@ impl From<crate::repository::model::Profile> for Profile {
 pub struct ProductRegistration {
     pub id: u64,
     pub purchase_date: chrono::DateTime<chrono::Utc>,
+    pub purchase_details: PurchaseDetails,
     pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
     pub product: String,
     pub serial_code: String,
+    pub lot: Option<String>,
+    pub status: RegistrationStatus,
+    pub status_history: Vec<RegistrationStatusChange>,
+    pub transferred_from: Option<u64>,
+    pub transferred_to: Option<u64>,
+    pub upgraded_from: Option<u64>,
+    pub upgraded_to: Option<u64>,
 }
 
 impl From<crate::repository::model::ProductRegistration> for ProductRegistration {
@@ -34,9 +42,190 @@ impl From<crate::repository::model::ProductRegistration> for ProductRegistration
         ProductRegistration {
             id: value.id,
             purchase_date: value.purchase_date,
+            purchase_details: value.purchase_details.into(),
             expiry_at: value.expiry_at,
             product: value.product,
             serial_code: value.serial_code,
+            lot: value.lot,
+            status: value.status.into(),
+            status_history: value
+                .status_history
+                .into_iter()
+                .map(|change| change.into())
+                .collect(),
+            transferred_from: value.transferred_from,
+            transferred_to: value.transferred_to,
+            upgraded_from: value.upgraded_from,
+            upgraded_to: value.upgraded_to,
+        }
+    }
+}
+
+pub const DEFAULT_SERIAL_ALPHABET: &str = crate::repository::serial::UNAMBIGUOUS;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerialTemplate {
+    pub prefix: String,
+    pub groups: Vec<usize>,
+    pub separator: String,
+    pub alphabet: String,
+    pub check_digit: bool,
+}
+
+impl From<crate::repository::model::SerialTemplate> for SerialTemplate {
+    fn from(value: crate::repository::model::SerialTemplate) -> Self {
+        SerialTemplate {
+            prefix: value.prefix,
+            groups: value.groups,
+            separator: value.separator,
+            alphabet: value.alphabet,
+            check_digit: value.check_digit,
+        }
+    }
+}
+
+impl From<SerialTemplate> for crate::repository::model::SerialTemplate {
+    fn from(value: SerialTemplate) -> Self {
+        crate::repository::model::SerialTemplate {
+            prefix: value.prefix,
+            groups: value.groups,
+            separator: value.separator,
+            alphabet: value.alphabet,
+            check_digit: value.check_digit,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PooledSerialCode {
+    pub serial_code: String,
+    pub lot: String,
+}
+
+impl From<PooledSerialCode> for crate::repository::model::PooledSerialCode {
+    fn from(value: PooledSerialCode) -> Self {
+        crate::repository::model::PooledSerialCode {
+            serial_code: value.serial_code,
+            lot: value.lot,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerialPoolImport {
+    pub imported: usize,
+    // already pooled or registered, or repeated in the import
+    pub skipped: std::collections::BTreeSet<String>,
+}
+
+// What a restored backup held
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupSummary {
+    pub profiles: usize,
+    pub products: usize,
+    pub product_registrations: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntegrityViolationKind {
+    MissingProfile,
+    MissingProduct,
+    InvalidParent,
+    OverlappingRegistrations,
+    IndexMismatch,
+}
+
+impl From<crate::repository::model::IntegrityViolationKind> for IntegrityViolationKind {
+    fn from(value: crate::repository::model::IntegrityViolationKind) -> Self {
+        use crate::repository::model::IntegrityViolationKind as Kind;
+
+        match value {
+            Kind::MissingProfile => IntegrityViolationKind::MissingProfile,
+            Kind::MissingProduct => IntegrityViolationKind::MissingProduct,
+            Kind::InvalidParent => IntegrityViolationKind::InvalidParent,
+            Kind::OverlappingRegistrations => IntegrityViolationKind::OverlappingRegistrations,
+            Kind::IndexMismatch => IntegrityViolationKind::IndexMismatch,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IntegrityViolation {
+    pub kind: IntegrityViolationKind,
+    pub profile_id: Option<u64>,
+    pub registration_id: Option<u64>,
+    pub product: Option<String>,
+    pub description: String,
+    pub repair: String,
+}
+
+impl From<crate::repository::model::IntegrityViolation> for IntegrityViolation {
+    fn from(value: crate::repository::model::IntegrityViolation) -> Self {
+        IntegrityViolation {
+            kind: value.kind.into(),
+            profile_id: value.profile_id,
+            registration_id: value.registration_id,
+            product: value.product,
+            description: value.description,
+            repair: value.repair,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IntegrityReport {
+    pub violations: Vec<IntegrityViolation>,
+    // whether the violations were repaired, or only reported
+    pub repaired: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerialPoolLot {
+    pub lot: String,
+    pub imported: usize,
+    pub remaining: usize,
+}
+
+impl From<crate::repository::model::SerialPoolLot> for SerialPoolLot {
+    fn from(value: crate::repository::model::SerialPoolLot) -> Self {
+        SerialPoolLot {
+            lot: value.lot,
+            imported: value.imported,
+            remaining: value.remaining,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PurchaseDetails {
+    pub retailer: Option<String>,
+    pub channel: Option<String>,
+    pub order_reference: Option<String>,
+    // in minor units of the currency
+    pub price: Option<u64>,
+    pub currency: Option<String>,
+}
+
+impl From<crate::repository::model::PurchaseDetails> for PurchaseDetails {
+    fn from(value: crate::repository::model::PurchaseDetails) -> Self {
+        PurchaseDetails {
+            retailer: value.retailer,
+            channel: value.channel,
+            order_reference: value.order_reference,
+            price: value.price,
+            currency: value.currency,
+        }
+    }
+}
+
+impl From<PurchaseDetails> for crate::repository::model::PurchaseDetails {
+    fn from(value: PurchaseDetails) -> Self {
+        crate::repository::model::PurchaseDetails {
+            retailer: value.retailer,
+            channel: value.channel,
+            order_reference: value.order_reference,
+            price: value.price,
+            currency: value.currency,
         }
     }
 }
@@ -55,3 +244,244 @@ impl From<crate::repository::model::ProductRegistrationRecord> for ProductRegist
         }
     }
 }
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProductRegistrationRenewal {
+    pub registration_id: u64,
+    pub renewed_at: chrono::DateTime<chrono::Utc>,
+    pub period: u64,
+    pub previous_expiry_at: chrono::DateTime<chrono::Utc>,
+    pub expiry_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<crate::repository::model::ProductRegistrationRenewal> for ProductRegistrationRenewal {
+    fn from(value: crate::repository::model::ProductRegistrationRenewal) -> Self {
+        ProductRegistrationRenewal {
+            registration_id: value.registration_id,
+            renewed_at: value.renewed_at,
+            period: value.period,
+            previous_expiry_at: value.previous_expiry_at,
+            expiry_at: value.expiry_at,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegistrationStatus {
+    Pending,
+    Active,
+    Suspended,
+    Expired,
+    Revoked,
+    Transferred,
+    Upgraded,
+}
+
+impl From<crate::repository::model::RegistrationStatus> for RegistrationStatus {
+    fn from(value: crate::repository::model::RegistrationStatus) -> Self {
+        use crate::repository::model::RegistrationStatus as Status;
+
+        match value {
+            Status::Pending => RegistrationStatus::Pending,
+            Status::Active => RegistrationStatus::Active,
+            Status::Suspended => RegistrationStatus::Suspended,
+            Status::Expired => RegistrationStatus::Expired,
+            Status::Revoked => RegistrationStatus::Revoked,
+            Status::Transferred => RegistrationStatus::Transferred,
+            Status::Upgraded => RegistrationStatus::Upgraded,
+        }
+    }
+}
+
+impl From<RegistrationStatus> for crate::repository::model::RegistrationStatus {
+    fn from(value: RegistrationStatus) -> Self {
+        use crate::repository::model::RegistrationStatus as Status;
+
+        match value {
+            RegistrationStatus::Pending => Status::Pending,
+            RegistrationStatus::Active => Status::Active,
+            RegistrationStatus::Suspended => Status::Suspended,
+            RegistrationStatus::Expired => Status::Expired,
+            RegistrationStatus::Revoked => Status::Revoked,
+            RegistrationStatus::Transferred => Status::Transferred,
+            RegistrationStatus::Upgraded => Status::Upgraded,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegistrationStatusChange {
+    pub status: RegistrationStatus,
+    pub changed_at: chrono::DateTime<chrono::Utc>,
+    pub reason: Option<String>,
+}
+
+impl From<crate::repository::model::RegistrationStatusChange> for RegistrationStatusChange {
+    fn from(value: crate::repository::model::RegistrationStatusChange) -> Self {
+        RegistrationStatusChange {
+            status: value.status.into(),
+            changed_at: value.changed_at,
+            reason: value.reason,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProductRegistrationOwner {
+    pub registration_id: u64,
+    pub profile_id: u64,
+    pub owned_from: chrono::DateTime<chrono::Utc>,
+    // None for the current owner
+    pub owned_until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<crate::repository::model::ProductRegistration> for ProductRegistrationOwner {
+    fn from(value: crate::repository::model::ProductRegistration) -> Self {
+        use crate::repository::model::RegistrationStatus as Status;
+
+        ProductRegistrationOwner {
+            registration_id: value.id,
+            profile_id: value.profile_id,
+            owned_from: value
+                .status_history
+                .first()
+                .map(|change| change.changed_at)
+                .unwrap_or(value.purchase_date),
+            owned_until: value
+                .status_history
+                .iter()
+                .find(|change| change.status == Status::Transferred)
+                .map(|change| change.changed_at),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entitlement {
+    pub product: String,
+    pub registration_id: u64,
+    pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<crate::repository::model::ProductEntitlement> for Entitlement {
+    fn from(value: crate::repository::model::ProductEntitlement) -> Self {
+        Entitlement {
+            product: value.product,
+            registration_id: value.registration_id,
+            expiry_at: value.expiry_at,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EntitlementCheck {
+    pub product: String,
+    pub entitled: bool,
+    // None if the entitlement does not expire, or the profile is not entitled
+    pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
+    // Registrations granting the product, more than one if a bundle is covered by several
+    pub granted_by: Vec<ProductRegistrationRecord>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LicenseKey {
+    pub key_id: String,
+    pub license_key: String,
+    pub claims: crate::license::LicenseClaims,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegistrationCertificate {
+    pub registration_id: u64,
+    pub profile_name: String,
+    pub product: String,
+    pub serial_code: String,
+    pub purchase_date: chrono::DateTime<chrono::Utc>,
+    pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub status: RegistrationStatus,
+    // Public page confirming the registration, encoded in the certificate's QR code
+    pub verification_url: String,
+    pub issued_at: chrono::DateTime<chrono::Utc>,
+}
+
+// What the public gets to know about a registration, anything else is reported as inactive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifiedStatus {
+    Active,
+    Expired,
+    Inactive,
+}
+
+///
+/// Proof a serial code is registered, without anything about who it is registered to
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SerialCodeVerification {
+    pub product: String,
+    pub status: VerifiedStatus,
+    pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApiKeyRole {
+    Admin,
+    Support,
+    Partner,
+    ReadOnly,
+}
+
+impl From<crate::repository::model::ApiKeyRole> for ApiKeyRole {
+    fn from(value: crate::repository::model::ApiKeyRole) -> Self {
+        use crate::repository::model::ApiKeyRole as Role;
+
+        match value {
+            Role::Admin => ApiKeyRole::Admin,
+            Role::Support => ApiKeyRole::Support,
+            Role::Partner => ApiKeyRole::Partner,
+            Role::ReadOnly => ApiKeyRole::ReadOnly,
+        }
+    }
+}
+
+impl From<ApiKeyRole> for crate::repository::model::ApiKeyRole {
+    fn from(value: ApiKeyRole) -> Self {
+        use crate::repository::model::ApiKeyRole as Role;
+
+        match value {
+            ApiKeyRole::Admin => Role::Admin,
+            ApiKeyRole::Support => Role::Support,
+            ApiKeyRole::Partner => Role::Partner,
+            ApiKeyRole::ReadOnly => Role::ReadOnly,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApiKey {
+    pub id: u64,
+    pub name: String,
+    pub prefix: String,
+    pub role: ApiKeyRole,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<crate::repository::model::ApiKey> for ApiKey {
+    fn from(value: crate::repository::model::ApiKey) -> Self {
+        ApiKey {
+            id: value.id,
+            name: value.name,
+            prefix: value.prefix,
+            role: value.role.into(),
+            created_at: value.created_at,
+            revoked_at: value.revoked_at,
+        }
+    }
+}
+
+// The only time the key itself is handed out
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CreatedApiKey {
+    pub api_key: ApiKey,
+    pub key: String,
+}
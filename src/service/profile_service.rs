@@ -1,16 +1,35 @@
-use std::{collections::HashSet, sync::OnceLock};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    sync::OnceLock,
+};
 
 use super::{
-    model::{ProductRegistrationRecord, Profile},
+    backup,
+    model::{
+        ApiKey, ApiKeyRole, BackupSummary, CreatedApiKey, Entitlement, EntitlementCheck,
+        IntegrityReport, LicenseKey, PooledSerialCode, ProductRegistrationOwner,
+        ProductRegistrationRecord, ProductRegistrationRenewal, Profile, PurchaseDetails,
+        RegistrationCertificate, RegistrationStatus, SerialCodeVerification, SerialPoolImport,
+        SerialPoolLot, SerialTemplate, VerifiedStatus,
+    },
     ProfileServiceConfig,
 };
-use crate::repository::ProfileRepository;
+use crate::{
+    license::{verify_license_key, LicenseClaims, LicenseError, LicenseVerifyingKey},
+    repository::{serial::is_serial_code_valid, ProfileRepository, RepositoryError},
+};
 
+use rand::{distributions::Alphanumeric, Rng};
 use regex::Regex;
+use sha2::{Digest, Sha256};
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ProfileServiceError {
     BadRequest(String),
+    NotFound(String),
+    Conflict(String),
+    // Serial code is already registered to another profile
+    SerialCodeTaken(String),
     InternalServiceError(String),
 }
 
@@ -37,11 +56,216 @@ fn is_product_sku_valid(product: &str) -> Result<(), &'static str> {
     Ok(())
 }
 
+fn currency_verification_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new("^[A-Z]{3}$").unwrap())
+}
+
+fn is_purchase_details_valid(purchase_details: &PurchaseDetails) -> Result<(), &'static str> {
+    match (&purchase_details.price, &purchase_details.currency) {
+        (Some(_), None) => Err("price requires a currency"),
+        (None, Some(_)) => Err("currency is only allowed along with a price"),
+        (Some(_), Some(currency)) if !currency_verification_regex().is_match(currency) => {
+            Err("currency has to be a 3 letter ISO 4217 code")
+        }
+        _ => Ok(()),
+    }
+}
+
+// Percent encodes everything but unreserved characters, serial codes can be supplied by customers
+fn encode_path_segment(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+const API_KEY_PREFIX: &str = "pbk_";
+const API_KEY_LENGTH: usize = 40;
+// Shown in listings, so keys can be told apart
+const API_KEY_SHOWN_LENGTH: usize = 12;
+const MIN_API_KEY_LENGTH: usize = 32;
+
+fn hash_api_key(key: &str) -> String {
+    Sha256::digest(key.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn registration_error(
+    product_registration_id: u64,
+    action: &str,
+    err: RepositoryError,
+) -> ProfileServiceError {
+    match err {
+        RepositoryError::NotFound => ProfileServiceError::NotFound(format!(
+            "product_registration:{} does not exist",
+            product_registration_id
+        )),
+        RepositoryError::Conflict(products) => {
+            tracing::warn!(
+                "Unable to {} product_registration:{}, products {:?} are already registered",
+                action,
+                product_registration_id,
+                products
+            );
+
+            ProfileServiceError::Conflict(format!(
+                "Unable to {} registration as this will create a duplicate registration:{:?}",
+                action, products
+            ))
+        }
+        RepositoryError::SerialCodeTaken(_) => ProfileServiceError::Conflict(format!(
+            "Unable to {} registration as its serial code is already registered",
+            action
+        )),
+        RepositoryError::SerialCodesExhausted(product) => {
+            ProfileServiceError::InternalServiceError(format!(
+                "Unable to {} registration, no serial code could be generated for product:{}",
+                action, product
+            ))
+        }
+        RepositoryError::InvalidOperation(msg) => ProfileServiceError::BadRequest(msg),
+        RepositoryError::Storage(msg) => {
+            tracing::error!(
+                "Unable to {} product_registration:{}, it could not be stored: {}",
+                action,
+                product_registration_id,
+                msg
+            );
+
+            ProfileServiceError::InternalServiceError(format!(
+                "Unable to {} registration, it could not be stored: {}",
+                action, msg
+            ))
+        }
+    }
+}
+
+// For changes which can only fail to be stored
+fn storage_error(action: &str, err: RepositoryError) -> ProfileServiceError {
+    ProfileServiceError::InternalServiceError(format!("Unable to {}: {:?}", action, err))
+}
+
+const MAX_SERIAL_CODE_LENGTH: usize = 64;
+// 100 years, well within what a timestamp can be extended by
+const MAX_RENEWAL_PERIOD: u64 = 100 * 365 * 24 * 60 * 60;
+
+fn is_serial_template_valid(serial_template: &SerialTemplate) -> Result<(), &'static str> {
+    if serial_template.groups.is_empty() || serial_template.groups.contains(&0) {
+        return Err("groups have to be at least 1 character long");
+    }
+    // Summed without overflowing, the group sizes are up to the client
+    let length = serial_template
+        .groups
+        .iter()
+        .try_fold(0usize, |length, group| length.checked_add(*group))
+        .filter(|length| *length <= MAX_SERIAL_CODE_LENGTH)
+        .ok_or("serial codes can be at most 64 characters long")?;
+    if serial_template.check_digit && length < 2 {
+        return Err("a check digit needs at least one other character");
+    }
+
+    let alphabet: HashSet<char> = serial_template.alphabet.chars().collect();
+    if alphabet.len() < 2 || alphabet.len() != serial_template.alphabet.chars().count() {
+        return Err("alphabet has to have at least 2 characters, without repeating any");
+    }
+    if serial_template
+        .separator
+        .chars()
+        .any(|c| alphabet.contains(&c))
+    {
+        return Err("separator cannot use characters of the alphabet");
+    }
+
+    Ok(())
+}
+
+// Serial formats have to match the whole serial code, not just part of it
+fn serial_format_regex(serial_format: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", serial_format))
+}
+
 impl<Repo: ProfileRepository> ProfileService<Repo> {
     pub fn new(repo: Repo, config: ProfileServiceConfig) -> Self {
         Self { repo, config }
     }
 
+    ///
+    /// For maintenance of the storage, like taking snapshots
+    ///
+    pub fn repository(&self) -> &Repo {
+        &self.repo
+    }
+
+    ///
+    /// Versioned archive of everything stored, with a checksum so a damaged backup is refused when restoring it
+    ///
+    pub fn export_backup(&self) -> Vec<u8> {
+        let snapshot = self.repo.export_snapshot();
+        tracing::info!(
+            "Exporting a backup of {} profiles and {} product registrations",
+            snapshot.profiles.len(),
+            snapshot.product_registrations.len()
+        );
+
+        backup::write_backup(&snapshot, self.repo.current_time())
+    }
+
+    ///
+    /// Restores a backup made by export_backup, only into a repository without profiles, products or registrations
+    ///
+    pub fn restore_backup(&self, backup: &[u8]) -> Result<BackupSummary, ProfileServiceError> {
+        let snapshot = backup::read_backup(backup).map_err(ProfileServiceError::BadRequest)?;
+        let summary = BackupSummary {
+            profiles: snapshot.profiles.len(),
+            products: snapshot.products.len(),
+            product_registrations: snapshot.product_registrations.len(),
+        };
+
+        match self.repo.import_snapshot(snapshot) {
+            Ok(()) => {
+                tracing::info!("Restored a backup of {:?}", summary);
+                Ok(summary)
+            }
+            Err(RepositoryError::InvalidOperation(msg)) => {
+                tracing::warn!("Unable to restore a backup: {}", msg);
+                Err(ProfileServiceError::Conflict(
+                    "backups can only be restored when there are no profiles, products or registrations yet".into(),
+                ))
+            }
+            Err(err) => Err(storage_error("restore backup", err)),
+        }
+    }
+
+    ///
+    /// Looks for registrations, products and lookups which don't add up, and with `repair` repairs them
+    ///
+    pub fn check_integrity(&self, repair: bool) -> Result<IntegrityReport, ProfileServiceError> {
+        let violations = self
+            .repo
+            .check_integrity(repair)
+            .map_err(|err| storage_error("repair the data", err))?;
+        if !violations.is_empty() {
+            tracing::warn!(
+                "Integrity check found {} violations, repaired: {}",
+                violations.len(),
+                repair
+            );
+        }
+
+        Ok(IntegrityReport {
+            repaired: repair && !violations.is_empty(),
+            violations: violations.into_iter().map(|v| v.into()).collect(),
+        })
+    }
+
     pub fn get_profiles(&self, page: u32) -> Vec<Profile> {
         let start = page * self.config.profile_per_page as u32;
 
@@ -55,6 +279,8 @@ impl<Repo: ProfileRepository> ProfileService<Repo> {
     pub fn get_product_registrations_for_profile(
         &self,
         profile_id: u64,
+        status: Option<RegistrationStatus>,
+        as_of: Option<chrono::DateTime<chrono::Utc>>,
         page: u32,
     ) -> Option<Vec<ProductRegistrationRecord>> {
         let _ = self.repo.get_profile(profile_id)?;
@@ -62,6 +288,8 @@ impl<Repo: ProfileRepository> ProfileService<Repo> {
 
         let profile_registrations = self.repo.get_product_registrations_for_profile(
             profile_id,
+            status.map(|status| status.into()),
+            as_of,
             start.into(),
             self.config.product_registrations_per_page,
         );
@@ -83,6 +311,118 @@ impl<Repo: ProfileRepository> ProfileService<Repo> {
             .map(|registration| registration.into())
     }
 
+    pub fn get_product_registration_by_serial(
+        &self,
+        serial_code: &str,
+    ) -> Option<ProductRegistrationRecord> {
+        self.repo
+            .get_product_registration_by_serial(serial_code)
+            .map(|registration| registration.into())
+    }
+
+    ///
+    /// Public check of a serial code, leaving out the profile and registration history
+    ///
+    pub fn verify_serial_code(&self, serial_code: &str) -> Option<SerialCodeVerification> {
+        let record: ProductRegistrationRecord = self
+            .repo
+            .get_product_registration_by_serial(serial_code)?
+            .into();
+        // Children expire on their own, so everything is reported from the registration holding the serial code
+        let registration = std::iter::once(&record.registration)
+            .chain(record.children.iter())
+            .find(|registration| registration.serial_code == serial_code)
+            .unwrap_or(&record.registration);
+
+        Some(SerialCodeVerification {
+            product: registration.product.clone(),
+            status: match registration.status {
+                RegistrationStatus::Active => VerifiedStatus::Active,
+                RegistrationStatus::Expired => VerifiedStatus::Expired,
+                _ => VerifiedStatus::Inactive,
+            },
+            expiry_at: registration.expiry_at,
+        })
+    }
+
+    pub fn get_entitlements(
+        &self,
+        profile_id: u64,
+        as_of: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Option<Vec<Entitlement>> {
+        let _ = self.repo.get_profile(profile_id)?;
+
+        Some(
+            self.repo
+                .get_entitlements(profile_id, as_of)
+                .into_iter()
+                .map(|entitlement| entitlement.into())
+                .collect(),
+        )
+    }
+
+    ///
+    /// Whether a profile is entitled to a product right now, or at `as_of`, either through a
+    /// registration of the product itself, or, for bundles, through registrations covering all of
+    /// its leaf products
+    ///
+    pub fn get_entitlement(
+        &self,
+        profile_id: u64,
+        product_sku: &str,
+        as_of: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Option<EntitlementCheck> {
+        let _ = self.repo.get_profile(profile_id)?;
+
+        let entitlements: HashMap<String, Entitlement> = self
+            .repo
+            .get_entitlements(profile_id, as_of)
+            .into_iter()
+            .map(|entitlement| (entitlement.product.clone(), entitlement.into()))
+            .collect();
+
+        let granting_entitlements = match entitlements.get(product_sku) {
+            Some(entitlement) => Vec::from([entitlement]),
+            None => {
+                let leaf_products = self.repo.get_leaf_products(product_sku);
+                let bundle_entitlements: Option<Vec<&Entitlement>> = leaf_products
+                    .iter()
+                    .filter(|leaf| leaf.as_str() != product_sku)
+                    .map(|leaf| entitlements.get(leaf))
+                    .collect();
+
+                match bundle_entitlements {
+                    Some(bundle_entitlements) if !bundle_entitlements.is_empty() => {
+                        bundle_entitlements
+                    }
+                    _ => Vec::new(),
+                }
+            }
+        };
+
+        let registration_ids: BTreeSet<u64> = granting_entitlements
+            .iter()
+            .map(|entitlement| entitlement.registration_id)
+            .collect();
+
+        Some(EntitlementCheck {
+            product: product_sku.to_owned(),
+            entitled: !granting_entitlements.is_empty(),
+            expiry_at: granting_entitlements
+                .iter()
+                .filter_map(|entitlement| entitlement.expiry_at)
+                .min(),
+            granted_by: registration_ids
+                .into_iter()
+                .filter_map(|id| match as_of {
+                    Some(as_of) => self.repo.get_product_registration_as_of(id, as_of),
+                    None => self.repo.get_product_registration(id),
+                })
+                .map(|registration| registration.into())
+                .collect(),
+        })
+    }
+
     pub fn create_product(
         &self,
         product: &str,
@@ -136,20 +476,394 @@ impl<Repo: ProfileRepository> ProfileService<Repo> {
             )));
         }
 
-        let products = self.repo.insert_product(product, subproducts, active_for);
+        let products = self
+            .repo
+            .insert_product(product, subproducts, active_for)
+            .map_err(|err| storage_error("create product", err))?;
 
         Ok(products)
     }
 
+    pub fn create_product_upgrade(
+        &self,
+        product: &str,
+        upgrade: &str,
+    ) -> Result<HashSet<String>, ProfileServiceError> {
+        for p in [product, upgrade] {
+            if let Err(msg) = is_product_sku_valid(p) {
+                return Err(ProfileServiceError::BadRequest(String::from(msg)));
+            }
+
+            if !self.repo.product_exists(p) {
+                return Err(ProfileServiceError::BadRequest(format!(
+                    "product:{} does not exist",
+                    p
+                )));
+            }
+        }
+
+        if product == upgrade {
+            return Err(ProfileServiceError::BadRequest(format!(
+                "product:{} cannot be upgraded to itself",
+                product
+            )));
+        }
+
+        self.repo
+            .insert_product_upgrade(product, upgrade)
+            .map_err(|err| storage_error("create product upgrade", err))
+    }
+
+    pub fn set_product_serial_format(
+        &self,
+        product: &str,
+        serial_format: &str,
+    ) -> Result<(), ProfileServiceError> {
+        if !self.repo.product_exists(product) {
+            return Err(ProfileServiceError::NotFound(format!(
+                "product:{} does not exist",
+                product
+            )));
+        }
+
+        if let Err(err) = serial_format_regex(serial_format) {
+            return Err(ProfileServiceError::BadRequest(format!(
+                "serial_format is not a valid regex: {}",
+                err
+            )));
+        }
+
+        self.repo
+            .set_product_serial_format(product, serial_format)
+            .map_err(|err| storage_error("set serial format", err))
+    }
+
+    pub fn set_product_serial_template(
+        &self,
+        product: &str,
+        serial_template: SerialTemplate,
+    ) -> Result<SerialTemplate, ProfileServiceError> {
+        if !self.repo.product_exists(product) {
+            return Err(ProfileServiceError::NotFound(format!(
+                "product:{} does not exist",
+                product
+            )));
+        }
+
+        if let Err(msg) = is_serial_template_valid(&serial_template) {
+            return Err(ProfileServiceError::BadRequest(String::from(msg)));
+        }
+
+        self.repo
+            .set_product_serial_template(product, serial_template.clone().into())
+            .map_err(|err| storage_error("set serial template", err))?;
+
+        Ok(serial_template)
+    }
+
+    pub fn get_product_serial_template(&self, product: &str) -> Option<SerialTemplate> {
+        self.repo
+            .get_product_serial_template(product)
+            .map(|serial_template| serial_template.into())
+    }
+
+    ///
+    /// Everything printed on a registration's certificate, as of now
+    ///
+    pub fn get_registration_certificate(
+        &self,
+        product_registration_id: u64,
+    ) -> Option<RegistrationCertificate> {
+        let registration = self
+            .repo
+            .get_product_registration(product_registration_id)?
+            .registration;
+        let profile = self.repo.get_profile(registration.profile_id)?;
+
+        Some(RegistrationCertificate {
+            registration_id: registration.id,
+            profile_name: format!("{} {}", profile.firstname, profile.lastname),
+            verification_url: format!(
+                "{}/api/v1/verify/{}",
+                self.config.public_url.trim_end_matches('/'),
+                encode_path_segment(&registration.serial_code)
+            ),
+            product: registration.product,
+            serial_code: registration.serial_code,
+            purchase_date: registration.purchase_date,
+            expiry_at: registration.expiry_at,
+            status: registration.status.into(),
+            issued_at: self.repo.current_time(),
+        })
+    }
+
+    ///
+    /// Creates an API key with a random key, which is only returned here
+    ///
+    pub fn create_api_key(
+        &self,
+        name: &str,
+        role: ApiKeyRole,
+    ) -> Result<CreatedApiKey, ProfileServiceError> {
+        let random: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(API_KEY_LENGTH)
+            .map(char::from)
+            .collect();
+        let key = format!("{}{}", API_KEY_PREFIX, random);
+
+        Ok(CreatedApiKey {
+            api_key: self.import_api_key(name, role, &key)?,
+            key,
+        })
+    }
+
+    ///
+    /// Stores an API key chosen elsewhere, e.g. the admin key given in the config
+    ///
+    pub fn import_api_key(
+        &self,
+        name: &str,
+        role: ApiKeyRole,
+        key: &str,
+    ) -> Result<ApiKey, ProfileServiceError> {
+        if name.trim().is_empty() {
+            return Err(ProfileServiceError::BadRequest(
+                "api key name cannot be empty".into(),
+            ));
+        }
+        if key.len() < MIN_API_KEY_LENGTH {
+            return Err(ProfileServiceError::BadRequest(format!(
+                "api keys have to be at least {} characters",
+                MIN_API_KEY_LENGTH
+            )));
+        }
+
+        let key_hash = hash_api_key(key);
+        if self.repo.get_api_key_by_hash(&key_hash).is_some() {
+            return Err(ProfileServiceError::Conflict(
+                "api key already exists".into(),
+            ));
+        }
+
+        let prefix: String = key.chars().take(API_KEY_SHOWN_LENGTH).collect();
+        let api_key = self
+            .repo
+            .insert_api_key(name, role.into(), &prefix, &key_hash)
+            .map_err(|err| storage_error("create api key", err))?;
+        tracing::info!(
+            "Created api_key:{} {} with role {:?}",
+            api_key.id,
+            api_key.name,
+            role
+        );
+
+        Ok(api_key.into())
+    }
+
+    pub fn get_api_keys(&self) -> Vec<ApiKey> {
+        self.repo
+            .get_api_keys()
+            .into_iter()
+            .map(|api_key| api_key.into())
+            .collect()
+    }
+
+    pub fn revoke_api_key(&self, id: u64) -> Result<ApiKey, ProfileServiceError> {
+        match self.repo.revoke_api_key(id) {
+            Ok(api_key) => {
+                tracing::info!("Revoked api_key:{} {}", api_key.id, api_key.name);
+                Ok(api_key.into())
+            }
+            Err(RepositoryError::NotFound) => Err(ProfileServiceError::NotFound(format!(
+                "api_key:{} does not exist",
+                id
+            ))),
+            Err(RepositoryError::InvalidOperation(msg)) => {
+                Err(ProfileServiceError::BadRequest(msg))
+            }
+            Err(err) => {
+                tracing::error!("Unable to revoke api_key:{}: {:?}", id, err);
+
+                Err(ProfileServiceError::InternalServiceError(String::from(
+                    "Unable to revoke api key",
+                )))
+            }
+        }
+    }
+
+    ///
+    /// The API key the key belongs to, None if it is unknown or revoked
+    ///
+    pub fn authenticate_api_key(&self, key: &str) -> Option<ApiKey> {
+        self.repo
+            .get_api_key_by_hash(&hash_api_key(key))
+            .filter(|api_key| api_key.revoked_at.is_none())
+            .map(|api_key| api_key.into())
+    }
+
+    ///
+    /// Signs an offline license key for an active registration, if a license signing key is configured
+    /// The profile is identified in the claims by the id `public_profile_id` gives it
+    ///
+    pub fn create_license_key(
+        &self,
+        product_registration_id: u64,
+        public_profile_id: impl FnOnce(u64) -> String,
+    ) -> Result<LicenseKey, ProfileServiceError> {
+        let Some(signing_key) = &self.config.license_signing_key else {
+            return Err(ProfileServiceError::NotFound(
+                "license keys are not configured".into(),
+            ));
+        };
+        let Some(registration) = self.repo.get_product_registration(product_registration_id) else {
+            return Err(ProfileServiceError::NotFound(format!(
+                "product_registration:{} does not exist",
+                product_registration_id
+            )));
+        };
+
+        let registration = registration.registration;
+        if registration.status != crate::repository::model::RegistrationStatus::Active {
+            return Err(ProfileServiceError::BadRequest(format!(
+                "registration is {:?}, only active registrations get a license key",
+                registration.status
+            )));
+        }
+
+        let claims = LicenseClaims {
+            profile_id: public_profile_id(registration.profile_id),
+            sku: registration.product,
+            serial_code: registration.serial_code,
+            expiry_at: registration.expiry_at,
+            issued_at: self.repo.current_time(),
+        };
+
+        Ok(LicenseKey {
+            key_id: signing_key.key_id.clone(),
+            license_key: signing_key.sign(&claims),
+            claims,
+        })
+    }
+
+    ///
+    /// Public keys license keys can be verified with, the one currently signing first
+    ///
+    pub fn get_license_verifying_keys(&self) -> Vec<LicenseVerifyingKey> {
+        self.config
+            .license_signing_key
+            .iter()
+            .map(|signing_key| signing_key.verifying_key())
+            .chain(self.config.license_verifying_keys.iter().cloned())
+            .collect()
+    }
+
+    pub fn verify_license_key(
+        &self,
+        license_key: &str,
+    ) -> Result<LicenseClaims, ProfileServiceError> {
+        let verifying_keys = self.get_license_verifying_keys();
+
+        verify_license_key(license_key, &verifying_keys, self.repo.current_time()).map_err(|err| {
+            ProfileServiceError::BadRequest(match err {
+                LicenseError::Malformed => "license key is malformed".into(),
+                LicenseError::UnknownKeyId(key_id) => {
+                    format!("license key was signed with unknown key:{}", key_id)
+                }
+                LicenseError::InvalidSignature => "license key signature is invalid".into(),
+                LicenseError::Expired => "license key has expired".into(),
+            })
+        })
+    }
+
+    pub fn import_serial_pool(
+        &self,
+        product: &str,
+        serial_codes: Vec<PooledSerialCode>,
+    ) -> Result<SerialPoolImport, ProfileServiceError> {
+        if !self.repo.product_exists(product) {
+            return Err(ProfileServiceError::NotFound(format!(
+                "product:{} does not exist",
+                product
+            )));
+        }
+
+        let has_serial_format = self.repo.get_product_serial_format(product).is_some()
+            || self.repo.get_product_serial_template(product).is_some();
+        for pooled in serial_codes.iter() {
+            if pooled.serial_code.is_empty() || pooled.lot.is_empty() {
+                return Err(ProfileServiceError::BadRequest(
+                    "serial codes and lots cannot be empty".into(),
+                ));
+            }
+
+            // without a format, the factory's serial codes are taken as they are
+            if has_serial_format {
+                self.validate_serial_code(product, &pooled.serial_code)?;
+            }
+        }
+
+        let imported = serial_codes.len();
+        let skipped = self
+            .repo
+            .insert_serial_pool(
+                product,
+                serial_codes.into_iter().map(|s| s.into()).collect(),
+            )
+            .map_err(|err| storage_error("import serial codes", err))?;
+        if !skipped.is_empty() {
+            tracing::warn!(
+                "Skipped importing serial codes {:?} for product:{}, they are already known",
+                skipped,
+                product
+            );
+        }
+
+        Ok(SerialPoolImport {
+            imported: imported - skipped.len(),
+            skipped: skipped.into_iter().collect(),
+        })
+    }
+
+    pub fn get_serial_pool(&self, product: &str) -> Option<Vec<SerialPoolLot>> {
+        if !self.repo.product_exists(product) {
+            return None;
+        }
+
+        Some(
+            self.repo
+                .get_serial_pool(product)
+                .into_iter()
+                .map(|lot| lot.into())
+                .collect(),
+        )
+    }
+
+    pub fn get_product_registrations_for_lot(
+        &self,
+        lot: &str,
+    ) -> Option<Vec<ProductRegistrationRecord>> {
+        self.repo
+            .get_product_registrations_for_lot(lot)
+            .map(|records| {
+                records
+                    .into_iter()
+                    .map(|registration| registration.into())
+                    .collect()
+            })
+    }
+
     pub fn create_product_registration(
         &self,
         profile_id: u64,
         product_sku: &str,
+        purchase_date: Option<chrono::DateTime<chrono::Utc>>,
+        purchase_details: PurchaseDetails,
+        serial_code: Option<&str>,
     ) -> Result<ProductRegistrationRecord, ProfileServiceError> {
         let Some(_) = self.repo.get_profile(profile_id) else {
-            return Err(ProfileServiceError::BadRequest(format!(
-                "profile_id:{} does not exist",
-                profile_id
+            return Err(ProfileServiceError::BadRequest(String::from(
+                "profile does not exist",
             )));
         };
 
@@ -160,15 +874,327 @@ impl<Repo: ProfileRepository> ProfileService<Repo> {
             )));
         }
 
-        let res = self
-            .repo
-            .insert_product_registration(profile_id, product_sku);
+        if let Some(purchase_date) = purchase_date {
+            let now = self.repo.current_time();
+            if purchase_date > now {
+                return Err(ProfileServiceError::BadRequest(format!(
+                    "purchase_date:{} is in the future",
+                    purchase_date.to_rfc3339()
+                )));
+            }
+
+            let max_backdate =
+                chrono::Duration::days(self.config.max_purchase_backdate_days.into());
+            if now - purchase_date > max_backdate {
+                return Err(ProfileServiceError::BadRequest(format!(
+                    "purchase_date:{} is more than {} days ago",
+                    purchase_date.to_rfc3339(),
+                    self.config.max_purchase_backdate_days
+                )));
+            }
+        }
+
+        if let Err(msg) = is_purchase_details_valid(&purchase_details) {
+            return Err(ProfileServiceError::BadRequest(String::from(msg)));
+        }
+
+        if let Some(serial_code) = serial_code {
+            self.validate_serial_code(product_sku, serial_code)?;
+        }
+
+        let res = self.repo.insert_product_registration(
+            profile_id,
+            product_sku,
+            purchase_date,
+            purchase_details.into(),
+            serial_code.map(String::from),
+        );
         match res {
             Ok(reg) => Ok(reg.into()),
+            Err(RepositoryError::SerialCodeTaken(holder)) if holder == profile_id => {
+                Err(ProfileServiceError::Conflict(format!(
+                    "serial_code:{} is already registered to this profile",
+                    serial_code.unwrap_or_default()
+                )))
+            }
+            Err(RepositoryError::SerialCodeTaken(_)) => {
+                tracing::warn!(
+                    "Unable to register serial_code:{:?} to profile_id:{}, it is registered to another profile",
+                    serial_code,
+                    profile_id
+                );
+
+                Err(ProfileServiceError::SerialCodeTaken(format!(
+                    "serial_code:{} is registered to another profile",
+                    serial_code.unwrap_or_default()
+                )))
+            }
+            Err(RepositoryError::SerialCodesExhausted(product)) => {
+                Err(ProfileServiceError::InternalServiceError(format!(
+                    "Unable to create registration as no serial code could be generated for product:{}",
+                    product
+                )))
+            }
+            Err(RepositoryError::Storage(msg)) => Err(ProfileServiceError::InternalServiceError(
+                format!("Unable to create registration as it could not be stored: {}", msg),
+            )),
+            Err(RepositoryError::Conflict(products)) => {
+                tracing::warn!(
+                    "Unable to register product:{} to profile_id:{}, products {:?} are already registered",
+                    product_sku,
+                    profile_id,
+                    products
+                );
+
+                Err(ProfileServiceError::Conflict(format!(
+                    "Unable to create registration as this will create a duplicate registration:{:?}",
+                    products
+                )))
+            }
             Err(err) => Err(ProfileServiceError::InternalServiceError(format!(
-                "Unable to create registration as this will create a duplicate registration:{:?}",
+                "Unable to create registration:{:?}",
                 err
             ))),
         }
     }
+
+    // A supplied serial code has to match the product's serial format and template, whichever are set
+    fn validate_serial_code(
+        &self,
+        product_sku: &str,
+        serial_code: &str,
+    ) -> Result<(), ProfileServiceError> {
+        let serial_format = self.repo.get_product_serial_format(product_sku);
+        let serial_template = self.repo.get_product_serial_template(product_sku);
+        if serial_format.is_none() && serial_template.is_none() {
+            return Err(ProfileServiceError::BadRequest(format!(
+                "product:{} does not have serial codes to register with",
+                product_sku
+            )));
+        }
+
+        let invalid = || {
+            ProfileServiceError::BadRequest(format!(
+                "serial_code:{} is not a valid serial code for product:{}",
+                serial_code, product_sku
+            ))
+        };
+
+        if let Some(serial_format) = serial_format {
+            let regex = serial_format_regex(&serial_format).map_err(|err| {
+                ProfileServiceError::InternalServiceError(format!(
+                    "serial_format of product:{} is invalid: {}",
+                    product_sku, err
+                ))
+            })?;
+            if !regex.is_match(serial_code) {
+                return Err(invalid());
+            }
+        }
+
+        if let Some(serial_template) = serial_template {
+            if !is_serial_code_valid(&serial_template, serial_code) {
+                return Err(invalid());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn renew_product_registration(
+        &self,
+        product_registration_id: u64,
+        period: Option<u64>,
+    ) -> Result<ProductRegistrationRecord, ProfileServiceError> {
+        if period == Some(0) {
+            return Err(ProfileServiceError::BadRequest(String::from(
+                "Renewal period must be greater than 0",
+            )));
+        }
+        if period.is_some_and(|period| period > MAX_RENEWAL_PERIOD) {
+            return Err(ProfileServiceError::BadRequest(format!(
+                "Renewal period can be at most {} seconds",
+                MAX_RENEWAL_PERIOD
+            )));
+        }
+
+        let res = self
+            .repo
+            .renew_product_registration(product_registration_id, period);
+        res.map(|reg| reg.into())
+            .map_err(|err| registration_error(product_registration_id, "renew", err))
+    }
+
+    pub fn revoke_product_registration(
+        &self,
+        product_registration_id: u64,
+        reason: &str,
+    ) -> Result<ProductRegistrationRecord, ProfileServiceError> {
+        let reason = reason.trim();
+        if reason.is_empty() {
+            return Err(ProfileServiceError::BadRequest(String::from(
+                "A revocation reason is required",
+            )));
+        }
+
+        self.update_product_registration_status(
+            product_registration_id,
+            RegistrationStatus::Revoked,
+            Some(reason),
+        )
+    }
+
+    pub fn suspend_product_registration(
+        &self,
+        product_registration_id: u64,
+        reason: &str,
+    ) -> Result<ProductRegistrationRecord, ProfileServiceError> {
+        let reason = reason.trim();
+        if reason.is_empty() {
+            return Err(ProfileServiceError::BadRequest(String::from(
+                "A suspension reason is required",
+            )));
+        }
+
+        self.update_product_registration_status(
+            product_registration_id,
+            RegistrationStatus::Suspended,
+            Some(reason),
+        )
+    }
+
+    pub fn resume_product_registration(
+        &self,
+        product_registration_id: u64,
+    ) -> Result<ProductRegistrationRecord, ProfileServiceError> {
+        self.update_product_registration_status(
+            product_registration_id,
+            RegistrationStatus::Active,
+            None,
+        )
+    }
+
+    fn update_product_registration_status(
+        &self,
+        product_registration_id: u64,
+        status: RegistrationStatus,
+        reason: Option<&str>,
+    ) -> Result<ProductRegistrationRecord, ProfileServiceError> {
+        let res = self.repo.update_product_registration_status(
+            product_registration_id,
+            status.into(),
+            reason,
+        );
+        match res {
+            Ok(reg) => {
+                tracing::info!(
+                    "Moved product_registration:{} to {:?}, reason: {:?}",
+                    product_registration_id,
+                    status,
+                    reason
+                );
+
+                Ok(reg.into())
+            }
+            Err(err) => Err(registration_error(product_registration_id, "update", err)),
+        }
+    }
+
+    pub fn transfer_product_registration(
+        &self,
+        product_registration_id: u64,
+        target_profile_id: u64,
+    ) -> Result<ProductRegistrationRecord, ProfileServiceError> {
+        let Some(_) = self.repo.get_profile(target_profile_id) else {
+            return Err(ProfileServiceError::BadRequest(String::from(
+                "target profile does not exist",
+            )));
+        };
+
+        let res = self
+            .repo
+            .transfer_product_registration(product_registration_id, target_profile_id);
+        match res {
+            Ok(reg) => {
+                tracing::info!(
+                    "Transferred product_registration:{} to profile:{} as product_registration:{}",
+                    product_registration_id,
+                    target_profile_id,
+                    reg.registration.id
+                );
+
+                Ok(reg.into())
+            }
+            Err(err) => Err(registration_error(product_registration_id, "transfer", err)),
+        }
+    }
+
+    pub fn upgrade_product_registration(
+        &self,
+        product_registration_id: u64,
+        product_sku: &str,
+        prorate: bool,
+    ) -> Result<ProductRegistrationRecord, ProfileServiceError> {
+        if !self.repo.product_exists(product_sku) {
+            return Err(ProfileServiceError::BadRequest(format!(
+                "product:{} does not exist",
+                product_sku
+            )));
+        }
+
+        let res =
+            self.repo
+                .upgrade_product_registration(product_registration_id, product_sku, prorate);
+        match res {
+            Ok(reg) => {
+                tracing::info!(
+                    "Upgraded product_registration:{} to product:{} as product_registration:{}",
+                    product_registration_id,
+                    product_sku,
+                    reg.registration.id
+                );
+
+                Ok(reg.into())
+            }
+            Err(err) => Err(registration_error(product_registration_id, "upgrade", err)),
+        }
+    }
+
+    ///
+    /// Every owner of a registration, oldest first, following transfers in both directions
+    ///
+    pub fn get_product_registration_ownership(
+        &self,
+        product_registration_id: u64,
+    ) -> Option<Vec<ProductRegistrationOwner>> {
+        let registration = self
+            .repo
+            .get_product_registration(product_registration_id)?
+            .registration;
+
+        let mut chain = VecDeque::from([registration]);
+        while let Some(previous) = chain.front().and_then(|r| r.transferred_from) {
+            let Some(record) = self.repo.get_product_registration(previous) else {
+                break;
+            };
+            chain.push_front(record.registration);
+        }
+        while let Some(next) = chain.back().and_then(|r| r.transferred_to) {
+            let Some(record) = self.repo.get_product_registration(next) else {
+                break;
+            };
+            chain.push_back(record.registration);
+        }
+
+        Some(chain.into_iter().map(|r| r.into()).collect())
+    }
+
+    pub fn get_product_registration_renewals(
+        &self,
+        product_registration_id: u64,
+    ) -> Option<Vec<ProductRegistrationRenewal>> {
+        self.repo
+            .get_product_registration_renewals(product_registration_id)
+            .map(|renewals| renewals.into_iter().map(|r| r.into()).collect())
+    }
 }
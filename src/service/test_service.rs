@@ -1,8 +1,11 @@
-use std::sync::{Once, OnceLock};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Once, OnceLock,
+};
 
-use crate::repository::inram::InMemoryProfileRepository;
+use crate::repository::{inram::InMemoryProfileRepository, ProfileRepository};
 
-use super::{model::*, ProfileService, ProfileServiceConfig};
+use super::{model::*, ProfileService, ProfileServiceConfig, ProfileServiceError};
 
 fn registration1() -> &'static ProductRegistrationRecord {
     static REG1: OnceLock<ProductRegistrationRecord> = OnceLock::new();
@@ -12,6 +15,7 @@ fn registration1() -> &'static ProductRegistrationRecord {
             purchase_date: chrono::DateTime::parse_from_rfc3339("2023-01-15T15:04:05Z")
                 .unwrap()
                 .into(),
+            purchase_details: PurchaseDetails::default(),
             expiry_at: Some(
                 chrono::DateTime::parse_from_rfc3339("2024-01-15T15:04:05Z")
                     .unwrap()
@@ -19,6 +23,19 @@ fn registration1() -> &'static ProductRegistrationRecord {
             ),
             product: "ARIE4".into(),
             serial_code: "A1B2C3D4".into(),
+            lot: None,
+            status: RegistrationStatus::Active,
+            status_history: Vec::from([RegistrationStatusChange {
+                status: RegistrationStatus::Active,
+                changed_at: chrono::DateTime::parse_from_rfc3339("2023-01-15T15:04:05Z")
+                    .unwrap()
+                    .into(),
+                reason: None,
+            }]),
+            transferred_from: None,
+            transferred_to: None,
+            upgraded_from: None,
+            upgraded_to: None,
         },
         children: Vec::new(),
     })
@@ -26,6 +43,12 @@ fn registration1() -> &'static ProductRegistrationRecord {
 
 static INIT: Once = Once::new();
 
+// Serial codes cannot repeat, so the generator is shared by all tests
+fn sequential_serial_generator() -> String {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    format!("{:015}", NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
 fn setup() -> ProfileService<InMemoryProfileRepository> {
     INIT.call_once(|| {
         tracing_subscriber::fmt()
@@ -34,13 +57,28 @@ fn setup() -> ProfileService<InMemoryProfileRepository> {
             .init()
     });
     ProfileService::new(
-        InMemoryProfileRepository::with_example_data(String::new, || {
+        InMemoryProfileRepository::with_example_data(sequential_serial_generator, || {
             chrono::DateTime::<chrono::Utc>::MIN_UTC
         }),
         ProfileServiceConfig::default(),
     )
 }
 
+fn setup_at(
+    now: fn() -> chrono::DateTime<chrono::Utc>,
+) -> ProfileService<InMemoryProfileRepository> {
+    ProfileService::new(
+        InMemoryProfileRepository::with_example_data(sequential_serial_generator, now),
+        ProfileServiceConfig::default(),
+    )
+}
+
+fn new_year_2025() -> chrono::DateTime<chrono::Utc> {
+    chrono::DateTime::parse_from_rfc3339("2025-01-01T00:00:00Z")
+        .unwrap()
+        .into()
+}
+
 #[test]
 fn test_product_insert_empty_product_name() {
     let service = setup();
@@ -54,7 +92,7 @@ fn test_product_insert_empty_product_name() {
 fn test_get_product_registration_for_profile() {
     let service = setup();
 
-    let res = service.get_product_registrations_for_profile(1, 0);
+    let res = service.get_product_registrations_for_profile(1, None, None, 0);
 
     assert!(res.is_some());
     let registrations = res.unwrap();
@@ -67,9 +105,23 @@ fn test_get_product_registration_for_profile() {
                     purchase_date: chrono::DateTime::parse_from_rfc3339("2023-03-10T12:00:00Z")
                         .unwrap()
                         .into(),
+                    purchase_details: PurchaseDetails::default(),
                     expiry_at: None,
                     product: "ARCC4".into(),
                     serial_code: "L3M4N5O6".into(),
+                    lot: None,
+                    status: RegistrationStatus::Active,
+                    status_history: Vec::from([RegistrationStatusChange {
+                        status: RegistrationStatus::Active,
+                        changed_at: chrono::DateTime::parse_from_rfc3339("2023-03-10T12:00:00Z")
+                            .unwrap()
+                            .into(),
+                        reason: None,
+                    }]),
+                    transferred_from: None,
+                    transferred_to: None,
+                    upgraded_from: None,
+                    upgraded_to: None,
                 },
                 children: Vec::new()
             }
@@ -82,11 +134,21 @@ fn test_get_product_registration_for_profile() {
 fn test_get_product_registration_for_profile_nonexistent_profile() {
     let service = setup();
 
-    let res = service.get_product_registrations_for_profile(1337, 0);
+    let res = service.get_product_registrations_for_profile(1337, None, None, 0);
 
     assert!(res.is_none());
 }
 
+#[test]
+fn test_get_product_registration_for_profile_zero_id() {
+    let service = setup();
+
+    assert!(service
+        .get_product_registrations_for_profile(0, None, None, 0)
+        .is_none());
+    assert_eq!(None, service.get_product_registration(0));
+}
+
 #[test]
 fn get_product_registration_success() {
     let service = setup();
@@ -105,13 +167,227 @@ fn get_product_registration_notfound() {
     assert_eq!(None, res);
 }
 
+#[test]
+fn renew_product_registration_zero_period() {
+    let service = setup();
+
+    let res = service.renew_product_registration(1, Some(0));
+    assert!(matches!(res, Err(ProfileServiceError::BadRequest(_))));
+}
+
+#[test]
+fn renew_product_registration_period_too_long() {
+    let service = setup();
+
+    let res = service.renew_product_registration(1, Some(u64::MAX));
+    assert!(matches!(res, Err(ProfileServiceError::BadRequest(_))));
+}
+
+#[test]
+fn renew_product_registration_notfound() {
+    let service = setup();
+
+    let res = service.renew_product_registration(1337, Some(3600));
+    assert!(matches!(res, Err(ProfileServiceError::NotFound(_))));
+    assert_eq!(None, service.get_product_registration_renewals(1337));
+}
+
+#[test]
+fn revoke_product_registration_empty_reason() {
+    let service = setup();
+
+    let res = service.revoke_product_registration(1, "  ");
+    assert!(matches!(res, Err(ProfileServiceError::BadRequest(_))));
+}
+
+#[test]
+fn revoke_product_registration_success() {
+    let service = setup();
+
+    let res = service.revoke_product_registration(1, "refund").unwrap();
+    assert_eq!(RegistrationStatus::Revoked, res.registration.status);
+    assert_eq!(
+        Some(res),
+        service.get_product_registration(registration1().registration.id)
+    );
+}
+
+#[test]
+fn suspend_and_resume_product_registration() {
+    let service = setup();
+
+    let suspended = service
+        .suspend_product_registration(1, "fraud hold")
+        .unwrap();
+    assert_eq!(RegistrationStatus::Suspended, suspended.registration.status);
+    assert_eq!(
+        Some(Vec::from([suspended.clone()])),
+        service.get_product_registrations_for_profile(
+            1,
+            Some(RegistrationStatus::Suspended),
+            None,
+            0
+        )
+    );
+
+    let resumed = service.resume_product_registration(1).unwrap();
+    assert_eq!(RegistrationStatus::Active, resumed.registration.status);
+    assert_eq!(3, resumed.registration.status_history.len());
+}
+
+#[test]
+fn resume_active_product_registration() {
+    let service = setup();
+
+    let res = service.resume_product_registration(1);
+    assert!(matches!(res, Err(ProfileServiceError::BadRequest(_))));
+}
+
+#[test]
+fn transfer_product_registration_nonexistent_profile() {
+    let service = setup();
+
+    let res = service.transfer_product_registration(1, 1337);
+    assert!(matches!(res, Err(ProfileServiceError::BadRequest(_))));
+}
+
+#[test]
+fn transfer_product_registration_ownership_chain() {
+    let service = setup();
+
+    let transferred = service.transfer_product_registration(1, 2).unwrap();
+    let returned = service
+        .transfer_product_registration(transferred.registration.id, 1)
+        .unwrap();
+
+    for id in [1, transferred.registration.id, returned.registration.id] {
+        let owners = service.get_product_registration_ownership(id).unwrap();
+        assert_eq!(
+            vec![
+                (1, 1, true),
+                (transferred.registration.id, 2, true),
+                (returned.registration.id, 1, false)
+            ],
+            owners
+                .iter()
+                .map(|owner| (
+                    owner.registration_id,
+                    owner.profile_id,
+                    owner.owned_until.is_some()
+                ))
+                .collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test]
+fn create_product_upgrade_nonexistent_product() {
+    let service = setup();
+
+    let res = service.create_product_upgrade("ARCC4", "FOO");
+    assert!(matches!(res, Err(ProfileServiceError::BadRequest(_))));
+    let res = service.create_product_upgrade("ARCC4", "ARCC4");
+    assert!(matches!(res, Err(ProfileServiceError::BadRequest(_))));
+}
+
+#[test]
+fn upgrade_product_registration_undeclared_path() {
+    let service = setup();
+
+    let res = service.upgrade_product_registration(2, "ARIE4", false);
+    assert!(matches!(res, Err(ProfileServiceError::BadRequest(_))));
+
+    service.create_product_upgrade("ARCC4", "ARIE4").unwrap();
+    let res = service.upgrade_product_registration(2, "ARIE4", false);
+    assert!(res.is_ok());
+}
+
+#[test]
+fn get_entitlement_for_registered_product() {
+    let service = setup();
+
+    let res = service.get_entitlement(1, "ARIE4", None).unwrap();
+    assert!(res.entitled);
+    assert_eq!(registration1().registration.expiry_at, res.expiry_at);
+    assert_eq!(Vec::from([registration1().clone()]), res.granted_by);
+
+    let res = service.get_entitlement(1, "AKB48", None).unwrap();
+    assert!(!res.entitled);
+    assert!(res.granted_by.is_empty());
+}
+
+#[test]
+fn get_entitlement_for_bundle_covered_by_leaf_products() {
+    let service = setup();
+    for leaf in ["ARAS1", "ARCS1"] {
+        service
+            .create_product_registration(2, leaf, None, PurchaseDetails::default(), None)
+            .unwrap();
+    }
+
+    let res = service.get_entitlement(2, "ARCC4", None).unwrap();
+    assert!(!res.entitled);
+
+    service
+        .create_product_registration(2, "ARCH1", None, PurchaseDetails::default(), None)
+        .unwrap();
+    let res = service.get_entitlement(2, "ARCC4", None).unwrap();
+    assert!(res.entitled);
+    assert_eq!(
+        vec![3, 4, 6, 8],
+        res.granted_by
+            .iter()
+            .map(|record| record.registration.id)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        chrono::DateTime::parse_from_rfc3339("2023-12-25T08:30:00Z")
+            .ok()
+            .map(|expiry_at| expiry_at.into()),
+        res.expiry_at
+    );
+
+    let res = service.get_entitlement(2, "NOTAPRODUCT", None).unwrap();
+    assert!(!res.entitled);
+}
+
+#[test]
+fn get_entitlements_nonexistent_profile() {
+    let service = setup();
+
+    assert_eq!(None, service.get_entitlements(1337, None));
+    assert_eq!(None, service.get_entitlement(1337, "ARIE4", None));
+}
+
+#[test]
+fn get_entitlement_as_of() {
+    let service = setup();
+
+    let as_of = chrono::DateTime::parse_from_rfc3339("2023-06-01T00:00:00Z")
+        .unwrap()
+        .into();
+    let res = service.get_entitlement(1, "ARIE4", Some(as_of)).unwrap();
+    assert!(res.entitled);
+    assert_eq!(Vec::from([registration1().clone()]), res.granted_by);
+
+    let as_of = chrono::DateTime::parse_from_rfc3339("2022-06-01T00:00:00Z")
+        .unwrap()
+        .into();
+    let res = service.get_entitlement(1, "ARIE4", Some(as_of)).unwrap();
+    assert!(!res.entitled);
+    assert_eq!(
+        Some(Vec::new()),
+        service.get_product_registrations_for_profile(1, None, Some(as_of), 0)
+    );
+}
+
 /*
 Commented out as due to hashing function randomness, we so far cannot guarantee this always passes
 #[test]
 fn create_product_registration_success() {
     let service = setup();
 
-    let res = service.create_product_registration(1, "AKB48");
+    let res = service.create_product_registration(1, "AKB48", None, PurchaseDetails::default(), None);
     assert!(res.is_ok());
     let record = res.unwrap();
     assert_eq!(
@@ -144,3 +420,661 @@ fn create_product_registration_success() {
     );
 }
 */
+
+#[test]
+fn create_product_registration_of_held_product_conflicts() {
+    let service = setup();
+
+    service
+        .create_product_registration(2, "SKE48", None, PurchaseDetails::default(), None)
+        .unwrap();
+    let res =
+        service.create_product_registration(2, "SKE48", None, PurchaseDetails::default(), None);
+    assert!(matches!(res, Err(ProfileServiceError::Conflict(_))));
+}
+
+#[test]
+fn create_product_registration_with_backdated_purchase() {
+    let service = setup_at(new_year_2025);
+    service
+        .create_product("WKMN1", Some(30 * 24 * 3600), &[])
+        .unwrap();
+    let purchase_date = chrono::DateTime::parse_from_rfc3339("2024-12-01T00:00:00Z")
+        .unwrap()
+        .into();
+    let purchase_details = PurchaseDetails {
+        retailer: Some("Tower Records".into()),
+        channel: Some("store".into()),
+        order_reference: None,
+        price: Some(1999),
+        currency: Some("USD".into()),
+    };
+
+    let res = service
+        .create_product_registration(
+            2,
+            "WKMN1",
+            Some(purchase_date),
+            purchase_details.clone(),
+            None,
+        )
+        .unwrap();
+
+    assert_eq!(purchase_date, res.registration.purchase_date);
+    assert_eq!(purchase_details, res.registration.purchase_details);
+    // active for 30 days from the purchase, not from registering it
+    assert_eq!(
+        Some(purchase_date + chrono::Duration::days(30)),
+        res.registration.expiry_at
+    );
+}
+
+#[test]
+fn create_product_registration_with_invalid_purchase_fails() {
+    let service = setup_at(new_year_2025);
+    let date = |timestamp| {
+        chrono::DateTime::parse_from_rfc3339(timestamp)
+            .ok()
+            .map(|timestamp| timestamp.into())
+    };
+
+    let future = service.create_product_registration(
+        2,
+        "ARCM1",
+        date("2025-01-02T00:00:00Z"),
+        PurchaseDetails::default(),
+        None,
+    );
+    let too_old = service.create_product_registration(
+        2,
+        "ARCM1",
+        date("2023-12-31T00:00:00Z"),
+        PurchaseDetails::default(),
+        None,
+    );
+    let missing_currency = service.create_product_registration(
+        2,
+        "ARCM1",
+        None,
+        PurchaseDetails {
+            price: Some(1999),
+            ..Default::default()
+        },
+        None,
+    );
+
+    assert!(matches!(future, Err(ProfileServiceError::BadRequest(_))));
+    assert!(matches!(too_old, Err(ProfileServiceError::BadRequest(_))));
+    assert!(matches!(
+        missing_currency,
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+}
+
+#[test]
+fn create_product_registration_with_supplied_serial_code() {
+    let service = setup_at(new_year_2025);
+    service.create_product("WKMN1", None, &[]).unwrap();
+    let register = |profile_id, serial_code| {
+        service.create_product_registration(
+            profile_id,
+            "WKMN1",
+            None,
+            PurchaseDetails::default(),
+            Some(serial_code),
+        )
+    };
+
+    assert!(matches!(
+        register(1, "Q1W2E3R4"),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+    assert_eq!(
+        Err(ProfileServiceError::NotFound(
+            "product:WKMN2 does not exist".into()
+        )),
+        service.set_product_serial_format("WKMN2", "[A-Z0-9]{8}")
+    );
+    service
+        .set_product_serial_format("WKMN1", "([A-Z][0-9]){4}")
+        .unwrap();
+
+    assert!(matches!(
+        register(1, "Q1W2E3R4X"),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+    // Z5X6C7V8 is printed on profile 2's ARCM1
+    assert!(matches!(
+        register(1, "Z5X6C7V8"),
+        Err(ProfileServiceError::SerialCodeTaken(_))
+    ));
+    let res = register(1, "Q1W2E3R4").unwrap();
+    assert_eq!("Q1W2E3R4", res.registration.serial_code);
+    assert!(matches!(
+        register(2, "Q1W2E3R4"),
+        Err(ProfileServiceError::SerialCodeTaken(_))
+    ));
+}
+
+#[test]
+fn create_product_registration_with_serial_code_from_template() {
+    let service = setup_at(new_year_2025);
+    service.create_product("WKMN1", None, &[]).unwrap();
+    let serial_template = SerialTemplate {
+        prefix: String::new(),
+        groups: Vec::from([3]),
+        separator: String::new(),
+        alphabet: "0123456789".into(),
+        check_digit: true,
+    };
+
+    assert!(matches!(
+        service.set_product_serial_template(
+            "WKMN1",
+            SerialTemplate {
+                separator: "1".into(),
+                ..serial_template.clone()
+            }
+        ),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+    // would wrap around to 64 characters if summed unchecked
+    assert!(matches!(
+        service.set_product_serial_template(
+            "WKMN1",
+            SerialTemplate {
+                groups: Vec::from([usize::MAX, 65]),
+                ..serial_template.clone()
+            }
+        ),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+    service
+        .set_product_serial_template("WKMN1", serial_template.clone())
+        .unwrap();
+    assert_eq!(
+        Some(serial_template),
+        service.get_product_serial_template("WKMN1")
+    );
+
+    let register = |serial_code| {
+        service.create_product_registration(
+            1,
+            "WKMN1",
+            None,
+            PurchaseDetails::default(),
+            Some(serial_code),
+        )
+    };
+    // 12 has the Luhn check digit 5
+    assert!(matches!(
+        register("124"),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+    assert_eq!("125", register("125").unwrap().registration.serial_code);
+}
+
+#[test]
+fn import_serial_pool_and_find_registrations_by_lot() {
+    let service = setup_at(new_year_2025);
+    service.create_product("WKMN1", None, &[]).unwrap();
+    let pooled = |serial_code: &str, lot: &str| PooledSerialCode {
+        serial_code: serial_code.into(),
+        lot: lot.into(),
+    };
+
+    assert!(matches!(
+        service.import_serial_pool("WKMN2", Vec::from([pooled("Q1W2E3R4", "LOT-1")])),
+        Err(ProfileServiceError::NotFound(_))
+    ));
+    assert!(matches!(
+        service.import_serial_pool("WKMN1", Vec::from([pooled("Q1W2E3R4", "")])),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+    service
+        .set_product_serial_format("WKMN1", "([A-Z][0-9]){4}")
+        .unwrap();
+    assert!(matches!(
+        service.import_serial_pool("WKMN1", Vec::from([pooled("Q1W2E3R4X", "LOT-1")])),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+
+    let import = service
+        .import_serial_pool(
+            "WKMN1",
+            Vec::from([
+                pooled("Q1W2E3R4", "LOT-1"),
+                pooled("T5Y6U7I8", "LOT-1"),
+                pooled("A1B2C3D4", "LOT-1"),
+                pooled("O9P8L7K6", "LOT-2"),
+            ]),
+        )
+        .unwrap();
+    assert_eq!(3, import.imported);
+    assert_eq!(
+        std::collections::BTreeSet::from(["A1B2C3D4".into()]),
+        import.skipped
+    );
+
+    let res = service
+        .create_product_registration(1, "WKMN1", None, PurchaseDetails::default(), None)
+        .unwrap();
+    assert_eq!("Q1W2E3R4", res.registration.serial_code);
+    assert_eq!(Some("LOT-1".into()), res.registration.lot);
+    assert_eq!(
+        Some(Vec::from([
+            (String::from("LOT-1"), 2, 0),
+            ("LOT-2".into(), 1, 1)
+        ])),
+        service.get_serial_pool("WKMN1").map(|lots| lots
+            .into_iter()
+            .map(|lot| (lot.lot, lot.imported, lot.remaining))
+            .collect::<Vec<_>>())
+    );
+    assert_eq!(
+        Some(Vec::from([res.registration.id])),
+        service
+            .get_product_registrations_for_lot("LOT-1")
+            .map(|records| records
+                .iter()
+                .map(|record| record.registration.id)
+                .collect::<Vec<_>>())
+    );
+    assert!(service.get_product_registrations_for_lot("LOT-3").is_none());
+}
+
+fn setup_with_license_keys(
+    license_signing_key: crate::license::LicenseSigningKey,
+    license_verifying_keys: Vec<crate::license::LicenseVerifyingKey>,
+) -> ProfileService<InMemoryProfileRepository> {
+    ProfileService::new(
+        InMemoryProfileRepository::with_example_data(sequential_serial_generator, new_year_2025),
+        ProfileServiceConfig {
+            license_signing_key: Some(license_signing_key),
+            license_verifying_keys,
+            ..Default::default()
+        },
+    )
+}
+
+fn public_profile_id(profile_id: u64) -> String {
+    format!("prof_{}", profile_id)
+}
+
+#[test]
+fn create_license_key_and_verify_after_rotation() {
+    use crate::license::LicenseSigningKey;
+
+    assert!(matches!(
+        setup_at(new_year_2025).create_license_key(2, public_profile_id),
+        Err(ProfileServiceError::NotFound(_))
+    ));
+
+    let signing_key_2024 = LicenseSigningKey::new("2024", [24; 32]);
+    let service = setup_with_license_keys(signing_key_2024.clone(), Vec::new());
+    assert!(matches!(
+        service.create_license_key(10, public_profile_id),
+        Err(ProfileServiceError::NotFound(_))
+    ));
+    // ARIE4 expired a year ago
+    assert!(matches!(
+        service.create_license_key(1, public_profile_id),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+
+    let license_key = service.create_license_key(2, public_profile_id).unwrap();
+    assert_eq!("2024", license_key.key_id);
+    assert_eq!(
+        crate::license::LicenseClaims {
+            profile_id: "prof_1".into(),
+            sku: "ARCC4".into(),
+            serial_code: "L3M4N5O6".into(),
+            expiry_at: None,
+            issued_at: new_year_2025(),
+        },
+        license_key.claims
+    );
+    assert_eq!(
+        Ok(license_key.claims.clone()),
+        service.verify_license_key(&license_key.license_key)
+    );
+    let tampered = license_key.license_key.replacen("2024.", "2024.e", 1);
+    assert!(matches!(
+        service.verify_license_key(&tampered),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+
+    // keys signed before the rotation verify as long as the old key is kept for verifying
+    let signing_key_2025 = LicenseSigningKey::new("2025", [25; 32]);
+    let rotated = setup_with_license_keys(
+        signing_key_2025.clone(),
+        Vec::from([signing_key_2024.verifying_key()]),
+    );
+    assert_eq!(
+        Vec::from(["2025", "2024"]),
+        rotated
+            .get_license_verifying_keys()
+            .iter()
+            .map(|key| key.key_id.as_str())
+            .collect::<Vec<_>>()
+    );
+    assert!(rotated.verify_license_key(&license_key.license_key).is_ok());
+    assert_eq!(
+        "2025",
+        rotated
+            .create_license_key(2, public_profile_id)
+            .unwrap()
+            .key_id
+    );
+    assert!(matches!(
+        setup_with_license_keys(signing_key_2025, Vec::new())
+            .verify_license_key(&license_key.license_key),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+}
+
+#[test]
+fn verify_expired_license_key() {
+    use crate::license::{verify_license_key, LicenseClaims, LicenseError, LicenseSigningKey};
+
+    let signing_key = LicenseSigningKey::new("2024", [24; 32]);
+    let claims = LicenseClaims {
+        profile_id: "prof_2".into(),
+        sku: "ARCM1".into(),
+        serial_code: "Z5X6C7V8".into(),
+        expiry_at: Some(
+            chrono::DateTime::parse_from_rfc3339("2023-12-25T00:00:00Z")
+                .unwrap()
+                .into(),
+        ),
+        issued_at: chrono::DateTime::parse_from_rfc3339("2023-06-01T00:00:00Z")
+            .unwrap()
+            .into(),
+    };
+    let license_key = signing_key.sign(&claims);
+    let verifying_keys = [signing_key.verifying_key()];
+
+    assert_eq!(
+        Ok(claims.clone()),
+        verify_license_key(&license_key, &verifying_keys, claims.issued_at)
+    );
+    assert_eq!(
+        Err(LicenseError::Expired),
+        verify_license_key(&license_key, &verifying_keys, new_year_2025())
+    );
+    assert_eq!(
+        Err(LicenseError::Malformed),
+        verify_license_key("2024.notalicensekey", &verifying_keys, new_year_2025())
+    );
+}
+
+#[test]
+fn get_registration_certificate() {
+    let service = setup_at(new_year_2025);
+    assert!(service.get_registration_certificate(10).is_none());
+
+    let certificate = service.get_registration_certificate(3).unwrap();
+    assert_eq!(
+        RegistrationCertificate {
+            registration_id: 3,
+            profile_name: "Jane Smith".into(),
+            product: "ARCM1".into(),
+            serial_code: "Z5X6C7V8".into(),
+            purchase_date: chrono::DateTime::parse_from_rfc3339("2022-12-25T08:30:00Z")
+                .unwrap()
+                .into(),
+            expiry_at: Some(
+                chrono::DateTime::parse_from_rfc3339("2023-12-25T08:30:00Z")
+                    .unwrap()
+                    .into()
+            ),
+            status: RegistrationStatus::Expired,
+            verification_url: "http://localhost:3000/api/v1/verify/Z5X6C7V8".into(),
+            issued_at: new_year_2025(),
+        },
+        certificate
+    );
+
+    // supplied serial codes can contain anything their format allows
+    service.create_product("WKMN1", None, &[]).unwrap();
+    service
+        .set_product_serial_format("WKMN1", "[A-Z]{2}[ /#][0-9]{2}")
+        .unwrap();
+    let res = service
+        .create_product_registration(1, "WKMN1", None, PurchaseDetails::default(), Some("AB/12"))
+        .unwrap();
+    assert_eq!(
+        "http://localhost:3000/api/v1/verify/AB%2F12",
+        service
+            .get_registration_certificate(res.registration.id)
+            .unwrap()
+            .verification_url
+    );
+}
+
+#[test]
+fn verify_serial_code_without_profile() {
+    let service = setup_at(new_year_2025);
+    assert!(service.verify_serial_code("NOTASERIAL").is_none());
+
+    assert_eq!(
+        Some(SerialCodeVerification {
+            product: "ARCC4".into(),
+            status: VerifiedStatus::Active,
+            expiry_at: None,
+        }),
+        service.verify_serial_code("L3M4N5O6")
+    );
+    service
+        .revoke_product_registration(2, "chargeback")
+        .unwrap();
+    assert_eq!(
+        Some(VerifiedStatus::Inactive),
+        service
+            .verify_serial_code("L3M4N5O6")
+            .map(|verification| verification.status)
+    );
+    assert_eq!(
+        Some(VerifiedStatus::Expired),
+        service
+            .verify_serial_code("A1B2C3D4")
+            .map(|verification| verification.status)
+    );
+}
+
+#[test]
+fn verify_serial_code_of_child_registration() {
+    let repo =
+        InMemoryProfileRepository::with_example_data(sequential_serial_generator, new_year_2025);
+    repo.insert_product("VERSUB", &[], Some(60)).unwrap();
+    repo.insert_product("VERBUNDLE", &["VERSUB".into()], None)
+        .unwrap();
+    let service = ProfileService::new(repo, ProfileServiceConfig::default());
+
+    // the child expired a minute after purchase, its parent never expires
+    let record = service
+        .create_product_registration(
+            2,
+            "VERBUNDLE",
+            Some(new_year_2025() - chrono::Duration::minutes(2)),
+            PurchaseDetails::default(),
+            None,
+        )
+        .unwrap();
+    let child = record
+        .children
+        .iter()
+        .find(|child| child.product == "VERSUB")
+        .unwrap();
+
+    assert_eq!(
+        Some(SerialCodeVerification {
+            product: "VERSUB".into(),
+            status: VerifiedStatus::Expired,
+            expiry_at: child.expiry_at,
+        }),
+        service.verify_serial_code(&child.serial_code)
+    );
+    assert_eq!(
+        Some(SerialCodeVerification {
+            product: "VERBUNDLE".into(),
+            status: VerifiedStatus::Active,
+            expiry_at: None,
+        }),
+        service.verify_serial_code(&record.registration.serial_code)
+    );
+}
+
+#[test]
+fn create_and_revoke_api_key() {
+    let service = setup();
+
+    assert!(matches!(
+        service.create_api_key(" ", ApiKeyRole::Partner),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+    assert!(matches!(
+        service.import_api_key("short", ApiKeyRole::Admin, "too-short"),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+
+    let created = service
+        .create_api_key("retailer", ApiKeyRole::Partner)
+        .unwrap();
+    assert!(created.key.starts_with(&created.api_key.prefix));
+    assert_eq!(
+        Some(created.api_key.clone()),
+        service.authenticate_api_key(&created.key)
+    );
+    assert!(service
+        .authenticate_api_key(&created.key.to_lowercase())
+        .is_none());
+    assert!(matches!(
+        service.import_api_key("copy", ApiKeyRole::Admin, &created.key),
+        Err(ProfileServiceError::Conflict(_))
+    ));
+
+    service.revoke_api_key(created.api_key.id).unwrap();
+    assert!(service.authenticate_api_key(&created.key).is_none());
+    assert!(matches!(
+        service.revoke_api_key(created.api_key.id),
+        Err(ProfileServiceError::BadRequest(_))
+    ));
+    assert!(matches!(
+        service.revoke_api_key(10),
+        Err(ProfileServiceError::NotFound(_))
+    ));
+}
+
+#[test]
+fn backup_round_trip() {
+    let source = setup();
+    source.create_product("WKMN1", Some(3600), &[]).unwrap();
+    source
+        .create_product_registration(2, "AKB48", None, PurchaseDetails::default(), None)
+        .unwrap();
+    let partner = source
+        .create_api_key("retailer", ApiKeyRole::Partner)
+        .unwrap();
+    let backup = source.export_backup();
+
+    let target = ProfileService::new(
+        InMemoryProfileRepository::new(),
+        ProfileServiceConfig::default(),
+    );
+    let admin = target.create_api_key("admin", ApiKeyRole::Admin).unwrap();
+    assert_eq!(
+        Ok(BackupSummary {
+            profiles: 2,
+            products: 12,
+            product_registrations: 6,
+        }),
+        target.restore_backup(&backup)
+    );
+
+    // everything but the API keys is the same, the target's own API key is kept
+    let state = |service: &ProfileService<InMemoryProfileRepository>| {
+        let mut state = serde_json::to_value(service.repository().export_snapshot()).unwrap();
+        state.as_object_mut().unwrap().remove("api_keys");
+        state
+    };
+    assert_eq!(state(&source), state(&target));
+    for created in [partner, admin] {
+        assert_eq!(
+            Some(created.api_key),
+            target.authenticate_api_key(&created.key)
+        );
+    }
+}
+
+#[test]
+fn restore_backup_refuses_invalid_backups_and_existing_data() {
+    let source = setup();
+    let backup = source.export_backup();
+    let target = ProfileService::new(
+        InMemoryProfileRepository::new(),
+        ProfileServiceConfig::default(),
+    );
+
+    let mut damaged = backup.clone();
+    let middle = damaged.len() / 2;
+    damaged[middle] ^= 1;
+    let newer =
+        String::from_utf8(backup.clone())
+            .unwrap()
+            .replacen("\"version\":1", "\"version\":2", 1);
+    for invalid in [&damaged[..], newer.as_bytes(), b"{}", b""] {
+        assert!(matches!(
+            target.restore_backup(invalid),
+            Err(ProfileServiceError::BadRequest(_))
+        ));
+    }
+
+    assert!(matches!(
+        source.restore_backup(&backup),
+        Err(ProfileServiceError::Conflict(_))
+    ));
+    assert!(target.restore_backup(&backup).is_ok());
+    assert!(matches!(
+        target.restore_backup(&backup),
+        Err(ProfileServiceError::Conflict(_))
+    ));
+}
+
+#[test]
+fn check_integrity_reports_then_repairs() {
+    let source = setup();
+    let mut snapshot = source.repository().export_snapshot();
+    snapshot.products.remove("ARCM1");
+    snapshot.serial_codes.clear();
+    let target = ProfileService::new(
+        InMemoryProfileRepository::new(),
+        ProfileServiceConfig::default(),
+    );
+    target.repository().import_snapshot(snapshot).unwrap();
+
+    let report = target.check_integrity(false).unwrap();
+    assert!(!report.repaired);
+    assert!(report.violations.iter().any(|violation| violation.kind
+        == IntegrityViolationKind::MissingProduct
+        && violation.product.as_deref() == Some("ARCM1")));
+    assert!(report
+        .violations
+        .iter()
+        .any(|violation| violation.kind == IntegrityViolationKind::IndexMismatch));
+    assert_eq!(
+        report.violations,
+        target.check_integrity(false).unwrap().violations
+    );
+
+    let repaired = target.check_integrity(true).unwrap();
+    assert!(repaired.repaired);
+    assert_eq!(report.violations, repaired.violations);
+    assert_eq!(
+        IntegrityReport {
+            violations: Vec::new(),
+            repaired: false,
+        },
+        target.check_integrity(true).unwrap()
+    );
+}
@@ -0,0 +1,166 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+
+use crate::{
+    repository::inram::InMemoryProfileRepository,
+    service::{
+        model::{ApiKey, ApiKeyRole},
+        ProfileService,
+    },
+};
+
+use super::{
+    error::ProfileApiError,
+    jwt::JwtVerifier,
+    public_id::{IdKind, PublicIds},
+};
+
+pub(crate) const API_KEY_HEADER: &str = "x-api-key";
+
+///
+/// Who may call a group of routes in main.rs
+///
+#[derive(Clone, Copy)]
+pub(crate) struct Allowed {
+    roles: &'static [ApiKeyRole],
+    // customers are further limited to their own profile by the handlers
+    customers: bool,
+}
+
+const READ_ROLES: &[ApiKeyRole] = &[
+    ApiKeyRole::Admin,
+    ApiKeyRole::Support,
+    ApiKeyRole::Partner,
+    ApiKeyRole::ReadOnly,
+];
+const REGISTER_ROLES: &[ApiKeyRole] =
+    &[ApiKeyRole::Admin, ApiKeyRole::Support, ApiKeyRole::Partner];
+
+pub(crate) const ADMIN: Allowed = Allowed {
+    roles: &[ApiKeyRole::Admin],
+    customers: false,
+};
+pub(crate) const SUPPORT: Allowed = Allowed {
+    roles: &[ApiKeyRole::Admin, ApiKeyRole::Support],
+    customers: false,
+};
+pub(crate) const READ: Allowed = Allowed {
+    roles: READ_ROLES,
+    customers: false,
+};
+pub(crate) const CUSTOMER_REGISTER: Allowed = Allowed {
+    roles: REGISTER_ROLES,
+    customers: true,
+};
+pub(crate) const CUSTOMER_READ: Allowed = Allowed {
+    roles: READ_ROLES,
+    customers: true,
+};
+
+///
+/// Whoever made the request, added to the request's extensions by `authenticate`
+///
+#[derive(Clone)]
+pub(crate) enum Caller {
+    ApiKey(ApiKey),
+    // Bearer token issued to a customer, for their own profile only
+    Customer { profile_id: u64 },
+}
+
+impl Caller {
+    pub fn can_access_profile(&self, profile_id: u64) -> bool {
+        match self {
+            Caller::ApiKey(_) => true,
+            Caller::Customer {
+                profile_id: customer_profile_id,
+            } => *customer_profile_id == profile_id,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct AuthState {
+    pub service: Arc<ProfileService<InMemoryProfileRepository>>,
+    // None if customer tokens are not accepted
+    pub jwt_verifier: Option<Arc<JwtVerifier>>,
+    // customer tokens are issued for the public profile id
+    pub public_ids: Arc<PublicIds>,
+}
+
+fn bearer_token(request: &Request) -> Option<&str> {
+    request
+        .headers()
+        .get(http::header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+///
+/// Rejects requests without a valid API key in the `X-Api-Key` header, or a customer token as a bearer token
+///
+pub(crate) async fn authenticate(
+    State(auth): State<AuthState>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, ProfileApiError> {
+    let api_key = request
+        .headers()
+        .get(API_KEY_HEADER)
+        .and_then(|key| key.to_str().ok());
+
+    let caller = match (api_key, bearer_token(&request), &auth.jwt_verifier) {
+        (Some(key), _, _) => match auth.service.authenticate_api_key(key) {
+            Some(api_key) => Caller::ApiKey(api_key),
+            None => {
+                tracing::warn!("Rejected unknown api key on {}", request.uri().path());
+                return Err(ProfileApiError::Unauthorized);
+            }
+        },
+        (None, Some(token), Some(jwt_verifier)) => {
+            match jwt_verifier.verify(token).and_then(|subject| {
+                auth.public_ids
+                    .decode(IdKind::Profile, &subject)
+                    .ok_or_else(|| format!("token subject:{} is not a profile id", subject))
+            }) {
+                Ok(profile_id) => Caller::Customer { profile_id },
+                Err(err) => {
+                    tracing::warn!(
+                        "Rejected customer token on {}, {}",
+                        request.uri().path(),
+                        err
+                    );
+                    return Err(ProfileApiError::Unauthorized);
+                }
+            }
+        }
+        _ => return Err(ProfileApiError::Unauthorized),
+    };
+
+    request.extensions_mut().insert(caller);
+    Ok(next.run(request).await)
+}
+
+///
+/// Only lets allowed callers through, has to run after `authenticate`
+///
+pub(crate) async fn authorize(
+    State(allowed): State<Allowed>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ProfileApiError> {
+    let is_allowed = match request.extensions().get::<Caller>() {
+        Some(Caller::ApiKey(api_key)) => allowed.roles.contains(&api_key.role),
+        Some(Caller::Customer { .. }) => allowed.customers,
+        None => false,
+    };
+    if !is_allowed {
+        return Err(ProfileApiError::Forbidden);
+    }
+
+    Ok(next.run(request).await)
+}
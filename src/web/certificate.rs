@@ -0,0 +1,271 @@
+use pdf_writer::{Content, Finish, Name, Pdf, Rect, Ref, Str};
+use qrcode::{types::QrError, Color, EcLevel, QrCode};
+
+use crate::service::model::{RegistrationCertificate, RegistrationStatus};
+
+const TITLE: &str = "Certificate of Product Registration";
+// A4 in points, which is what both formats are laid out in
+const PAGE_WIDTH: f32 = 595.0;
+const PAGE_HEIGHT: f32 = 842.0;
+const MARGIN: f32 = 72.0;
+const QR_CODE_SIZE: f32 = 144.0;
+
+fn format_date(date: chrono::DateTime<chrono::Utc>) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
+
+fn format_status(status: RegistrationStatus) -> &'static str {
+    match status {
+        RegistrationStatus::Pending => "Pending",
+        RegistrationStatus::Active => "Active",
+        RegistrationStatus::Suspended => "Suspended",
+        RegistrationStatus::Expired => "Expired",
+        RegistrationStatus::Revoked => "Revoked",
+        RegistrationStatus::Transferred => "Transferred",
+        RegistrationStatus::Upgraded => "Upgraded",
+    }
+}
+
+// Label and value of each line below the title, the registration is shown with its public id
+fn certificate_lines(
+    certificate: &RegistrationCertificate,
+    registration_id: &str,
+) -> Vec<(&'static str, String)> {
+    Vec::from([
+        ("Registered to", certificate.profile_name.clone()),
+        ("Product", certificate.product.clone()),
+        ("Serial code", certificate.serial_code.clone()),
+        ("Purchase date", format_date(certificate.purchase_date)),
+        (
+            "Expiry date",
+            certificate
+                .expiry_at
+                .map_or(String::from("Does not expire"), format_date),
+        ),
+        ("Status", format_status(certificate.status).into()),
+        ("Registration", registration_id.into()),
+        ("Issued", format_date(certificate.issued_at)),
+    ])
+}
+
+// Dark modules of the QR code, as (x, y) from the top left, and its width in modules
+fn qr_code_modules(data: &str) -> Result<(usize, Vec<(usize, usize)>), QrError> {
+    let qr_code = QrCode::with_error_correction_level(data, EcLevel::M)?;
+    let width = qr_code.width();
+    let modules = qr_code
+        .to_colors()
+        .into_iter()
+        .enumerate()
+        .filter(|(_, color)| *color == Color::Dark)
+        .map(|(i, _)| (i % width, i / width))
+        .collect();
+
+    Ok((width, modules))
+}
+
+fn escape_xml(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '&' => "&amp;".into(),
+            '<' => "&lt;".into(),
+            '>' => "&gt;".into(),
+            '"' => "&quot;".into(),
+            '\'' => "&apos;".into(),
+            c => c.to_string(),
+        })
+        .collect()
+}
+
+pub(crate) fn render_svg(
+    certificate: &RegistrationCertificate,
+    registration_id: &str,
+) -> Result<String, QrError> {
+    let (qr_code_width, qr_code_modules) = qr_code_modules(&certificate.verification_url)?;
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}pt" height="{h}pt" viewBox="0 0 {w} {h}">"#,
+        w = PAGE_WIDTH,
+        h = PAGE_HEIGHT
+    );
+    svg.push_str(&format!(
+        r#"<rect width="{}" height="{}" fill="white"/>"#,
+        PAGE_WIDTH, PAGE_HEIGHT
+    ));
+    svg.push_str(&format!(
+        r#"<text x="{}" y="{}" font-family="Helvetica, Arial, sans-serif" font-size="22" font-weight="bold">{}</text>"#,
+        MARGIN,
+        MARGIN + 22.0,
+        TITLE
+    ));
+
+    let mut y = MARGIN + 72.0;
+    for (label, value) in certificate_lines(certificate, registration_id) {
+        svg.push_str(&format!(
+            r#"<text x="{}" y="{}" font-family="Helvetica, Arial, sans-serif" font-size="12"><tspan font-weight="bold">{}:</tspan> {}</text>"#,
+            MARGIN,
+            y,
+            label,
+            escape_xml(&value)
+        ));
+        y += 24.0;
+    }
+
+    // the quiet zone around the QR code is left to the page's margin
+    let module_size = QR_CODE_SIZE / qr_code_width as f32;
+    let qr_code_y = y;
+    let path: String = qr_code_modules
+        .iter()
+        .map(|(x, y)| format!("M{} {}h1v1h-1z", x, y))
+        .collect();
+    svg.push_str(&format!(
+        r#"<path transform="translate({} {}) scale({})" d="{}" fill="black"/>"#,
+        MARGIN, qr_code_y, module_size, path
+    ));
+    svg.push_str(&format!(
+        r#"<text x="{}" y="{}" font-family="Helvetica, Arial, sans-serif" font-size="10">Scan or visit {} to verify this registration</text>"#,
+        MARGIN,
+        qr_code_y + QR_CODE_SIZE + 20.0,
+        escape_xml(&certificate.verification_url)
+    ));
+    svg.push_str("</svg>");
+
+    Ok(svg)
+}
+
+// Helvetica is one of the standard fonts, so nothing is embedded, but it can only show WinAnsi characters
+fn win_ansi(text: &str) -> Vec<u8> {
+    text.chars()
+        .map(|c| match c as u32 {
+            code @ (0x20..=0x7e | 0xa0..=0xff) => code as u8,
+            _ => b'?',
+        })
+        .collect()
+}
+
+pub(crate) fn render_pdf(
+    certificate: &RegistrationCertificate,
+    registration_id: &str,
+) -> Result<Vec<u8>, QrError> {
+    let (qr_code_width, qr_code_modules) = qr_code_modules(&certificate.verification_url)?;
+
+    let catalog_id = Ref::new(1);
+    let page_tree_id = Ref::new(2);
+    let page_id = Ref::new(3);
+    let regular_font_id = Ref::new(4);
+    let bold_font_id = Ref::new(5);
+    let content_id = Ref::new(6);
+    let regular_font = Name(b"F1");
+    let bold_font = Name(b"F2");
+
+    let mut pdf = Pdf::new();
+    pdf.catalog(catalog_id).pages(page_tree_id);
+    pdf.pages(page_tree_id).kids([page_id]).count(1);
+
+    let mut page = pdf.page(page_id);
+    page.media_box(Rect::new(0.0, 0.0, PAGE_WIDTH, PAGE_HEIGHT));
+    page.parent(page_tree_id);
+    page.contents(content_id);
+    let mut resources = page.resources();
+    let mut fonts = resources.fonts();
+    fonts.pair(regular_font, regular_font_id);
+    fonts.pair(bold_font, bold_font_id);
+    fonts.finish();
+    resources.finish();
+    page.finish();
+
+    pdf.type1_font(regular_font_id)
+        .base_font(Name(b"Helvetica"))
+        .encoding_predefined(Name(b"WinAnsiEncoding"));
+    pdf.type1_font(bold_font_id)
+        .base_font(Name(b"Helvetica-Bold"))
+        .encoding_predefined(Name(b"WinAnsiEncoding"));
+
+    // PDF coordinates start at the bottom left
+    let mut content = Content::new();
+    content
+        .begin_text()
+        .set_font(bold_font, 22.0)
+        .next_line(MARGIN, PAGE_HEIGHT - MARGIN - 22.0)
+        .show(Str(TITLE.as_bytes()))
+        .end_text();
+
+    let mut y = PAGE_HEIGHT - MARGIN - 72.0;
+    for (label, value) in certificate_lines(certificate, registration_id) {
+        content
+            .begin_text()
+            .set_font(bold_font, 12.0)
+            .next_line(MARGIN, y)
+            .show(Str(format!("{}: ", label).as_bytes()))
+            .set_font(regular_font, 12.0)
+            .show(Str(&win_ansi(&value)))
+            .end_text();
+        y -= 24.0;
+    }
+
+    let module_size = QR_CODE_SIZE / qr_code_width as f32;
+    let qr_code_top = y;
+    content.set_fill_gray(0.0);
+    for (x, y) in qr_code_modules {
+        content.rect(
+            MARGIN + x as f32 * module_size,
+            qr_code_top - (y + 1) as f32 * module_size,
+            module_size,
+            module_size,
+        );
+    }
+    content.fill_nonzero();
+    content
+        .begin_text()
+        .set_font(regular_font, 10.0)
+        .next_line(MARGIN, qr_code_top - QR_CODE_SIZE - 20.0)
+        .show(Str(&win_ansi(&format!(
+            "Scan or visit {} to verify this registration",
+            certificate.verification_url
+        ))))
+        .end_text();
+    pdf.stream(content_id, &content.finish());
+
+    Ok(pdf.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn certificate(profile_name: &str) -> RegistrationCertificate {
+        RegistrationCertificate {
+            registration_id: 1,
+            profile_name: profile_name.into(),
+            product: "SKE48".into(),
+            serial_code: "A1B2C3D4".into(),
+            purchase_date: chrono::DateTime::parse_from_rfc3339("2023-01-15T15:04:05Z")
+                .unwrap()
+                .into(),
+            expiry_at: None,
+            status: RegistrationStatus::Active,
+            verification_url: "https://example.com/verify/A1B2C3D4?token=abc&v=1".into(),
+            issued_at: chrono::DateTime::parse_from_rfc3339("2024-06-01T00:00:00Z")
+                .unwrap()
+                .into(),
+        }
+    }
+
+    #[test]
+    fn render_svg_escapes_text() {
+        let svg = render_svg(&certificate("<Tom> & \"Jerry\""), "reg_0000000000001").unwrap();
+
+        assert!(svg.contains("&lt;Tom&gt; &amp; &quot;Jerry&quot;"));
+        assert!(!svg.contains("<Tom>"));
+        assert!(svg.contains("https://example.com/verify/A1B2C3D4?token=abc&amp;v=1"));
+        assert!(svg.ends_with("</svg>"));
+    }
+
+    #[test]
+    fn render_pdf_maps_characters_outside_win_ansi() {
+        let pdf = render_pdf(&certificate("Lee 李 Chen"), "reg_0000000000001").unwrap();
+
+        assert!(pdf.starts_with(b"%PDF"));
+        assert!(pdf.windows(12).any(|text| text == b"(Lee ? Chen)"));
+        assert_eq!(b"Zo\xeb ?".to_vec(), win_ansi("Zoë 李"));
+    }
+}
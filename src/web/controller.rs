@@ -3,30 +3,81 @@ use std::sync::Arc;
 use axum::{
     debug_handler,
     extract::{Path, Query, State},
-    Json,
+    response::IntoResponse,
+    Extension, Json,
 };
 
 use crate::{
-    repository::inram::InMemoryProfileRepository, service::ProfileService,
-    web::model::ProductRegistrationRecord,
+    repository::inram::InMemoryProfileRepository,
+    service::{model::PurchaseDetails, ProfileService},
+    web::model::{
+        ApiKey, ApiKeyRole, BackupSummary, CreatedApiKey, Entitlement, EntitlementCheck,
+        IntegrityReport, LicenseClaims, LicenseKey, LicenseVerifyingKey, PooledSerialCode, Product,
+        ProductRegistrationOwner, ProductRegistrationRecord, ProductRegistrationRenewal,
+        RegistrationStatus, SerialCodeVerification, SerialPool, SerialPoolImport, SerialTemplate,
+    },
 };
 
-use super::{error::ProfileApiError, model::Profile};
+use super::{
+    auth::Caller,
+    certificate,
+    error::ProfileApiError,
+    model::Profile,
+    public_id::{IdKind, PublicIds},
+};
 
 #[derive(serde::Deserialize)]
 pub(crate) struct Pagination {
     pub page: Option<u32>,
 }
 
+#[derive(serde::Deserialize)]
+pub(crate) struct ProductRegistrationsQuery {
+    pub page: Option<u32>,
+    pub status: Option<RegistrationStatus>,
+    // RFC 3339 timestamp, lists registrations as they were at that point in time
+    pub as_of: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct AsOfQuery {
+    // RFC 3339 timestamp
+    pub as_of: Option<chrono::DateTime<chrono::Utc>>,
+}
+
 #[derive(serde::Serialize)]
 pub(crate) struct PagedResult<T> {
     pub page: u32,
     pub items: Vec<T>,
 }
 
+// Ids which don't decode were never handed out, so they are treated like unknown ids
+fn decode_id(
+    public_ids: &PublicIds,
+    kind: IdKind,
+    public_id: &str,
+) -> Result<u64, ProfileApiError> {
+    public_ids
+        .decode(kind, public_id)
+        .ok_or(ProfileApiError::NotFound)
+}
+
+type Service = ProfileService<InMemoryProfileRepository>;
+
+// Changes wait for the journal to be synced to disk, so they are made off the runtime
+async fn run_blocking<T: Send + 'static>(
+    service: Arc<Service>,
+    f: impl FnOnce(&Service) -> T + Send + 'static,
+) -> Result<T, ProfileApiError> {
+    tokio::task::spawn_blocking(move || f(&service))
+        .await
+        .map_err(|err| ProfileApiError::InternalError(format!("Task failed: {}", err)))
+}
+
 #[debug_handler]
 pub(crate) async fn profiles_get(
     State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
     Query(query): Query<Pagination>,
 ) -> Result<Json<PagedResult<Profile>>, ProfileApiError> {
     let page = query.page.unwrap_or(0);
@@ -35,19 +86,33 @@ pub(crate) async fn profiles_get(
 
     Ok(Json(PagedResult {
         page,
-        items: res.into_iter().map(|profile| profile.into()).collect(),
+        items: res
+            .into_iter()
+            .map(|profile| Profile::new(profile, &public_ids))
+            .collect(),
     }))
 }
 
 #[debug_handler]
 pub(crate) async fn profile_product_registrations_get(
     State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
-    Path(profile_id): Path<u64>,
-    Query(query): Query<Pagination>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Extension(caller): Extension<Caller>,
+    Path(profile_id): Path<String>,
+    Query(query): Query<ProductRegistrationsQuery>,
 ) -> Result<Json<PagedResult<ProductRegistrationRecord>>, ProfileApiError> {
+    let profile_id = decode_id(&public_ids, IdKind::Profile, &profile_id)?;
+    if !caller.can_access_profile(profile_id) {
+        return Err(ProfileApiError::Forbidden);
+    }
     let page = query.page.unwrap_or(0);
 
-    let res = service.get_product_registrations_for_profile(profile_id, page);
+    let res = service.get_product_registrations_for_profile(
+        profile_id,
+        query.status.map(|status| status.into()),
+        query.as_of,
+        page,
+    );
 
     match res {
         None => Err(ProfileApiError::NotFound),
@@ -55,7 +120,7 @@ pub(crate) async fn profile_product_registrations_get(
             page,
             items: registrations
                 .into_iter()
-                .map(|registration| registration.into())
+                .map(|registration| ProductRegistrationRecord::new(registration, &public_ids))
                 .collect(),
         })),
     }
@@ -64,11 +129,36 @@ pub(crate) async fn profile_product_registrations_get(
 #[debug_handler]
 pub(crate) async fn product_registrations_get(
     State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
-    Path(product_registration_id): Path<u64>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(product_registration_id): Path<String>,
 ) -> Result<Json<ProductRegistrationRecord>, ProfileApiError> {
+    let product_registration_id = decode_id(
+        &public_ids,
+        IdKind::ProductRegistration,
+        &product_registration_id,
+    )?;
     let product_registration = service.get_product_registration(product_registration_id);
     match product_registration {
-        Some(registration) => Ok(Json(registration.into())),
+        Some(registration) => Ok(Json(ProductRegistrationRecord::new(
+            registration,
+            &public_ids,
+        ))),
+        None => Err(ProfileApiError::NotFound),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn product_registration_by_serial_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(serial_code): Path<String>,
+) -> Result<Json<ProductRegistrationRecord>, ProfileApiError> {
+    let product_registration = service.get_product_registration_by_serial(&serial_code);
+    match product_registration {
+        Some(registration) => Ok(Json(ProductRegistrationRecord::new(
+            registration,
+            &public_ids,
+        ))),
         None => Err(ProfileApiError::NotFound),
     }
 }
@@ -92,31 +182,756 @@ pub(crate) async fn product_post(
     State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
     Json(req): Json<ProductPostRequest>,
 ) -> Result<Json<ProductPostResponse>, ProfileApiError> {
-    let res = service.create_product(&req.sku, req.active_for, &req.bundled_products);
+    let sku = req.sku.clone();
+    let res = run_blocking(service, move |service| {
+        service.create_product(&req.sku, req.active_for, &req.bundled_products)
+    })
+    .await?;
     match res {
         Ok(products) => Ok(Json(ProductPostResponse {
-            sku_added: req.sku,
+            sku_added: sku,
             bundled_products: products.into_iter().collect(),
         })),
         Err(err) => Err(err.into()),
     }
 }
 
+#[derive(serde::Deserialize)]
+pub(crate) struct ProductUpgradePostRequest {
+    pub sku: String,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct ProductUpgradePostResponse {
+    pub sku: String,
+    pub upgrades: Vec<String>,
+}
+
+#[debug_handler]
+pub(crate) async fn product_upgrade_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Path(sku): Path<String>,
+    Json(req): Json<ProductUpgradePostRequest>,
+) -> Result<Json<ProductUpgradePostResponse>, ProfileApiError> {
+    let upgraded_sku = sku.clone();
+    let res = run_blocking(service, move |service| {
+        service.create_product_upgrade(&upgraded_sku, &req.sku)
+    })
+    .await?;
+    match res {
+        Ok(upgrades) => Ok(Json(ProductUpgradePostResponse {
+            sku,
+            upgrades: upgrades.into_iter().collect(),
+        })),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct ProductSerialFormatRequest {
+    // regex the whole serial code has to match
+    pub serial_format: String,
+}
+
+#[debug_handler]
+pub(crate) async fn product_serial_format_put(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Path(sku): Path<String>,
+    Json(req): Json<ProductSerialFormatRequest>,
+) -> Result<Json<ProductSerialFormatRequest>, ProfileApiError> {
+    let serial_format = req.serial_format.clone();
+    let res = run_blocking(service, move |service| {
+        service.set_product_serial_format(&sku, &serial_format)
+    })
+    .await?;
+
+    match res {
+        Ok(()) => Ok(Json(req)),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn product_serial_template_put(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Path(sku): Path<String>,
+    Json(req): Json<SerialTemplate>,
+) -> Result<Json<SerialTemplate>, ProfileApiError> {
+    let res = run_blocking(service, move |service| {
+        service.set_product_serial_template(&sku, req.into())
+    })
+    .await?;
+
+    match res {
+        Ok(serial_template) => Ok(Json(serial_template.into())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn product_serial_template_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Path(sku): Path<String>,
+) -> Result<Json<SerialTemplate>, ProfileApiError> {
+    match service.get_product_serial_template(&sku) {
+        Some(serial_template) => Ok(Json(serial_template.into())),
+        None => Err(ProfileApiError::NotFound),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct SerialPoolPostRequest {
+    pub serial_codes: Vec<PooledSerialCode>,
+}
+
+// One `serial_code,lot` per line, with an optional header
+fn parse_serial_pool_csv(body: &str) -> Result<Vec<PooledSerialCode>, String> {
+    let mut serial_codes = Vec::new();
+    for (i, line) in body.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || (i == 0 && line == "serial_code,lot") {
+            continue;
+        }
+
+        let columns: Vec<&str> = line.split(',').map(|column| column.trim()).collect();
+        let [serial_code, lot] = columns[..] else {
+            return Err(format!(
+                "line {} has to be serial_code,lot, found:{}",
+                i + 1,
+                line
+            ));
+        };
+        serial_codes.push(PooledSerialCode {
+            serial_code: serial_code.into(),
+            lot: lot.into(),
+        });
+    }
+
+    Ok(serial_codes)
+}
+
+#[debug_handler]
+pub(crate) async fn product_serial_pool_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Path(sku): Path<String>,
+    headers: http::HeaderMap,
+    body: String,
+) -> Result<Json<SerialPoolImport>, ProfileApiError> {
+    let is_csv = headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.starts_with("text/csv"));
+    let serial_codes = if is_csv {
+        parse_serial_pool_csv(&body).map_err(ProfileApiError::BadRequest)?
+    } else {
+        serde_json::from_str::<SerialPoolPostRequest>(&body)
+            .map_err(|err| ProfileApiError::BadRequest(err.to_string()))?
+            .serial_codes
+    };
+
+    let res = run_blocking(service, move |service| {
+        service.import_serial_pool(
+            &sku,
+            serial_codes
+                .into_iter()
+                .map(|pooled| pooled.into())
+                .collect(),
+        )
+    })
+    .await?;
+
+    match res {
+        Ok(import) => Ok(Json(import.into())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn product_serial_pool_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Path(sku): Path<String>,
+) -> Result<Json<SerialPool>, ProfileApiError> {
+    let Some(lots) = service.get_serial_pool(&sku) else {
+        return Err(ProfileApiError::NotFound);
+    };
+
+    let remaining = lots.iter().map(|lot| lot.remaining).sum();
+    Ok(Json(SerialPool {
+        product: Product { sku },
+        remaining,
+        depleted: remaining == 0,
+        lots: lots.into_iter().map(|lot| lot.into()).collect(),
+    }))
+}
+
+#[debug_handler]
+pub(crate) async fn lot_registrations_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(lot): Path<String>,
+) -> Result<Json<Vec<ProductRegistrationRecord>>, ProfileApiError> {
+    match service.get_product_registrations_for_lot(&lot) {
+        Some(records) => Ok(Json(
+            records
+                .into_iter()
+                .map(|r| ProductRegistrationRecord::new(r, &public_ids))
+                .collect(),
+        )),
+        None => Err(ProfileApiError::NotFound),
+    }
+}
+
 #[derive(serde::Deserialize)]
 pub(crate) struct ProductRegistrationPostParams {
     pub product: String,
+    // RFC 3339 timestamp, defaults to now
+    pub purchase_date: Option<chrono::DateTime<chrono::Utc>>,
+    pub retailer: Option<String>,
+    pub channel: Option<String>,
+    pub order_reference: Option<String>,
+    // in minor units of the currency, e.g. cents
+    pub price: Option<u64>,
+    pub currency: Option<String>,
+    // serial code printed on the product, generated if not given
+    pub serial_code: Option<String>,
 }
 
 #[debug_handler]
 pub(crate) async fn product_registrations_post(
     State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
-    Path(profile): Path<u64>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Extension(caller): Extension<Caller>,
+    Path(profile): Path<String>,
     Query(query): Query<ProductRegistrationPostParams>,
 ) -> Result<Json<ProductRegistrationRecord>, ProfileApiError> {
-    let res = service.create_product_registration(profile, &query.product);
+    let profile = decode_id(&public_ids, IdKind::Profile, &profile)?;
+    if !caller.can_access_profile(profile) {
+        return Err(ProfileApiError::Forbidden);
+    }
+    let purchase_details = PurchaseDetails {
+        retailer: query.retailer,
+        channel: query.channel,
+        order_reference: query.order_reference,
+        price: query.price,
+        currency: query.currency,
+    };
+    let res = run_blocking(service, move |service| {
+        service.create_product_registration(
+            profile,
+            &query.product,
+            query.purchase_date,
+            purchase_details,
+            query.serial_code.as_deref(),
+        )
+    })
+    .await?;
 
     match res {
-        Ok(registration) => Ok(Json(registration.into())),
+        Ok(registration) => Ok(Json(ProductRegistrationRecord::new(
+            registration,
+            &public_ids,
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct ProductRegistrationRenewParams {
+    // in seconds, defaults to the product's active_for
+    pub period: Option<u64>,
+}
+
+#[debug_handler]
+pub(crate) async fn product_registration_renew_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(product_registration_id): Path<String>,
+    Query(query): Query<ProductRegistrationRenewParams>,
+) -> Result<Json<ProductRegistrationRecord>, ProfileApiError> {
+    let product_registration_id = decode_id(
+        &public_ids,
+        IdKind::ProductRegistration,
+        &product_registration_id,
+    )?;
+    let res = run_blocking(service, move |service| {
+        service.renew_product_registration(product_registration_id, query.period)
+    })
+    .await?;
+
+    match res {
+        Ok(registration) => Ok(Json(ProductRegistrationRecord::new(
+            registration,
+            &public_ids,
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn product_registration_renewals_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(product_registration_id): Path<String>,
+) -> Result<Json<Vec<ProductRegistrationRenewal>>, ProfileApiError> {
+    let product_registration_id = decode_id(
+        &public_ids,
+        IdKind::ProductRegistration,
+        &product_registration_id,
+    )?;
+    let renewals = service.get_product_registration_renewals(product_registration_id);
+    match renewals {
+        Some(renewals) => Ok(Json(
+            renewals
+                .into_iter()
+                .map(|renewal| ProductRegistrationRenewal::new(renewal, &public_ids))
+                .collect(),
+        )),
+        None => Err(ProfileApiError::NotFound),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct ProductRegistrationStatusRequest {
+    pub reason: String,
+}
+
+#[debug_handler]
+pub(crate) async fn product_registration_revoke_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(product_registration_id): Path<String>,
+    Json(req): Json<ProductRegistrationStatusRequest>,
+) -> Result<Json<ProductRegistrationRecord>, ProfileApiError> {
+    let product_registration_id = decode_id(
+        &public_ids,
+        IdKind::ProductRegistration,
+        &product_registration_id,
+    )?;
+    let res = run_blocking(service, move |service| {
+        service.revoke_product_registration(product_registration_id, &req.reason)
+    })
+    .await?;
+
+    match res {
+        Ok(registration) => Ok(Json(ProductRegistrationRecord::new(
+            registration,
+            &public_ids,
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn product_registration_suspend_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(product_registration_id): Path<String>,
+    Json(req): Json<ProductRegistrationStatusRequest>,
+) -> Result<Json<ProductRegistrationRecord>, ProfileApiError> {
+    let product_registration_id = decode_id(
+        &public_ids,
+        IdKind::ProductRegistration,
+        &product_registration_id,
+    )?;
+    let res = run_blocking(service, move |service| {
+        service.suspend_product_registration(product_registration_id, &req.reason)
+    })
+    .await?;
+
+    match res {
+        Ok(registration) => Ok(Json(ProductRegistrationRecord::new(
+            registration,
+            &public_ids,
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn product_registration_resume_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(product_registration_id): Path<String>,
+) -> Result<Json<ProductRegistrationRecord>, ProfileApiError> {
+    let product_registration_id = decode_id(
+        &public_ids,
+        IdKind::ProductRegistration,
+        &product_registration_id,
+    )?;
+    let res = run_blocking(service, move |service| {
+        service.resume_product_registration(product_registration_id)
+    })
+    .await?;
+
+    match res {
+        Ok(registration) => Ok(Json(ProductRegistrationRecord::new(
+            registration,
+            &public_ids,
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct ProductRegistrationTransferRequest {
+    pub profile_id: String,
+}
+
+#[debug_handler]
+pub(crate) async fn product_registration_transfer_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(product_registration_id): Path<String>,
+    Json(req): Json<ProductRegistrationTransferRequest>,
+) -> Result<Json<ProductRegistrationRecord>, ProfileApiError> {
+    let product_registration_id = decode_id(
+        &public_ids,
+        IdKind::ProductRegistration,
+        &product_registration_id,
+    )?;
+    let Some(profile_id) = public_ids.decode(IdKind::Profile, &req.profile_id) else {
+        return Err(ProfileApiError::BadRequest(format!(
+            "profile_id:{} does not exist",
+            req.profile_id
+        )));
+    };
+    let res = run_blocking(service, move |service| {
+        service.transfer_product_registration(product_registration_id, profile_id)
+    })
+    .await?;
+
+    match res {
+        Ok(registration) => Ok(Json(ProductRegistrationRecord::new(
+            registration,
+            &public_ids,
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn product_registration_ownership_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(product_registration_id): Path<String>,
+) -> Result<Json<Vec<ProductRegistrationOwner>>, ProfileApiError> {
+    let product_registration_id = decode_id(
+        &public_ids,
+        IdKind::ProductRegistration,
+        &product_registration_id,
+    )?;
+    let owners = service.get_product_registration_ownership(product_registration_id);
+    match owners {
+        Some(owners) => Ok(Json(
+            owners
+                .into_iter()
+                .map(|owner| ProductRegistrationOwner::new(owner, &public_ids))
+                .collect(),
+        )),
+        None => Err(ProfileApiError::NotFound),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct ProductRegistrationUpgradeRequest {
+    pub product: String,
+    // adds the time left on the upgraded registration to the new expiry
+    #[serde(default)]
+    pub prorate: bool,
+}
+
+#[debug_handler]
+pub(crate) async fn product_registration_upgrade_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(product_registration_id): Path<String>,
+    Json(req): Json<ProductRegistrationUpgradeRequest>,
+) -> Result<Json<ProductRegistrationRecord>, ProfileApiError> {
+    let product_registration_id = decode_id(
+        &public_ids,
+        IdKind::ProductRegistration,
+        &product_registration_id,
+    )?;
+    let res = run_blocking(service, move |service| {
+        service.upgrade_product_registration(product_registration_id, &req.product, req.prorate)
+    })
+    .await?;
+
+    match res {
+        Ok(registration) => Ok(Json(ProductRegistrationRecord::new(
+            registration,
+            &public_ids,
+        ))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn profile_entitlements_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(profile_id): Path<String>,
+    Query(query): Query<AsOfQuery>,
+) -> Result<Json<Vec<Entitlement>>, ProfileApiError> {
+    let profile_id = decode_id(&public_ids, IdKind::Profile, &profile_id)?;
+    let entitlements = service.get_entitlements(profile_id, query.as_of);
+    match entitlements {
+        Some(entitlements) => Ok(Json(
+            entitlements
+                .into_iter()
+                .map(|entitlement| Entitlement::new(entitlement, &public_ids))
+                .collect(),
+        )),
+        None => Err(ProfileApiError::NotFound),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn profile_entitlement_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path((profile_id, sku)): Path<(String, String)>,
+    Query(query): Query<AsOfQuery>,
+) -> Result<Json<EntitlementCheck>, ProfileApiError> {
+    let profile_id = decode_id(&public_ids, IdKind::Profile, &profile_id)?;
+    let entitlement = service.get_entitlement(profile_id, &sku, query.as_of);
+    match entitlement {
+        Some(entitlement) => Ok(Json(EntitlementCheck::new(entitlement, &public_ids))),
+        None => Err(ProfileApiError::NotFound),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn product_registration_license_key_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(public_id): Path<String>,
+) -> Result<Json<LicenseKey>, ProfileApiError> {
+    let id = decode_id(&public_ids, IdKind::ProductRegistration, &public_id)?;
+    let res = service.create_license_key(id, |profile_id| {
+        public_ids.encode(IdKind::Profile, profile_id)
+    });
+
+    match res {
+        Ok(license_key) => Ok(Json(license_key.into())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn license_keys_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+) -> Json<Vec<LicenseVerifyingKey>> {
+    Json(
+        service
+            .get_license_verifying_keys()
+            .into_iter()
+            .map(|key| key.into())
+            .collect(),
+    )
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct LicenseKeyVerifyRequest {
+    pub license_key: String,
+}
+
+#[debug_handler]
+pub(crate) async fn license_key_verify_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Json(request): Json<LicenseKeyVerifyRequest>,
+) -> Result<Json<LicenseClaims>, ProfileApiError> {
+    let res = service.verify_license_key(&request.license_key);
+
+    match res {
+        Ok(claims) => Ok(Json(claims.into())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[derive(serde::Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CertificateFormat {
+    #[default]
+    Pdf,
+    Svg,
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct CertificateQuery {
+    #[serde(default)]
+    pub format: CertificateFormat,
+}
+
+#[debug_handler]
+pub(crate) async fn product_registration_certificate_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(public_id): Path<String>,
+    Query(query): Query<CertificateQuery>,
+) -> Result<axum::response::Response, ProfileApiError> {
+    let id = decode_id(&public_ids, IdKind::ProductRegistration, &public_id)?;
+    let Some(certificate) = service.get_registration_certificate(id) else {
+        return Err(ProfileApiError::NotFound);
+    };
+
+    let (content_type, extension, body) = match query.format {
+        CertificateFormat::Pdf => (
+            "application/pdf",
+            "pdf",
+            certificate::render_pdf(&certificate, &public_id).map_err(|err| {
+                ProfileApiError::InternalError(format!("Unable to render certificate, {}", err))
+            })?,
+        ),
+        CertificateFormat::Svg => (
+            "image/svg+xml",
+            "svg",
+            certificate::render_svg(&certificate, &public_id)
+                .map_err(|err| {
+                    ProfileApiError::InternalError(format!("Unable to render certificate, {}", err))
+                })?
+                .into_bytes(),
+        ),
+    };
+
+    Ok((
+        [
+            (http::header::CONTENT_TYPE, String::from(content_type)),
+            (
+                http::header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"registration-{}-certificate.{}\"",
+                    public_id, extension
+                ),
+            ),
+        ],
+        body,
+    )
+        .into_response())
+}
+
+#[debug_handler]
+pub(crate) async fn verify_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Path(serial_code): Path<String>,
+) -> Result<Json<SerialCodeVerification>, ProfileApiError> {
+    match service.verify_serial_code(&serial_code) {
+        Some(verification) => Ok(Json(verification.into())),
+        None => Err(ProfileApiError::NotFound),
+    }
+}
+
+#[derive(serde::Deserialize)]
+pub(crate) struct ApiKeyPostRequest {
+    pub name: String,
+    pub role: ApiKeyRole,
+}
+
+#[debug_handler]
+pub(crate) async fn api_keys_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Json(request): Json<ApiKeyPostRequest>,
+) -> Result<Json<CreatedApiKey>, ProfileApiError> {
+    let res = run_blocking(service, move |service| {
+        service.create_api_key(&request.name, request.role.into())
+    })
+    .await?;
+
+    match res {
+        Ok(created) => Ok(Json(CreatedApiKey::new(created, &public_ids))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn api_keys_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+) -> Json<Vec<ApiKey>> {
+    Json(
+        service
+            .get_api_keys()
+            .into_iter()
+            .map(|api_key| ApiKey::new(api_key, &public_ids))
+            .collect(),
+    )
+}
+
+#[debug_handler]
+pub(crate) async fn api_key_revoke_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+    Path(public_id): Path<String>,
+) -> Result<Json<ApiKey>, ProfileApiError> {
+    let id = decode_id(&public_ids, IdKind::ApiKey, &public_id)?;
+    let res = run_blocking(service, move |service| service.revoke_api_key(id)).await?;
+
+    match res {
+        Ok(api_key) => Ok(Json(ApiKey::new(api_key, &public_ids))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn backup_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+) -> Result<axum::response::Response, ProfileApiError> {
+    // serializing and hashing all of the data would hold up the runtime
+    let backup = run_blocking(service, |service| service.export_backup()).await?;
+
+    Ok((
+        [
+            (
+                http::header::CONTENT_TYPE,
+                String::from("application/octet-stream"),
+            ),
+            (
+                http::header::CONTENT_DISPOSITION,
+                format!(
+                    "attachment; filename=\"backup-{}.pbak\"",
+                    chrono::Utc::now().format("%Y%m%dT%H%M%SZ")
+                ),
+            ),
+        ],
+        backup,
+    )
+        .into_response())
+}
+
+// The body is a backup as downloaded from backup_get
+#[debug_handler]
+pub(crate) async fn restore_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    backup: axum::body::Bytes,
+) -> Result<Json<BackupSummary>, ProfileApiError> {
+    let res = run_blocking(service, move |service| service.restore_backup(&backup)).await?;
+
+    match res {
+        Ok(summary) => Ok(Json(summary.into())),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[debug_handler]
+pub(crate) async fn fsck_get(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+) -> Result<Json<IntegrityReport>, ProfileApiError> {
+    match run_blocking(service, |service| service.check_integrity(false)).await? {
+        Ok(report) => Ok(Json(IntegrityReport::new(report, &public_ids))),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// Same check as fsck_get, what is found is repaired
+#[debug_handler]
+pub(crate) async fn fsck_repair_post(
+    State(service): State<Arc<ProfileService<InMemoryProfileRepository>>>,
+    Extension(public_ids): Extension<Arc<PublicIds>>,
+) -> Result<Json<IntegrityReport>, ProfileApiError> {
+    match run_blocking(service, |service| service.check_integrity(true)).await? {
+        Ok(report) => Ok(Json(IntegrityReport::new(report, &public_ids))),
         Err(err) => Err(err.into()),
     }
 }
@@ -1,8 +1,18 @@
 use axum::Json;
 
 pub enum ProfileApiError {
+    // No or an unknown API key
+    Unauthorized,
+    // The API key's role is not allowed to do this
+    Forbidden,
     NotFound,
     BadRequest(String),
+    Conflict(String),
+    SerialCodeTaken(String),
+    // The Idempotency-Key was first used with a different request
+    IdempotencyKeyReused(String),
+    // Seconds until the client can retry
+    TooManyRequests(u64),
     InternalError(String),
 }
 
@@ -11,15 +21,51 @@ struct ErrorResponse {
     pub reason: String,
 }
 
+#[derive(serde::Serialize)]
+struct CodedErrorResponse {
+    // stable identifier clients can match on, unlike the reason
+    pub code: &'static str,
+    pub reason: String,
+}
+
 impl axum::response::IntoResponse for ProfileApiError {
     fn into_response(self) -> axum::response::Response {
         match self {
+            ProfileApiError::Unauthorized => (http::StatusCode::UNAUTHORIZED).into_response(),
+            ProfileApiError::Forbidden => (http::StatusCode::FORBIDDEN).into_response(),
             ProfileApiError::NotFound => (http::StatusCode::NOT_FOUND).into_response(),
             ProfileApiError::BadRequest(reason) => (
                 http::StatusCode::BAD_REQUEST,
                 Json(ErrorResponse { reason }),
             )
                 .into_response(),
+            ProfileApiError::Conflict(reason) => {
+                (http::StatusCode::CONFLICT, Json(ErrorResponse { reason })).into_response()
+            }
+            ProfileApiError::SerialCodeTaken(reason) => (
+                http::StatusCode::CONFLICT,
+                Json(CodedErrorResponse {
+                    code: "serial_code_taken",
+                    reason,
+                }),
+            )
+                .into_response(),
+            ProfileApiError::IdempotencyKeyReused(reason) => (
+                http::StatusCode::UNPROCESSABLE_ENTITY,
+                Json(CodedErrorResponse {
+                    code: "idempotency_key_reused",
+                    reason,
+                }),
+            )
+                .into_response(),
+            ProfileApiError::TooManyRequests(retry_after) => (
+                http::StatusCode::TOO_MANY_REQUESTS,
+                [(http::header::RETRY_AFTER, retry_after.to_string())],
+                Json(ErrorResponse {
+                    reason: format!("Too many requests, retry in {}s", retry_after),
+                }),
+            )
+                .into_response(),
             ProfileApiError::InternalError(reason) => (
                 http::StatusCode::INTERNAL_SERVER_ERROR,
                 Json(ErrorResponse { reason }),
@@ -35,6 +81,11 @@ impl From<crate::service::ProfileServiceError> for ProfileApiError {
             crate::service::ProfileServiceError::BadRequest(msg) => {
                 ProfileApiError::BadRequest(msg)
             }
+            crate::service::ProfileServiceError::NotFound(_) => ProfileApiError::NotFound,
+            crate::service::ProfileServiceError::Conflict(msg) => ProfileApiError::Conflict(msg),
+            crate::service::ProfileServiceError::SerialCodeTaken(msg) => {
+                ProfileApiError::SerialCodeTaken(msg)
+            }
             crate::service::ProfileServiceError::InternalServiceError(msg) => {
                 ProfileApiError::InternalError(msg)
             }
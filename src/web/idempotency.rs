@@ -0,0 +1,417 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use dashmap::{mapref::entry::Entry, DashMap};
+use sha2::{Digest, Sha256};
+
+use super::{auth::Caller, error::ProfileApiError};
+
+pub(crate) const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+// Set on responses which were replayed rather than handled again
+pub(crate) const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+const MAX_KEY_LENGTH: usize = 255;
+// Same as the limit axum puts on bodies read by extractors
+const MAX_BODY_BYTES: usize = 2 * 1024 * 1024;
+// Live keys a single caller can have, further keys are turned away until some expire
+const MAX_KEYS_PER_CALLER: usize = 10_000;
+// How often expired keys are dropped
+pub(crate) const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+struct StoredResponse {
+    status: http::StatusCode,
+    headers: http::HeaderMap,
+    body: Bytes,
+}
+
+impl StoredResponse {
+    fn replay(self) -> Response {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response.headers_mut().insert(
+            IDEMPOTENT_REPLAYED_HEADER,
+            http::HeaderValue::from_static("true"),
+        );
+        response
+    }
+}
+
+struct StoredRequest {
+    // hash of the method, URI and body the key was first used with
+    fingerprint: [u8; 32],
+    created_at: Instant,
+    // None while the first request is still being handled
+    response: Option<StoredResponse>,
+}
+
+enum Begun {
+    // Not seen before, the request has to be handled and its response stored with `complete`
+    New,
+    Replay(StoredResponse),
+}
+
+///
+/// Responses to POST requests by their `Idempotency-Key`, kept for a while so retries get the same response
+///
+pub(crate) struct IdempotencyStore {
+    ttl: Duration,
+    // keyed by who made the request and their idempotency key, so callers can't see each other's responses
+    requests: DashMap<(String, String), StoredRequest>,
+    // caller -> keys of theirs in requests
+    caller_keys: DashMap<String, usize>,
+}
+
+impl IdempotencyStore {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            requests: DashMap::new(),
+            caller_keys: DashMap::new(),
+        }
+    }
+
+    ///
+    /// Drops expired keys, run every SWEEP_INTERVAL rather than on requests so they don't pay for it
+    ///
+    pub fn sweep(&self, now: Instant) {
+        self.requests.retain(|(caller, _), request| {
+            let live = now.duration_since(request.created_at) < self.ttl;
+            if !live {
+                self.forget_caller_key(caller);
+            }
+            live
+        });
+    }
+
+    fn forget_caller_key(&self, caller: &str) {
+        if let Entry::Occupied(mut keys) = self.caller_keys.entry(caller.to_string()) {
+            *keys.get_mut() -= 1;
+            if *keys.get() == 0 {
+                keys.remove();
+            }
+        }
+    }
+
+    fn forget(&self, key: &(String, String)) {
+        if self.requests.remove(key).is_some() {
+            self.forget_caller_key(&key.0);
+        }
+    }
+
+    fn begin(
+        &self,
+        key: (String, String),
+        fingerprint: [u8; 32],
+        now: Instant,
+    ) -> Result<Begun, ProfileApiError> {
+        match self.requests.entry(key) {
+            Entry::Occupied(mut entry)
+                if now.duration_since(entry.get().created_at) >= self.ttl =>
+            {
+                entry.insert(StoredRequest {
+                    fingerprint,
+                    created_at: now,
+                    response: None,
+                });
+                Ok(Begun::New)
+            }
+            Entry::Occupied(entry) => {
+                let request = entry.get();
+                if request.fingerprint != fingerprint {
+                    return Err(ProfileApiError::IdempotencyKeyReused(
+                        "Idempotency-Key was already used for a different request".into(),
+                    ));
+                }
+                match &request.response {
+                    Some(response) => Ok(Begun::Replay(response.clone())),
+                    None => Err(ProfileApiError::Conflict(
+                        "A request with this Idempotency-Key is still being processed".into(),
+                    )),
+                }
+            }
+            Entry::Vacant(entry) => {
+                let mut caller_keys = self.caller_keys.entry(entry.key().0.clone()).or_default();
+                if *caller_keys >= MAX_KEYS_PER_CALLER {
+                    tracing::warn!(
+                        "Turning away Idempotency-Key of {}, it has {} live keys",
+                        entry.key().0,
+                        *caller_keys
+                    );
+                    return Err(ProfileApiError::TooManyRequests(SWEEP_INTERVAL.as_secs()));
+                }
+                *caller_keys += 1;
+
+                entry.insert(StoredRequest {
+                    fingerprint,
+                    created_at: now,
+                    response: None,
+                });
+                Ok(Begun::New)
+            }
+        }
+    }
+}
+
+// Forgets the key unless a response was stored, so a request which was dropped halfway, because the client went away
+// or the handler panicked, can be retried
+struct PendingRequest<'a> {
+    store: &'a IdempotencyStore,
+    key: (String, String),
+    completed: bool,
+}
+
+impl PendingRequest<'_> {
+    fn complete(mut self, response: StoredResponse) {
+        if let Some(mut request) = self.store.requests.get_mut(&self.key) {
+            request.response = Some(response);
+        }
+        self.completed = true;
+    }
+}
+
+impl Drop for PendingRequest<'_> {
+    fn drop(&mut self) {
+        if !self.completed {
+            self.store.forget(&self.key);
+        }
+    }
+}
+
+fn is_key_valid(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_KEY_LENGTH
+        && key.chars().all(|c| c.is_ascii_graphic() || c == ' ')
+}
+
+fn fingerprint(method: &http::Method, uri: &http::Uri, body: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_str());
+    hasher.update([0]);
+    hasher.update(uri.to_string());
+    hasher.update([0]);
+    hasher.update(body);
+    hasher.finalize().into()
+}
+
+fn caller_scope(caller: Option<&Caller>) -> String {
+    match caller {
+        Some(Caller::ApiKey(api_key)) => format!("api_key:{}", api_key.id),
+        Some(Caller::Customer { profile_id }) => format!("customer:{}", profile_id),
+        None => String::from("anonymous"),
+    }
+}
+
+///
+/// Replays the stored response of POST requests retried with the same `Idempotency-Key`, has to run after
+/// `authenticate`
+///
+pub(crate) async fn idempotency(
+    State(store): State<Arc<IdempotencyStore>>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ProfileApiError> {
+    if request.method() != http::Method::POST {
+        return Ok(next.run(request).await);
+    }
+    let Some(idempotency_key) = request.headers().get(IDEMPOTENCY_KEY_HEADER) else {
+        return Ok(next.run(request).await);
+    };
+    let idempotency_key = match idempotency_key.to_str() {
+        Ok(key) if is_key_valid(key) => key.to_string(),
+        _ => {
+            return Err(ProfileApiError::BadRequest(format!(
+                "Idempotency-Key has to be 1 to {} printable ASCII characters",
+                MAX_KEY_LENGTH
+            )))
+        }
+    };
+
+    let (parts, body) = request.into_parts();
+    let body = axum::body::to_bytes(body, MAX_BODY_BYTES)
+        .await
+        .map_err(|_| ProfileApiError::BadRequest("Request body is too large".into()))?;
+    let key = (
+        caller_scope(parts.extensions.get::<Caller>()),
+        idempotency_key,
+    );
+
+    match store.begin(
+        key.clone(),
+        fingerprint(&parts.method, &parts.uri, &body),
+        Instant::now(),
+    )? {
+        Begun::New => {}
+        Begun::Replay(response) => {
+            tracing::info!(
+                "Replaying the response to Idempotency-Key {} on {}",
+                key.1,
+                parts.uri.path()
+            );
+            return Ok(response.replay());
+        }
+    }
+    let pending = PendingRequest {
+        store: &store,
+        key,
+        completed: false,
+    };
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+
+    // server errors and rate limits are not the request's fault, so a retry should be handled again
+    let status = response.status();
+    if status.is_server_error() || status == http::StatusCode::TOO_MANY_REQUESTS {
+        return Ok(response);
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = axum::body::to_bytes(body, usize::MAX)
+        .await
+        .map_err(|err| {
+            ProfileApiError::InternalError(format!("Unable to read the response, {}", err))
+        })?;
+    pending.complete(StoredResponse {
+        status: parts.status,
+        headers: parts.headers.clone(),
+        body: body.clone(),
+    });
+
+    Ok(Response::from_parts(parts, Body::from(body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(scope: &str, idempotency_key: &str) -> (String, String) {
+        (scope.into(), idempotency_key.into())
+    }
+
+    fn complete(store: &IdempotencyStore, key: (String, String), status: http::StatusCode) {
+        PendingRequest {
+            store,
+            key,
+            completed: false,
+        }
+        .complete(StoredResponse {
+            status,
+            headers: http::HeaderMap::new(),
+            body: Bytes::from_static(b"{}"),
+        });
+    }
+
+    #[test]
+    fn replay_responses_until_they_expire() {
+        let store = IdempotencyStore::new(Duration::from_secs(3600));
+        let now = Instant::now();
+        let request = fingerprint(
+            &http::Method::POST,
+            &"/profiles/1/product_registrations".parse().unwrap(),
+            b"{}",
+        );
+        let other_request = fingerprint(
+            &http::Method::POST,
+            &"/profiles/1/product_registrations".parse().unwrap(),
+            b"{\"sku\":\"ARCC4\"}",
+        );
+
+        assert!(matches!(
+            store.begin(key("customer:1", "a"), request, now),
+            Ok(Begun::New)
+        ));
+        assert!(matches!(
+            store.begin(key("customer:1", "a"), request, now),
+            Err(ProfileApiError::Conflict(_))
+        ));
+        complete(&store, key("customer:1", "a"), http::StatusCode::CREATED);
+
+        match store.begin(
+            key("customer:1", "a"),
+            request,
+            now + Duration::from_secs(60),
+        ) {
+            Ok(Begun::Replay(response)) => assert_eq!(http::StatusCode::CREATED, response.status),
+            _ => panic!("expected the stored response to be replayed"),
+        }
+        assert!(matches!(
+            store.begin(key("customer:1", "a"), other_request, now),
+            Err(ProfileApiError::IdempotencyKeyReused(_))
+        ));
+        // keys are per caller
+        assert!(matches!(
+            store.begin(key("customer:2", "a"), other_request, now),
+            Ok(Begun::New)
+        ));
+        assert!(matches!(
+            store.begin(
+                key("customer:1", "a"),
+                other_request,
+                now + Duration::from_secs(3600)
+            ),
+            Ok(Begun::New)
+        ));
+    }
+
+    #[test]
+    fn sweep_expired_keys_and_cap_keys_per_caller() {
+        let store = IdempotencyStore::new(Duration::from_secs(3600));
+        let now = Instant::now();
+        let request = fingerprint(&http::Method::POST, &"/product".parse().unwrap(), b"{}");
+
+        for i in 0..MAX_KEYS_PER_CALLER {
+            assert!(matches!(
+                store.begin(key("api_key:1", &i.to_string()), request, now),
+                Ok(Begun::New)
+            ));
+        }
+        assert!(matches!(
+            store.begin(key("api_key:1", "one too many"), request, now),
+            Err(ProfileApiError::TooManyRequests(_))
+        ));
+        // other callers have keys of their own
+        assert!(matches!(
+            store.begin(key("api_key:2", "a"), request, now),
+            Ok(Begun::New)
+        ));
+
+        store.sweep(now + Duration::from_secs(60));
+        assert_eq!(MAX_KEYS_PER_CALLER + 1, store.requests.len());
+        store.sweep(now + Duration::from_secs(3600));
+        assert!(store.requests.is_empty());
+        assert!(store.caller_keys.is_empty());
+        assert!(matches!(
+            store.begin(key("api_key:1", "one too many"), request, now),
+            Ok(Begun::New)
+        ));
+    }
+
+    #[test]
+    fn forget_requests_dropped_before_completing() {
+        let store = IdempotencyStore::new(Duration::from_secs(3600));
+        let now = Instant::now();
+        let request = fingerprint(&http::Method::POST, &"/product".parse().unwrap(), b"{}");
+
+        assert!(matches!(
+            store.begin(key("api_key:1", "a"), request, now),
+            Ok(Begun::New)
+        ));
+        drop(PendingRequest {
+            store: &store,
+            key: key("api_key:1", "a"),
+            completed: false,
+        });
+        assert!(matches!(
+            store.begin(key("api_key:1", "a"), request, now),
+            Ok(Begun::New)
+        ));
+    }
+}
@@ -0,0 +1,179 @@
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, JwkSet, KeyAlgorithm},
+    Algorithm, DecodingKey, Validation,
+};
+
+// Keys not tied to a key id are tried for any token of their algorithm
+struct JwtKey {
+    key_id: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+#[derive(serde::Deserialize)]
+struct CustomerClaims {
+    sub: String,
+}
+
+///
+/// Validates customer bearer tokens signed with HS256 or RS256, their subject being the public profile id
+///
+pub(crate) struct JwtVerifier {
+    keys: Vec<JwtKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl JwtVerifier {
+    pub fn new(issuer: Option<String>, audience: Option<String>) -> Self {
+        Self {
+            keys: Vec::new(),
+            issuer,
+            audience,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn add_hs256_secret(&mut self, secret: &[u8]) {
+        self.keys.push(JwtKey {
+            key_id: None,
+            algorithm: Algorithm::HS256,
+            key: DecodingKey::from_secret(secret),
+        });
+    }
+
+    pub fn add_rs256_public_key_pem(&mut self, pem: &[u8]) -> Result<(), String> {
+        let key = DecodingKey::from_rsa_pem(pem)
+            .map_err(|err| format!("RS256 public key is not a PEM encoded RSA key, {}", err))?;
+        self.keys.push(JwtKey {
+            key_id: None,
+            algorithm: Algorithm::RS256,
+            key,
+        });
+
+        Ok(())
+    }
+
+    ///
+    /// Adds the RSA and symmetric keys of a JWKS document, keys for other algorithms are rejected
+    ///
+    pub fn add_jwks(&mut self, jwks: &str) -> Result<(), String> {
+        let jwks: JwkSet =
+            serde_json::from_str(jwks).map_err(|err| format!("JWKS is invalid, {}", err))?;
+
+        for jwk in jwks.keys.iter() {
+            let algorithm = match (&jwk.algorithm, jwk.common.key_algorithm) {
+                (AlgorithmParameters::RSA(_), None | Some(KeyAlgorithm::RS256)) => Algorithm::RS256,
+                (AlgorithmParameters::OctetKey(_), None | Some(KeyAlgorithm::HS256)) => {
+                    Algorithm::HS256
+                }
+                _ => {
+                    return Err(format!(
+                        "JWKS key {:?} is neither an RS256 nor an HS256 key",
+                        jwk.common.key_id
+                    ))
+                }
+            };
+            let key = DecodingKey::from_jwk(jwk)
+                .map_err(|err| format!("JWKS key {:?} is invalid, {}", jwk.common.key_id, err))?;
+
+            self.keys.push(JwtKey {
+                key_id: jwk.common.key_id.clone(),
+                algorithm,
+                key,
+            });
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// The subject of the token, the public id of the profile it was issued for
+    ///
+    pub fn verify(&self, token: &str) -> Result<String, String> {
+        let header = decode_header(token).map_err(|err| err.to_string())?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_required_spec_claims(&["exp", "sub"]);
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        let candidates = self.keys.iter().filter(|key| {
+            key.algorithm == header.alg && (key.key_id.is_none() || key.key_id == header.kid)
+        });
+        let mut last_error = format!("no {:?} key matches the token", header.alg);
+        for candidate in candidates {
+            match decode::<CustomerClaims>(token, &candidate.key, &validation) {
+                Ok(token) => return Ok(token.claims.sub),
+                Err(err) => last_error = err.to_string(),
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+
+    fn token(header: Header, secret: &[u8], sub: &str, exp: i64) -> String {
+        encode(
+            &header,
+            &serde_json::json!({ "sub": sub, "exp": exp, "iss": "accounts" }),
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn verify_customer_tokens() {
+        let exp = chrono::Utc::now().timestamp() + 600;
+        let mut verifier = JwtVerifier::new(Some("accounts".into()), None);
+        verifier.add_hs256_secret(b"local secret");
+        // "jwks secret" base64url encoded
+        verifier
+            .add_jwks(
+                r#"{"keys":[{"kty":"oct","kid":"2024","alg":"HS256","k":"andrcyBzZWNyZXQ"}]}"#,
+            )
+            .unwrap();
+
+        assert_eq!(
+            Ok(String::from("prof_1")),
+            verifier.verify(&token(Header::default(), b"local secret", "prof_1", exp))
+        );
+        let with_key_id = Header {
+            kid: Some("2024".into()),
+            ..Header::default()
+        };
+        assert_eq!(
+            Ok(String::from("prof_2")),
+            verifier.verify(&token(with_key_id, b"jwks secret", "prof_2", exp))
+        );
+
+        assert!(verifier
+            .verify(&token(Header::default(), b"other secret", "1", exp))
+            .is_err());
+        assert!(verifier
+            .verify(&token(Header::default(), b"local secret", "1", exp - 1200))
+            .is_err());
+        assert!(verifier
+            .verify(&token(
+                Header::new(Algorithm::HS384),
+                b"local secret",
+                "1",
+                exp
+            ))
+            .is_err());
+    }
+}
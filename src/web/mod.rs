@@ -1,3 +1,9 @@
+pub(crate) mod auth;
+pub(crate) mod certificate;
 pub(crate) mod controller;
 pub(crate) mod error;
+pub(crate) mod idempotency;
+pub(crate) mod jwt;
 pub(crate) mod model;
+pub(crate) mod public_id;
+pub(crate) mod rate_limit;
@@ -1,18 +1,20 @@
+use super::public_id::{IdKind, PublicIds};
+
 #[derive(serde::Deserialize, serde::Serialize)]
 pub(crate) struct Profile {
     // Note, this is a slight divergence from the spec,
     // spec specifies id should be int
-    // but since all the other ids are in uint64, I decided to unify everything as uint64
-    pub id: u64,
+    // but sequential ids let anyone enumerate the profiles, so every id is an opaque string
+    pub id: String,
     pub email: String,
     pub firstname: String,
     pub lastname: String,
 }
 
-impl From<crate::service::model::Profile> for Profile {
-    fn from(value: crate::service::model::Profile) -> Self {
+impl Profile {
+    pub fn new(value: crate::service::model::Profile, public_ids: &PublicIds) -> Self {
         Profile {
-            id: value.id,
+            id: public_ids.encode(IdKind::Profile, value.id),
             email: value.email,
             firstname: value.firstname,
             lastname: value.lastname,
@@ -20,26 +22,112 @@ impl From<crate::service::model::Profile> for Profile {
     }
 }
 
+fn default_serial_alphabet() -> String {
+    crate::service::model::DEFAULT_SERIAL_ALPHABET.into()
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct SerialTemplate {
+    #[serde(default)]
+    pub prefix: String,
+    // e.g. [4, 4] for XXXX-XXXX
+    pub groups: Vec<usize>,
+    #[serde(default)]
+    pub separator: String,
+    // defaults to upper case letters and digits, without the ambiguous 0, O, 1 and I
+    #[serde(default = "default_serial_alphabet")]
+    pub alphabet: String,
+    #[serde(default)]
+    pub check_digit: bool,
+}
+
+impl From<crate::service::model::SerialTemplate> for SerialTemplate {
+    fn from(value: crate::service::model::SerialTemplate) -> Self {
+        SerialTemplate {
+            prefix: value.prefix,
+            groups: value.groups,
+            separator: value.separator,
+            alphabet: value.alphabet,
+            check_digit: value.check_digit,
+        }
+    }
+}
+
+impl From<SerialTemplate> for crate::service::model::SerialTemplate {
+    fn from(value: SerialTemplate) -> Self {
+        crate::service::model::SerialTemplate {
+            prefix: value.prefix,
+            groups: value.groups,
+            separator: value.separator,
+            alphabet: value.alphabet,
+            check_digit: value.check_digit,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct PurchaseDetails {
+    pub retailer: Option<String>,
+    pub channel: Option<String>,
+    pub order_reference: Option<String>,
+    // in minor units of the currency, e.g. cents
+    pub price: Option<u64>,
+    pub currency: Option<String>,
+}
+
+impl From<crate::service::model::PurchaseDetails> for PurchaseDetails {
+    fn from(value: crate::service::model::PurchaseDetails) -> Self {
+        PurchaseDetails {
+            retailer: value.retailer,
+            channel: value.channel,
+            order_reference: value.order_reference,
+            price: value.price,
+            currency: value.currency,
+        }
+    }
+}
+
 #[derive(serde::Deserialize, serde::Serialize)]
 pub(crate) struct ProductRegistration {
-    pub id: u64,
+    pub id: String,
     // set as Unix Epoch, at milliseconds precision
     #[serde(with = "chrono::serde::ts_milliseconds")]
     pub purchase_date: chrono::DateTime<chrono::Utc>,
+    pub purchase_details: PurchaseDetails,
     #[serde(with = "chrono::serde::ts_milliseconds_option")]
     pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
     pub product: Product,
     pub serial_code: String,
+    pub lot: Option<String>,
+    pub status: RegistrationStatus,
+    pub status_history: Vec<RegistrationStatusChange>,
+    pub transferred_from: Option<String>,
+    pub transferred_to: Option<String>,
+    pub upgraded_from: Option<String>,
+    pub upgraded_to: Option<String>,
 }
 
-impl From<crate::service::model::ProductRegistration> for ProductRegistration {
-    fn from(value: crate::service::model::ProductRegistration) -> Self {
+impl ProductRegistration {
+    pub fn new(value: crate::service::model::ProductRegistration, public_ids: &PublicIds) -> Self {
+        let registration_id = |id| public_ids.encode(IdKind::ProductRegistration, id);
         ProductRegistration {
-            id: value.id,
+            id: registration_id(value.id),
             purchase_date: value.purchase_date,
+            purchase_details: value.purchase_details.into(),
             expiry_at: value.expiry_at,
             product: Product { sku: value.product },
             serial_code: value.serial_code,
+            lot: value.lot,
+            status: value.status.into(),
+            status_history: value
+                .status_history
+                .into_iter()
+                .map(|change| change.into())
+                .collect(),
+            transferred_from: value.transferred_from.map(registration_id),
+            transferred_to: value.transferred_to.map(registration_id),
+            upgraded_from: value.upgraded_from.map(registration_id),
+            upgraded_to: value.upgraded_to.map(registration_id),
         }
     }
 }
@@ -51,14 +139,17 @@ pub(crate) struct ProductRegistrationRecord {
     pub additional_product_registrations: Vec<ProductRegistration>,
 }
 
-impl From<crate::service::model::ProductRegistrationRecord> for ProductRegistrationRecord {
-    fn from(value: crate::service::model::ProductRegistrationRecord) -> Self {
+impl ProductRegistrationRecord {
+    pub fn new(
+        value: crate::service::model::ProductRegistrationRecord,
+        public_ids: &PublicIds,
+    ) -> Self {
         ProductRegistrationRecord {
-            registration: value.registration.into(),
+            registration: ProductRegistration::new(value.registration, public_ids),
             additional_product_registrations: value
                 .children
                 .into_iter()
-                .map(|a| a.into())
+                .map(|a| ProductRegistration::new(a, public_ids))
                 .collect(),
         }
     }
@@ -68,3 +159,533 @@ impl From<crate::service::model::ProductRegistrationRecord> for ProductRegistrat
 pub(crate) struct Product {
     pub sku: String,
 }
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct ProductRegistrationRenewal {
+    pub registration_id: String,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub renewed_at: chrono::DateTime<chrono::Utc>,
+    // in seconds
+    pub period: u64,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub previous_expiry_at: chrono::DateTime<chrono::Utc>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub expiry_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl ProductRegistrationRenewal {
+    pub fn new(
+        value: crate::service::model::ProductRegistrationRenewal,
+        public_ids: &PublicIds,
+    ) -> Self {
+        ProductRegistrationRenewal {
+            registration_id: public_ids.encode(IdKind::ProductRegistration, value.registration_id),
+            renewed_at: value.renewed_at,
+            period: value.period,
+            previous_expiry_at: value.previous_expiry_at,
+            expiry_at: value.expiry_at,
+        }
+    }
+}
+
+#[derive(Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RegistrationStatus {
+    Pending,
+    Active,
+    Suspended,
+    Expired,
+    Revoked,
+    Transferred,
+    Upgraded,
+}
+
+impl From<crate::service::model::RegistrationStatus> for RegistrationStatus {
+    fn from(value: crate::service::model::RegistrationStatus) -> Self {
+        use crate::service::model::RegistrationStatus as Status;
+
+        match value {
+            Status::Pending => RegistrationStatus::Pending,
+            Status::Active => RegistrationStatus::Active,
+            Status::Suspended => RegistrationStatus::Suspended,
+            Status::Expired => RegistrationStatus::Expired,
+            Status::Revoked => RegistrationStatus::Revoked,
+            Status::Transferred => RegistrationStatus::Transferred,
+            Status::Upgraded => RegistrationStatus::Upgraded,
+        }
+    }
+}
+
+impl From<RegistrationStatus> for crate::service::model::RegistrationStatus {
+    fn from(value: RegistrationStatus) -> Self {
+        use crate::service::model::RegistrationStatus as Status;
+
+        match value {
+            RegistrationStatus::Pending => Status::Pending,
+            RegistrationStatus::Active => Status::Active,
+            RegistrationStatus::Suspended => Status::Suspended,
+            RegistrationStatus::Expired => Status::Expired,
+            RegistrationStatus::Revoked => Status::Revoked,
+            RegistrationStatus::Transferred => Status::Transferred,
+            RegistrationStatus::Upgraded => Status::Upgraded,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct RegistrationStatusChange {
+    pub status: RegistrationStatus,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub changed_at: chrono::DateTime<chrono::Utc>,
+    pub reason: Option<String>,
+}
+
+impl From<crate::service::model::RegistrationStatusChange> for RegistrationStatusChange {
+    fn from(value: crate::service::model::RegistrationStatusChange) -> Self {
+        RegistrationStatusChange {
+            status: value.status.into(),
+            changed_at: value.changed_at,
+            reason: value.reason,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct ProductRegistrationOwner {
+    pub registration_id: String,
+    pub profile_id: String,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub owned_from: chrono::DateTime<chrono::Utc>,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub owned_until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ProductRegistrationOwner {
+    pub fn new(
+        value: crate::service::model::ProductRegistrationOwner,
+        public_ids: &PublicIds,
+    ) -> Self {
+        ProductRegistrationOwner {
+            registration_id: public_ids.encode(IdKind::ProductRegistration, value.registration_id),
+            profile_id: public_ids.encode(IdKind::Profile, value.profile_id),
+            owned_from: value.owned_from,
+            owned_until: value.owned_until,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct PooledSerialCode {
+    pub serial_code: String,
+    pub lot: String,
+}
+
+impl From<PooledSerialCode> for crate::service::model::PooledSerialCode {
+    fn from(value: PooledSerialCode) -> Self {
+        crate::service::model::PooledSerialCode {
+            serial_code: value.serial_code,
+            lot: value.lot,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct SerialPoolImport {
+    pub imported: usize,
+    pub skipped: Vec<String>,
+}
+
+impl From<crate::service::model::SerialPoolImport> for SerialPoolImport {
+    fn from(value: crate::service::model::SerialPoolImport) -> Self {
+        SerialPoolImport {
+            imported: value.imported,
+            skipped: value.skipped.into_iter().collect(),
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct BackupSummary {
+    pub profiles: usize,
+    pub products: usize,
+    pub product_registrations: usize,
+}
+
+impl From<crate::service::model::BackupSummary> for BackupSummary {
+    fn from(value: crate::service::model::BackupSummary) -> Self {
+        BackupSummary {
+            profiles: value.profiles,
+            products: value.products,
+            product_registrations: value.product_registrations,
+        }
+    }
+}
+
+#[derive(Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum IntegrityViolationKind {
+    MissingProfile,
+    MissingProduct,
+    InvalidParent,
+    OverlappingRegistrations,
+    IndexMismatch,
+}
+
+impl From<crate::service::model::IntegrityViolationKind> for IntegrityViolationKind {
+    fn from(value: crate::service::model::IntegrityViolationKind) -> Self {
+        use crate::service::model::IntegrityViolationKind as Kind;
+
+        match value {
+            Kind::MissingProfile => IntegrityViolationKind::MissingProfile,
+            Kind::MissingProduct => IntegrityViolationKind::MissingProduct,
+            Kind::InvalidParent => IntegrityViolationKind::InvalidParent,
+            Kind::OverlappingRegistrations => IntegrityViolationKind::OverlappingRegistrations,
+            Kind::IndexMismatch => IntegrityViolationKind::IndexMismatch,
+        }
+    }
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct IntegrityViolation {
+    pub kind: IntegrityViolationKind,
+    pub profile_id: Option<String>,
+    pub registration_id: Option<String>,
+    pub product: Option<String>,
+    pub description: String,
+    pub repair: String,
+}
+
+#[derive(serde::Serialize)]
+pub(crate) struct IntegrityReport {
+    pub violations: Vec<IntegrityViolation>,
+    pub repaired: bool,
+}
+
+impl IntegrityReport {
+    pub fn new(value: crate::service::model::IntegrityReport, public_ids: &PublicIds) -> Self {
+        IntegrityReport {
+            violations: value
+                .violations
+                .into_iter()
+                .map(|violation| IntegrityViolation {
+                    kind: violation.kind.into(),
+                    profile_id: violation
+                        .profile_id
+                        .map(|id| public_ids.encode(IdKind::Profile, id)),
+                    registration_id: violation
+                        .registration_id
+                        .map(|id| public_ids.encode(IdKind::ProductRegistration, id)),
+                    product: violation.product,
+                    description: violation.description,
+                    repair: violation.repair,
+                })
+                .collect(),
+            repaired: value.repaired,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct SerialPoolLot {
+    pub lot: String,
+    pub imported: usize,
+    pub remaining: usize,
+}
+
+impl From<crate::service::model::SerialPoolLot> for SerialPoolLot {
+    fn from(value: crate::service::model::SerialPoolLot) -> Self {
+        SerialPoolLot {
+            lot: value.lot,
+            imported: value.imported,
+            remaining: value.remaining,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct SerialPool {
+    pub product: Product,
+    // serial codes left before falling back to generating them
+    pub remaining: usize,
+    pub depleted: bool,
+    pub lots: Vec<SerialPoolLot>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct Entitlement {
+    pub product: Product,
+    pub registration_id: String,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Entitlement {
+    pub fn new(value: crate::service::model::Entitlement, public_ids: &PublicIds) -> Self {
+        Entitlement {
+            product: Product { sku: value.product },
+            registration_id: public_ids.encode(IdKind::ProductRegistration, value.registration_id),
+            expiry_at: value.expiry_at,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct EntitlementCheck {
+    pub product: Product,
+    pub entitled: bool,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub granted_by: Vec<ProductRegistrationRecord>,
+}
+
+impl EntitlementCheck {
+    pub fn new(value: crate::service::model::EntitlementCheck, public_ids: &PublicIds) -> Self {
+        EntitlementCheck {
+            product: Product { sku: value.product },
+            entitled: value.entitled,
+            expiry_at: value.expiry_at,
+            granted_by: value
+                .granted_by
+                .into_iter()
+                .map(|registration| ProductRegistrationRecord::new(registration, public_ids))
+                .collect(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct LicenseClaims {
+    pub profile_id: String,
+    pub sku: String,
+    pub serial_code: String,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub issued_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl From<crate::license::LicenseClaims> for LicenseClaims {
+    fn from(value: crate::license::LicenseClaims) -> Self {
+        LicenseClaims {
+            profile_id: value.profile_id,
+            sku: value.sku,
+            serial_code: value.serial_code,
+            expiry_at: value.expiry_at,
+            issued_at: value.issued_at,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct LicenseKey {
+    pub key_id: String,
+    pub license_key: String,
+    pub claims: LicenseClaims,
+}
+
+impl From<crate::service::model::LicenseKey> for LicenseKey {
+    fn from(value: crate::service::model::LicenseKey) -> Self {
+        LicenseKey {
+            key_id: value.key_id,
+            license_key: value.license_key,
+            claims: value.claims.into(),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct LicenseVerifyingKey {
+    pub key_id: String,
+    // base64 Ed25519 public key
+    pub public_key: String,
+}
+
+impl From<crate::license::LicenseVerifyingKey> for LicenseVerifyingKey {
+    fn from(value: crate::license::LicenseVerifyingKey) -> Self {
+        LicenseVerifyingKey {
+            public_key: value.public_key(),
+            key_id: value.key_id,
+        }
+    }
+}
+
+#[derive(Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum VerifiedStatus {
+    Active,
+    Expired,
+    Inactive,
+}
+
+impl From<crate::service::model::VerifiedStatus> for VerifiedStatus {
+    fn from(value: crate::service::model::VerifiedStatus) -> Self {
+        use crate::service::model::VerifiedStatus as Status;
+
+        match value {
+            Status::Active => VerifiedStatus::Active,
+            Status::Expired => VerifiedStatus::Expired,
+            Status::Inactive => VerifiedStatus::Inactive,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct SerialCodeVerification {
+    pub sku: String,
+    pub status: VerifiedStatus,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub expiry_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl From<crate::service::model::SerialCodeVerification> for SerialCodeVerification {
+    fn from(value: crate::service::model::SerialCodeVerification) -> Self {
+        SerialCodeVerification {
+            sku: value.product,
+            status: value.status.into(),
+            expiry_at: value.expiry_at,
+        }
+    }
+}
+
+#[derive(Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum ApiKeyRole {
+    Admin,
+    Support,
+    Partner,
+    ReadOnly,
+}
+
+impl From<crate::service::model::ApiKeyRole> for ApiKeyRole {
+    fn from(value: crate::service::model::ApiKeyRole) -> Self {
+        use crate::service::model::ApiKeyRole as Role;
+
+        match value {
+            Role::Admin => ApiKeyRole::Admin,
+            Role::Support => ApiKeyRole::Support,
+            Role::Partner => ApiKeyRole::Partner,
+            Role::ReadOnly => ApiKeyRole::ReadOnly,
+        }
+    }
+}
+
+impl From<ApiKeyRole> for crate::service::model::ApiKeyRole {
+    fn from(value: ApiKeyRole) -> Self {
+        use crate::service::model::ApiKeyRole as Role;
+
+        match value {
+            ApiKeyRole::Admin => Role::Admin,
+            ApiKeyRole::Support => Role::Support,
+            ApiKeyRole::Partner => Role::Partner,
+            ApiKeyRole::ReadOnly => Role::ReadOnly,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub prefix: String,
+    pub role: ApiKeyRole,
+    #[serde(with = "chrono::serde::ts_milliseconds")]
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(with = "chrono::serde::ts_milliseconds_option")]
+    pub revoked_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ApiKey {
+    pub fn new(value: crate::service::model::ApiKey, public_ids: &PublicIds) -> Self {
+        ApiKey {
+            id: public_ids.encode(IdKind::ApiKey, value.id),
+            name: value.name,
+            prefix: value.prefix,
+            role: value.role.into(),
+            created_at: value.created_at,
+            revoked_at: value.revoked_at,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+pub(crate) struct CreatedApiKey {
+    pub api_key: ApiKey,
+    pub key: String,
+}
+
+impl CreatedApiKey {
+    pub fn new(value: crate::service::model::CreatedApiKey, public_ids: &PublicIds) -> Self {
+        CreatedApiKey {
+            api_key: ApiKey::new(value.api_key, public_ids),
+            key: value.key,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::{
+        repository::inram::InMemoryProfileRepository,
+        service::{ProfileService, ProfileServiceConfig},
+    };
+
+    use super::*;
+
+    // Every number of the response and every word of its strings
+    fn collect_numbers(value: &serde_json::Value, numbers: &mut Vec<String>) {
+        match value {
+            serde_json::Value::Number(number) => numbers.push(number.to_string()),
+            serde_json::Value::String(string) => numbers.extend(
+                string
+                    .split(|c: char| c.is_whitespace() || c == ':')
+                    .filter(|word| !word.is_empty())
+                    .map(String::from),
+            ),
+            serde_json::Value::Array(values) => values
+                .iter()
+                .for_each(|value| collect_numbers(value, numbers)),
+            serde_json::Value::Object(fields) => fields
+                .values()
+                .for_each(|value| collect_numbers(value, numbers)),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn transferred_registrations_carry_no_internal_ids() {
+        let service = ProfileService::new(
+            InMemoryProfileRepository::with_example_data(
+                || String::from("ABCDEFGH"),
+                || chrono::DateTime::<chrono::Utc>::MIN_UTC,
+            ),
+            ProfileServiceConfig::default(),
+        );
+        let public_ids = PublicIds::new(b"secret");
+
+        let transferred = service.transfer_product_registration(1, 2).unwrap();
+        let previous = service.get_product_registration(1).unwrap();
+        let internal_ids: HashSet<String> = [1, 2, transferred.registration.id]
+            .into_iter()
+            .chain(transferred.children.iter().map(|child| child.id))
+            .chain(previous.children.iter().map(|child| child.id))
+            .map(|id| id.to_string())
+            .collect();
+
+        for record in [transferred, previous] {
+            let response =
+                serde_json::to_value(ProductRegistrationRecord::new(record, &public_ids)).unwrap();
+            let mut numbers = Vec::new();
+            collect_numbers(&response, &mut numbers);
+            for number in numbers {
+                assert!(
+                    !internal_ids.contains(&number),
+                    "{} in {}",
+                    number,
+                    response
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use sha2::{Digest, Sha256};
+
+// Crockford's base32 in lower case, without i, l, o and u
+const ALPHABET: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+// 64 bits take 13 characters, the first one only holding 4 bits
+const ENCODED_LENGTH: usize = 13;
+const FEISTEL_ROUNDS: u8 = 8;
+
+#[derive(Clone, Copy)]
+pub(crate) enum IdKind {
+    Profile,
+    ProductRegistration,
+    ApiKey,
+}
+
+impl IdKind {
+    fn prefix(self) -> &'static str {
+        match self {
+            IdKind::Profile => "prof",
+            IdKind::ProductRegistration => "reg",
+            IdKind::ApiKey => "key",
+        }
+    }
+}
+
+///
+/// Turns internal ids into the opaque ids the API hands out, e.g. `prof_3k9x0c1vd8m2q`, and back
+/// The id is encrypted with a keyed Feistel network, so ids can't be enumerated or counted without the secret,
+/// and nothing has to be stored to look them up
+///
+pub(crate) struct PublicIds {
+    key: [u8; 32],
+}
+
+impl PublicIds {
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            key: Sha256::digest(secret).into(),
+        }
+    }
+
+    pub fn encode(&self, kind: IdKind, id: u64) -> String {
+        let encrypted = self.encrypt(kind, id);
+        let encoded: String = (0..ENCODED_LENGTH)
+            .rev()
+            .map(|group| ALPHABET[((encrypted >> (group * 5)) & 31) as usize] as char)
+            .collect();
+
+        format!("{}_{}", kind.prefix(), encoded)
+    }
+
+    // None if it is not an id of this kind
+    pub fn decode(&self, kind: IdKind, public_id: &str) -> Option<u64> {
+        let encoded = public_id
+            .strip_prefix(kind.prefix())
+            .and_then(|rest| rest.strip_prefix('_'))
+            .filter(|encoded| encoded.len() == ENCODED_LENGTH)?;
+
+        let mut encrypted: u64 = 0;
+        for (i, character) in encoded.bytes().enumerate() {
+            let digit = ALPHABET.iter().position(|&c| c == character)? as u64;
+            // the first character only has room for the top 4 bits
+            if i == 0 && digit >= 16 {
+                return None;
+            }
+            encrypted = (encrypted << 5) | digit;
+        }
+
+        Some(self.decrypt(kind, encrypted))
+    }
+
+    // Every kind gets its own permutation, so the same internal id looks unrelated across kinds
+    fn round_function(&self, kind: IdKind, round: u8, half: u32) -> u32 {
+        let digest = Sha256::new()
+            .chain_update(self.key)
+            .chain_update([kind as u8, round])
+            .chain_update(half.to_be_bytes())
+            .finalize();
+
+        u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+    }
+
+    fn encrypt(&self, kind: IdKind, id: u64) -> u64 {
+        let (mut left, mut right) = ((id >> 32) as u32, id as u32);
+        for round in 0..FEISTEL_ROUNDS {
+            (left, right) = (right, left ^ self.round_function(kind, round, right));
+        }
+
+        ((left as u64) << 32) | right as u64
+    }
+
+    fn decrypt(&self, kind: IdKind, encrypted: u64) -> u64 {
+        let (mut left, mut right) = ((encrypted >> 32) as u32, encrypted as u32);
+        for round in (0..FEISTEL_ROUNDS).rev() {
+            (left, right) = (right ^ self.round_function(kind, round, left), left);
+        }
+
+        ((left as u64) << 32) | right as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_encoded_ids() {
+        let public_ids = PublicIds::new(b"secret");
+
+        for id in [0, 1, 2, 1337, u64::MAX] {
+            let public_id = public_ids.encode(IdKind::ProductRegistration, id);
+            assert!(public_id.starts_with("reg_"));
+            assert_eq!(
+                Some(id),
+                public_ids.decode(IdKind::ProductRegistration, &public_id)
+            );
+            // ids of one kind are not ids of another
+            assert_eq!(None, public_ids.decode(IdKind::Profile, &public_id));
+        }
+        assert_ne!(
+            public_ids.encode(IdKind::Profile, 1),
+            PublicIds::new(b"other secret").encode(IdKind::Profile, 1)
+        );
+    }
+
+    #[test]
+    fn reject_malformed_ids() {
+        let public_ids = PublicIds::new(b"secret");
+
+        assert!(public_ids
+            .decode(IdKind::Profile, "prof_0000000000000")
+            .is_some());
+        for public_id in [
+            "",
+            "1",
+            "prof_",
+            "prof_000000000000",
+            "prof_00000000000000",
+            // more than 64 bits
+            "prof_g000000000000",
+            "prof_000000000000u",
+            "PROF_0000000000000",
+        ] {
+            assert_eq!(
+                None,
+                public_ids.decode(IdKind::Profile, public_id),
+                "{}",
+                public_id
+            );
+        }
+    }
+}
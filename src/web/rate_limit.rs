@@ -0,0 +1,101 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use dashmap::DashMap;
+
+use super::error::ProfileApiError;
+
+///
+/// Fixed window rate limit per client address
+///
+pub(crate) struct RateLimiter {
+    limit: u32,
+    window: Duration,
+    // start of the client's current window and the requests made in it
+    clients: DashMap<IpAddr, (Instant, u32)>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            clients: DashMap::new(),
+        }
+    }
+
+    ///
+    /// Drops clients whose window has passed, run now and then rather than on requests so they don't pay for it
+    ///
+    pub fn sweep(&self, now: Instant) {
+        self.clients
+            .retain(|_, (started_at, _)| now.duration_since(*started_at) < self.window);
+    }
+
+    // Counts the request, or returns how long until the client can make another one
+    fn check(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut entry = self.clients.entry(client).or_insert((now, 0));
+        let (started_at, requests) = entry.value_mut();
+        if now.duration_since(*started_at) >= self.window {
+            *started_at = now;
+            *requests = 0;
+        }
+        if *requests >= self.limit {
+            return Err(self.window - now.duration_since(*started_at));
+        }
+
+        *requests += 1;
+        Ok(())
+    }
+}
+
+pub(crate) async fn rate_limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Result<Response, ProfileApiError> {
+    if let Err(retry_after) = limiter.check(address.ip(), Instant::now()) {
+        tracing::warn!("Rate limited {} on {}", address.ip(), request.uri().path());
+        return Err(ProfileApiError::TooManyRequests(
+            retry_after.as_secs().max(1),
+        ));
+    }
+
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_resets_after_window() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        let client = IpAddr::from([192, 0, 2, 1]);
+        let other_client = IpAddr::from([192, 0, 2, 2]);
+        let now = Instant::now();
+
+        assert_eq!(Ok(()), limiter.check(client, now));
+        assert_eq!(Ok(()), limiter.check(client, now + Duration::from_secs(10)));
+        assert_eq!(
+            Err(Duration::from_secs(40)),
+            limiter.check(client, now + Duration::from_secs(20))
+        );
+        assert_eq!(Ok(()), limiter.check(other_client, now));
+        assert_eq!(Ok(()), limiter.check(client, now + Duration::from_secs(60)));
+
+        limiter.sweep(now + Duration::from_secs(90));
+        assert_eq!(1, limiter.clients.len());
+        limiter.sweep(now + Duration::from_secs(120));
+        assert!(limiter.clients.is_empty());
+    }
+}